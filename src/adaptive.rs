@@ -0,0 +1,213 @@
+use std::path::{Path, PathBuf};
+use regex::Regex;
+use errors::*;
+use omittable::Omittable;
+use runner;
+use simulation::{combine_phsp, fresh_seed, Backend, ParSimFinished, ParSimInput, ParSimReport,
+                 RunOptions, Seed};
+use uncertain::Uf64;
+
+/// Largest factor by which one round may multiply the number of histories.
+/// Estimates from few histories are noisy, so we do not extrapolate further.
+const MAX_GROWTH: f64 = 10.;
+const MAX_ROUNDS: usize = 20;
+
+/// When an adaptive run may stop launching chunks.
+#[derive(Debug, Clone)]
+pub struct Target {
+    /// Relative standard deviation every selected dose should reach.
+    pub rstd: f64,
+    /// Only doses in geometries matching this are considered, all if `None`.
+    pub geometry: Option<Regex>,
+    /// CPU time in seconds that all rounds together may use.
+    pub cpu_budget: Option<f64>,
+}
+
+impl Target {
+    /// The selected dose with the largest relative uncertainty.
+    pub fn worst<'a>(&self, dose: &'a [(String, Uf64)]) -> Result<&'a (String, Uf64)> {
+        let rstd = |d: &Uf64| {
+            let r = d.rstd();
+            if r.is_nan() {
+                f64::INFINITY
+            } else {
+                r
+            }
+        };
+        dose.iter()
+            .filter(|(name, _)| match self.geometry {
+                Some(ref re) => re.is_match(name),
+                None => true,
+            })
+            .fold(None, |worst: Option<&(String, Uf64)>, d| match worst {
+                Some(w) if rstd(&w.1) >= rstd(&d.1) => Some(w),
+                _ => Some(d),
+            })
+            .ok_or_else(|| match self.geometry {
+                Some(ref re) => format!("No geometry matches {:?}", re.as_str()).into(),
+                None => "The simulation did not score any dose".into(),
+            })
+    }
+
+    /// Histories to add to `nhistories` for `rstd` to reach the target,
+    /// assuming the uncertainty falls like one over the square root of the
+    /// number of histories.
+    pub fn extra_histories(&self, rstd: f64, nhistories: u64) -> u64 {
+        if rstd <= self.rstd {
+            return 0;
+        }
+        let factor = (rstd / self.rstd).powi(2).min(MAX_GROWTH);
+        ((factor - 1.) * nhistories as f64).ceil() as u64
+    }
+}
+
+/// A seed for each of `seeds` that is not in `used`, derived like a retry
+/// by `fresh_seed`. The second components stay those of the input, so the
+/// rounds of different files of a split simulation cannot collide.
+pub fn new_seeds(seeds: &[Seed], used: &[Seed]) -> Result<Vec<Seed>> {
+    let mut used = used.to_vec();
+    let mut ret = Vec::new();
+    for &seed in seeds {
+        let fresh = fresh_seed(seed, &used)?;
+        used.push(fresh);
+        ret.push(fresh);
+    }
+    Ok(ret)
+}
+
+/// A simulation that was run in several rounds.
+#[derive(Debug)]
+pub struct AdaptiveRun {
+    pub rounds: Vec<ParSimFinished>,
+    /// All rounds combined.
+    pub report: ParSimReport,
+}
+
+impl AdaptiveRun {
+    pub fn combine_phsp(&self, output_path: &Path, cleanup: bool) -> Result<Vec<PathBuf>> {
+        combine_phsp(
+            self.rounds.iter().flat_map(|r| r.outputs.iter()),
+            output_path,
+            cleanup,
+        )
+    }
+}
+
+/// Number of histories the next round should run, `None` if we are done.
+fn next_round(report: &ParSimReport, target: &Target, round: usize) -> Result<Option<u64>> {
+    if runner::interrupted().is_some() {
+        return Ok(None);
+    }
+    let dose = match report.dose {
+        Omittable::Available(ref dose) => dose,
+        _ => {
+            println!("Round {}: no dose available, stopping", round);
+            return Ok(None);
+        }
+    };
+    let (name, worst) = target.worst(dose)?;
+    let total: u64 = report.input.ncases.iter().sum();
+    let nhistories = total.saturating_sub(report.lost_histories);
+    println!(
+        "Round {}: {} histories, rstd {:.3}% in {}, target {:.3}%",
+        round,
+        nhistories,
+        worst.rstd() * 100.,
+        name,
+        target.rstd * 100.
+    );
+    let mut extra = target.extra_histories(worst.rstd(), nhistories);
+    if extra == 0 {
+        return Ok(None);
+    }
+    if round >= MAX_ROUNDS {
+        println!("Target not reached after {} rounds, stopping", round);
+        return Ok(None);
+    }
+    if let (Some(budget), Omittable::Available(cpu)) =
+        (target.cpu_budget, report.total_cpu_time.clone())
+    {
+        let per_history = cpu / nhistories as f64;
+        let affordable = ((budget - cpu) / per_history).floor().max(0.);
+        if affordable < extra as f64 {
+            extra = affordable as u64;
+        }
+        if extra == 0 {
+            println!("CPU budget of {} s is used up, stopping", budget);
+            return Ok(None);
+        }
+    }
+    Ok(Some(extra))
+}
+
+/// Run `input`, then further chunks with fresh seeds until the target is reached.
+pub fn run_adaptive(
+    input: &ParSimInput,
+    options: &RunOptions,
+    target: &Target,
+) -> Result<AdaptiveRun> {
+    if !input.particle_ranges.is_empty() {
+        bail!("Adaptive runs cannot read more particles from a phase space source");
+    }
+    if options.backend == Backend::Native {
+        bail!("Adaptive runs cannot use the native backend");
+    }
+    let nchunks = input.seeds.len();
+    let mut round_input = input.clone();
+    let mut used: Vec<Seed> = Vec::new();
+    let mut rounds = Vec::new();
+    let mut reports = Vec::new();
+    loop {
+        used.extend(&round_input.seeds);
+        let fin = round_input.run_with_options(options)?;
+        used.extend(&fin.input.seeds);
+        reports.push(fin.report());
+        rounds.push(fin);
+        let report = ParSimReport::combine(&reports)?;
+        let extra = match next_round(&report, target, rounds.len())? {
+            Some(extra) => extra,
+            None => return Ok(AdaptiveRun { rounds, report }),
+        };
+        let ncase = extra.div_ceil(nchunks as u64).max(1);
+        println!("Running {} more histories in {} chunks", ncase * nchunks as u64, nchunks);
+        round_input = ParSimInput {
+            seeds: new_seeds(&input.seeds, &used)?,
+            ncases: vec![ncase; nchunks],
+            ..input.clone()
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target() {
+        let target = Target {
+            rstd: 0.01,
+            geometry: Some(Regex::new("^cav").unwrap()),
+            cpu_budget: None,
+        };
+        let dose = vec![
+            ("cavity1".to_string(), Uf64::from_value_rstd(1., 0.02)),
+            ("cavity2".to_string(), Uf64::from_value_rstd(1., 0.03)),
+            ("wall".to_string(), Uf64::from_value_rstd(1., 0.5)),
+        ];
+        assert_eq!(target.worst(&dose).unwrap().0, "cavity2");
+        assert!(target.worst(&dose[2..]).is_err());
+        assert_eq!(target.extra_histories(0.02, 1000), 3000);
+        assert_eq!(target.extra_histories(0.005, 1000), 0);
+        assert_eq!(target.extra_histories(0.5, 1000), 9000);
+        assert_eq!(target.extra_histories(f64::NAN, 1000), 9000);
+    }
+
+    #[test]
+    fn test_new_seeds() {
+        let used = vec![(42, 1), (42, 2), (43, 1), (42, 4)];
+        assert_eq!(new_seeds(&[(42, 1), (42, 2)], &used).unwrap(), vec![(44, 1), (43, 2)]);
+        // a file of a split simulation only owns its second components
+        let other_file = new_seeds(&[(42, 3), (42, 4)], &[(42, 3), (42, 4)]).unwrap();
+        assert_eq!(other_file, vec![(43, 3), (43, 4)]);
+    }
+}
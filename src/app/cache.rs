@@ -0,0 +1,82 @@
+use clap::ArgMatches;
+use std::time::Duration;
+use app::util::{GetMatch, SubCmd};
+use cache::Cache;
+use errors::*;
+
+const SECS_PER_DAY: f64 = 24. * 3600.;
+const BYTES_PER_MB: f64 = 1e6;
+
+#[derive(Debug)]
+pub struct CacheInfoConfig {
+    cache: Cache,
+}
+
+impl SubCmd for CacheInfoConfig {
+    fn parse(m: &ArgMatches) -> Result<Self> {
+        let cache = Cache::new(&m.get_abspath("CACHE_DIR")?);
+        Ok(CacheInfoConfig { cache })
+    }
+
+    fn run(&self) -> Result<()> {
+        let stats = self.cache.stats()?;
+        println!("Cache directory: {:?}", self.cache.dir());
+        println!("Chunks: {}", stats.entries);
+        println!("Size: {:.1} MB", stats.bytes as f64 / BYTES_PER_MB);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct CacheClearConfig {
+    cache: Cache,
+}
+
+impl SubCmd for CacheClearConfig {
+    fn parse(m: &ArgMatches) -> Result<Self> {
+        let cache = Cache::new(&m.get_abspath("CACHE_DIR")?);
+        Ok(CacheClearConfig { cache })
+    }
+
+    fn run(&self) -> Result<()> {
+        let n = self.cache.clear()?;
+        println!("Removed {} cached chunks", n);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct CachePruneConfig {
+    cache: Cache,
+    max_age: Option<Duration>,
+    max_bytes: Option<u64>,
+}
+
+impl SubCmd for CachePruneConfig {
+    fn parse(m: &ArgMatches) -> Result<Self> {
+        let cache = Cache::new(&m.get_abspath("CACHE_DIR")?);
+        let days: Option<f64> = m.get_parse_option("MAX_AGE")?;
+        let mb: Option<f64> = m.get_parse_option("MAX_SIZE")?;
+        if days.is_none() && mb.is_none() {
+            bail!("At least one of MAX_AGE and MAX_SIZE must be given.");
+        }
+        for &x in days.iter().chain(mb.iter()) {
+            if !x.is_finite() || x < 0. {
+                bail!("MAX_AGE and MAX_SIZE must be finite and not negative.");
+            }
+        }
+        let max_age = days.map(|d| Duration::from_secs_f64(d * SECS_PER_DAY));
+        let max_bytes = mb.map(|mb| (mb * BYTES_PER_MB) as u64);
+        Ok(CachePruneConfig {
+            cache,
+            max_age,
+            max_bytes,
+        })
+    }
+
+    fn run(&self) -> Result<()> {
+        let n = self.cache.prune(self.max_age, self.max_bytes)?;
+        println!("Removed {} cached chunks", n);
+        Ok(())
+    }
+}
@@ -12,6 +12,7 @@ use util::{load, save};
 pub struct CombineConfig {
     inputpath: PathBuf,
     outputpath: PathBuf,
+    exclude_outliers: bool,
 }
 
 impl CombineConfig {
@@ -51,9 +52,11 @@ impl SubCmd for CombineConfig {
     fn parse(m: &ArgMatches) -> Result<Self> {
         let inputpath = m.get_abspath("INPUT")?;
         let outputpath = m.get_abspath("OUTPUT")?;
+        let exclude_outliers = m.is_present("EXCLUDE_OUTLIERS");
         let ret = CombineConfig {
             inputpath,
             outputpath,
+            exclude_outliers,
         };
         Ok(ret)
     }
@@ -61,8 +64,24 @@ impl SubCmd for CombineConfig {
     fn run(&self) -> Result<()> {
         let d = self.create_path_report_dict()?;
         for (output_path, sims) in &d {
-            let out = ParSimReport::combine(&sims)?;
-            save(&output_path, &out)?
+            let mut out = ParSimReport::combine(&sims)?;
+            if self.exclude_outliers {
+                let flagged = out.flagged_chunks();
+                if !flagged.is_empty() {
+                    match out.clone().reject_flagged() {
+                        Ok(cleaned) => {
+                            println!("Excluding outlier chunks {:?} from {:?}", flagged, output_path);
+                            out = cleaned;
+                        }
+                        Err(e) => eprintln!(
+                            "Warning: Not excluding outlier chunks {:?} from {:?}: {}",
+                            flagged, output_path, e
+                        ),
+                    }
+                }
+            }
+            save(&output_path, &out)?;
+            out.save_dose3d(output_path)?;
         }
 
         Ok(())
@@ -2,7 +2,11 @@ use clap::{Arg, ArgMatches, SubCommand};
 use clap;
 use std::path::{Path, PathBuf};
 use num_cpus;
-use simulation::{ParSimInput, ParSimReport, Seed, SingSimInput};
+use simulation::{Backend, ParSimInput, ParSimReport, ParticleRange, RunOptions, Seed, SingSimInput,
+                 SingSimReport};
+use runner::{self, Limits};
+use progress::ProgressMode;
+use environment::EgsEnv;
 use util::{load, save};
 use errors::*;
 use std::fs;
@@ -12,13 +16,35 @@ use tokenizer::TokenStream;
 use std::io::BufReader;
 use std::ffi::OsStr;
 use serde_json;
-use util::{read_paths_in_dir, HenInfo};
+use util::{has_unique_elements, read_files_in_dir, run_queue, shell_quote, HenInfo, Slots};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use glob;
+use error_chain::ChainedError;
 
 mod util;
 mod combine;
-use app::util::{arg_application, arg_cleanup, arg_input, arg_output, arg_pegsfile, arg_report,
-                GetMatch, SubCmd};
+mod phsp;
+mod scheduler;
+mod cache;
+mod verify;
+use app::util::{arg_application, arg_cleanup, arg_input, arg_max_cpu_time, arg_max_failed_chunks,
+                arg_max_memory, arg_no_progress, arg_output, arg_pegsfile, arg_progress_interval,
+                arg_report, arg_retries, arg_timeout, arg_extra_args, arg_job, arg_poll_cmd,
+                arg_submit_cmd, args_cache, args_egs_env, args_target,
+                arg_backend, arg_cache_dir, arg_dry_run, arg_nice, args_jobs, parse_cache, parse_jobs, parse_pin_cpus, parse_target, abspath_from_string, parse_egs_env,
+                parse_extra_args, parse_limits, parse_progress, GetMatch, SubCmd};
 use app::combine::CombineConfig;
+use app::scheduler::{PollConfig, SubmitConfig};
+use adaptive::{run_adaptive, Target};
+use checkpoint::Checkpoints;
+use cache::Cache;
+use app::cache::{CacheClearConfig, CacheInfoConfig, CachePruneConfig};
+use scheduler::{Job, Scheduler};
+use std::os::unix::fs::PermissionsExt;
+use template;
+use app::phsp::{PhspCombineConfig, PhspStatsConfig};
+use app::verify::VerifyConfig;
 
 fn create_app() -> clap::App<'static, 'static> {
     clap::App::new("hen")
@@ -30,16 +56,47 @@ fn create_app() -> clap::App<'static, 'static> {
                 .about("Run .egsinp files.")
                 .version(crate_version!())
                 .author(crate_authors!())
-                .arg(arg_input())
+                .arg(
+                    arg_input()
+                        .multiple(true)
+                        .help("Input files, directories or glob patterns like 'sims/**/*.egsinp'. The chunks of all inputs share the --jobs slots.")
+                )
+                .arg(
+                    Arg::with_name("RECURSIVE")
+                        .long("recursive")
+                        .short("r")
+                        .help("Also run the inputs in subdirectories of INPUT directories."),
+                )
                 .arg(arg_cleanup())
                 .arg(arg_output())
                 .arg(arg_pegsfile())
                 .arg(arg_application())
+                .arg(arg_timeout())
+                .arg(arg_max_memory())
+                .arg(arg_max_cpu_time())
+                .arg(arg_nice())
+                .args(&args_jobs())
+                .arg(arg_no_progress())
+                .arg(arg_progress_interval())
+                .arg(arg_retries())
+                .arg(arg_max_failed_chunks())
+                .args(&args_egs_env())
+                .arg(arg_extra_args())
+                .args(&args_target())
+                .args(&args_cache())
+                .arg(arg_backend())
+                .arg(arg_dry_run())
+                .arg(
+                    Arg::with_name("CHUNK")
+                        .long("chunk")
+                        .help("Replace {chunk} in INPUT and OUTPUT by this number, e.g. the task id of a job array.")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("NTHREADS")
                         .long("nthreads")
                         .short("t")
-                        .help("Number of threads that should be used for the simulation. Defaults to the number of cores.")
+                        .help("Number of chunks the simulation is split into. Defaults to the number of cores. See also --jobs.")
                         .takes_value(true),
                 )
                 .arg(
@@ -69,6 +126,26 @@ fn create_app() -> clap::App<'static, 'static> {
                         .default_value("smart")
                         .case_insensitive(true)
                 )
+                .arg(
+                    Arg::with_name("TIMELINE")
+                        .long("timeline")
+                        .help("Show when each chunk ran and what resources it used, instead of WHAT.")
+                )
+                .arg(
+                    Arg::with_name("RATIO")
+                        .long("ratio")
+                        .help("Show the ratio of the doses in two geometries, instead of WHAT.")
+                        .takes_value(true)
+                        .number_of_values(2)
+                        .value_names(&["NUMERATOR", "DENOMINATOR"])
+                )
+                .arg(
+                    Arg::with_name("EXPRESSION")
+                        .long("expr")
+                        .help("Show an expression of the doses in geometries, like 'sqrt(a / b)' or \
+                               'ln(\"my geometry\") ^ 2', instead of WHAT.")
+                        .takes_value(true)
+                )
         )
         .subcommand(
             SubCommand::with_name("view")
@@ -84,6 +161,38 @@ fn create_app() -> clap::App<'static, 'static> {
                 .about("Rerun a finished simulation.")
                 .arg(arg_report())
                 .arg(arg_output())
+                .arg(arg_timeout())
+                .arg(arg_max_memory())
+                .arg(arg_max_cpu_time())
+                .arg(arg_nice())
+                .args(&args_jobs())
+                .arg(arg_no_progress())
+                .arg(arg_progress_interval())
+                .arg(arg_retries())
+                .arg(arg_max_failed_chunks())
+                .args(&args_egs_env())
+                .arg(arg_extra_args())
+                .args(&args_cache())
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .version(crate_version!())
+                .author(crate_authors!())
+                .about("Rerun chunks of a finished simulation and check that they reproduce exactly.")
+                .arg(arg_report())
+                .arg(
+                    Arg::with_name("CHUNKS")
+                        .long("chunks")
+                        .help("Comma separated indices of the chunks to rerun. Defaults to 0.")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                )
+                .arg(arg_timeout())
+                .arg(arg_max_memory())
+                .arg(arg_max_cpu_time())
+                .arg(arg_nice())
+                .args(&args_egs_env())
         )
         .subcommand(
             SubCommand::with_name("fmt")
@@ -122,6 +231,34 @@ fn create_app() -> clap::App<'static, 'static> {
                 .arg(arg_output())
                 .arg(arg_pegsfile())
                 .arg(arg_application())
+                .args(&args_egs_env())
+                .arg(arg_extra_args())
+                .arg(
+                    Arg::with_name("SCHEDULER")
+                        .long("scheduler")
+                        .help("Also write a job array script for this batch system.")
+                        .possible_values(&["slurm", "pbs", "sge"])
+                        .takes_value(true),
+                )
+                .arg(arg_submit_cmd())
+                .arg(arg_poll_cmd())
+                .arg(arg_dry_run())
+        )
+        .subcommand(
+            SubCommand::with_name("submit")
+                .version(crate_version!())
+                .author(crate_authors!())
+                .about("Submit a job script written by hen split --scheduler.")
+                .arg(arg_job())
+                .arg(arg_submit_cmd())
+        )
+        .subcommand(
+            SubCommand::with_name("poll")
+                .version(crate_version!())
+                .author(crate_authors!())
+                .about("Show the state of a submitted job and its finished chunks.")
+                .arg(arg_job())
+                .arg(arg_poll_cmd())
         )
         .subcommand(
             SubCommand::with_name("combine")
@@ -130,6 +267,68 @@ fn create_app() -> clap::App<'static, 'static> {
                 .about("Combine multiple .henout files into one.")
                 .arg(arg_input())
                 .arg(arg_output())
+                .arg(
+                    Arg::with_name("EXCLUDE_OUTLIERS")
+                        .long("exclude-outliers")
+                        .help("Leave out chunks whose dose deviates from the others by more than their uncertainty allows. Nothing is left out if that would be more than a quarter of the chunks or leave fewer than two.")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("phsp")
+                .version(crate_version!())
+                .author(crate_authors!())
+                .about("Inspect and combine .egsphsp phase space files.")
+                .subcommand(
+                    SubCommand::with_name("stats")
+                        .about("Print summary statistics of a phase space file.")
+                        .arg(
+                            arg_input()
+                                .help("Path to a .egsphsp file.")
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("combine")
+                        .about("Concatenate phase space files and correct the header.")
+                        .arg(
+                            arg_input()
+                                .multiple(true)
+                                .help("Paths to the .egsphsp files that should be combined.")
+                        )
+                        .arg(arg_output())
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("cache")
+                .version(crate_version!())
+                .author(crate_authors!())
+                .about("Inspect and evict the cache of chunk results.")
+                .subcommand(
+                    SubCommand::with_name("info")
+                        .about("Print the number and size of cached chunks.")
+                        .arg(arg_cache_dir().required(true))
+                )
+                .subcommand(
+                    SubCommand::with_name("clear")
+                        .about("Remove all cached chunks.")
+                        .arg(arg_cache_dir().required(true))
+                )
+                .subcommand(
+                    SubCommand::with_name("prune")
+                        .about("Remove cached chunks that were not used recently.")
+                        .arg(arg_cache_dir().required(true))
+                        .arg(
+                            Arg::with_name("MAX_AGE")
+                                .long("max-age")
+                                .help("Remove chunks not used for this many days.")
+                                .takes_value(true)
+                        )
+                        .arg(
+                            Arg::with_name("MAX_SIZE")
+                                .long("max-size")
+                                .help("Remove the least recently used chunks until at most this many MB remain.")
+                                .takes_value(true)
+                        )
+                )
         )
 }
 
@@ -141,6 +340,12 @@ struct SplitConfig {
     nfiles: usize,
     application: String,
     pegsfile: String,
+    env: EgsEnv,
+    extra_args: Vec<String>,
+    scheduler: Option<Scheduler>,
+    submit_cmd: Option<String>,
+    poll_cmd: Option<String>,
+    dry_run: bool,
 }
 
 impl SplitConfig {
@@ -163,6 +368,12 @@ impl SubCmd for SplitConfig {
         let nthreads = m.get_parse("NTHREADS")?;
         let application = m.get_string("APPLICATION")?;
         let pegsfile = m.get_string("PEGSFILE")?;
+        let env = parse_egs_env(m)?;
+        let extra_args = parse_extra_args(m)?.unwrap_or_default();
+        let scheduler = m.get_parse_option("SCHEDULER")?;
+        let submit_cmd = m.value_of("SUBMIT_CMD").map(str::to_string);
+        let poll_cmd = m.value_of("POLL_CMD").map(str::to_string);
+        let dry_run = m.is_present("DRY_RUN");
         let ret = SplitConfig {
             inputpath,
             outputpath,
@@ -170,39 +381,105 @@ impl SubCmd for SplitConfig {
             nfiles,
             application,
             pegsfile,
+            env,
+            extra_args,
+            scheduler,
+            submit_cmd,
+            poll_cmd,
+            dry_run,
         };
         ret.validate()?;
         Ok(ret)
     }
 
     fn run(&self) -> Result<()> {
-        let prototype =
+        let mut prototype =
             SingSimInput::from_egsinp_path(&self.application, &self.inputpath, &self.pegsfile)?;
+        // not resolved, the chunks will run on other machines
+        prototype.env = self.env.clone();
+        prototype.extra_args = self.extra_args.clone();
         let n = self.nthreads * self.nfiles;
         let ParSimInput {
             prototype,
             seeds,
             ncases,
+            particle_ranges,
+            max_failed_chunks,
         } = prototype.splitn(n)?;
         let chunksize = self.nthreads;
         let seeds = seeds.chunks(chunksize);
         let ncases = ncases.chunks(chunksize);
+        let mut particle_ranges: Vec<Vec<ParticleRange>> = particle_ranges
+            .chunks(chunksize)
+            .map(|c| c.to_vec())
+            .collect();
+        particle_ranges.resize(self.nfiles, Vec::new());
         let filestem = &self.inputpath
             .file_stem()
             .ok_or("Cannot get file_stem".to_string())?
             .to_str()
             .ok_or("to_str failed")?
             .to_string();
-        for (i, (ncase, seed)) in ncases.zip(seeds).enumerate() {
+        for (i, ((ncase, seed), ranges)) in ncases.zip(seeds).zip(particle_ranges).enumerate() {
             let filename = format!("{}_{}.heninp", filestem, i).to_string();
             let path = self.outputpath.join(filename);
             let psim = ParSimInput {
                 prototype: prototype.clone(),
                 ncases: ncase.to_vec(),
                 seeds: seed.to_vec(),
+                particle_ranges: ranges,
+                max_failed_chunks,
             };
-            save(&path, &psim)?;
+            if self.dry_run {
+                print_dry_run(&psim, &dry_run_dir(&path))?;
+            } else {
+                save(&path, &psim)?;
+            }
+        }
+        if self.dry_run {
+            return Ok(());
         }
+        if let Some(scheduler) = self.scheduler {
+            self.write_job(scheduler, filestem)?;
+        }
+        Ok(())
+    }
+}
+
+impl SplitConfig {
+    /// Write a job array script running one .heninp file per task.
+    fn write_job(&self, scheduler: Scheduler, filestem: &str) -> Result<()> {
+        let chunk_path = |i: &str, ext: &str| self.outputpath.join(format!("{}_{}.{}", filestem, i, ext));
+        let job = Job {
+            scheduler,
+            name: filestem.to_string(),
+            script: self.outputpath.join(format!("{}.{}.sh", filestem, scheduler)),
+            inputs: (0..self.nfiles)
+                .map(|i| chunk_path(&i.to_string(), "heninp"))
+                .collect(),
+            outputs: (0..self.nfiles)
+                .map(|i| chunk_path(&i.to_string(), "henout"))
+                .collect(),
+            submit_cmd: self.submit_cmd
+                .clone()
+                .unwrap_or_else(|| scheduler.default_submit_cmd().to_string()),
+            poll_cmd: self.poll_cmd
+                .clone()
+                .unwrap_or_else(|| scheduler.default_poll_cmd().to_string()),
+            job_id: None,
+        };
+        let hen = std::env::current_exe().chain_err(|| "Cannot locate the hen executable")?;
+        let content = job.script_content(
+            &hen,
+            &chunk_path("{chunk}", "heninp"),
+            &chunk_path("{chunk}", "henout"),
+        );
+        fs::write(&job.script, content).chain_err(|| cannot_write(&job.script))?;
+        fs::set_permissions(&job.script, fs::Permissions::from_mode(0o755))
+            .chain_err(|| cannot_write(&job.script))?;
+        let job_path = self.outputpath.join(format!("{}.henjob", filestem));
+        save(&job_path, &job)?;
+        println!("Wrote {:?}, submit it with hen submit {:?}", job.script, job_path);
         Ok(())
     }
 }
@@ -236,21 +513,172 @@ impl SubCmd for FormatConfig {
 struct RerunConfig {
     path: PathBuf, // path to input
     outputpath: PathBuf,
+    limits: Limits,
+    progress: ProgressMode,
+    retries: usize,
+    jobs: usize,
+    pin_cpus: bool,
+    max_failed_chunks: Option<usize>,
+    env: EgsEnv,
+    extra_args: Option<Vec<String>>,
+    cache: Option<Cache>,
 }
 
 impl SubCmd for RerunConfig {
     fn parse(m: &ArgMatches) -> Result<RerunConfig> {
         let path = m.get_abspath("PATH")?;
         let outputpath = m.get_abspath("OUTPUT")?;
-        Ok(RerunConfig { path, outputpath })
+        let limits = parse_limits(m)?;
+        let progress = parse_progress(m)?;
+        let retries = m.get_parse("RETRIES")?;
+        let jobs = parse_jobs(m)?;
+        let pin_cpus = parse_pin_cpus(m)?;
+        let max_failed_chunks = m.get_parse_option("MAX_FAILED_CHUNKS")?;
+        let env = parse_egs_env(m)?;
+        let extra_args = parse_extra_args(m)?;
+        let cache = parse_cache(m)?;
+        Ok(RerunConfig {
+            path,
+            outputpath,
+            limits,
+            progress,
+            retries,
+            jobs,
+            pin_cpus,
+            max_failed_chunks,
+            env,
+            extra_args,
+            cache,
+        })
     }
 
     fn run(&self) -> Result<()> {
         let report: ParSimReport = load(&self.path)?;
-        let sim = report.input;
-        let out = sim.run()?.report();
+        // rerun with the backend that produced the report
+        let native = report.native_combine.is_some();
+        let mut sim = report.input;
+        if self.max_failed_chunks.is_some() {
+            sim.max_failed_chunks = self.max_failed_chunks;
+        }
+        let application = sim.prototype.application.clone();
+        let recorded = sim.prototype.env.clone();
+        if recorded != EgsEnv::default() {
+            let diffs = recorded.differences_here(&self.env, &application);
+            if !diffs.is_empty() {
+                eprintln!(
+                    "Warning: The EGSnrc environment differs from the one recorded in {:?}:",
+                    self.path
+                );
+                for d in diffs {
+                    eprintln!("    {}", d);
+                }
+                eprintln!("Using the recorded settings, unless given on the command line.");
+            }
+        }
+        sim.prototype.env = self.env.or(&recorded).resolve(&application);
+        if let Some(ref args) = self.extra_args {
+            sim.prototype.extra_args = args.clone();
+        }
+        let checkpoints = Checkpoints::for_output(&self.outputpath);
+        let options = RunOptions {
+            limits: self.limits.clone(),
+            progress: self.progress,
+            retries: self.retries,
+            jobs: self.jobs,
+            pin_cpus: self.pin_cpus,
+            checkpoints: Some(checkpoints.clone()),
+            cache: self.cache.clone(),
+            ..RunOptions::new()
+        };
+        runner::install_signal_handlers();
+        let fin = if native {
+            sim.run_native(&options)?
+        } else {
+            sim.run_with_options(&options)?
+        };
+        let mut out = fin.report();
+        fin.combine_phsp(&self.outputpath, is_complete(&out))?;
+        fin.keep_egsdat(&self.outputpath)?;
+        out.record_provenance();
         save(&self.outputpath, &out)?;
-        return Ok(());
+        out.save_dose3d(&self.outputpath)?;
+        finish_checkpoints(&checkpoints, &out)?;
+        check_interrupted()?;
+        out.check_failures()
+    }
+}
+
+/// The directory `<path>.dryrun` next to `path`.
+fn dry_run_dir(path: &Path) -> PathBuf {
+    let mut name = path.file_name()
+        .map(|s| s.to_os_string())
+        .unwrap_or_default();
+    name.push(".dryrun");
+    path.with_file_name(name)
+}
+
+/// Write the chunk inputs of `sim` to `dir` and print how they would be run.
+fn print_dry_run(sim: &ParSimInput, dir: &Path) -> Result<()> {
+    let chunks = sim.dry_run(dir)?;
+    println!(
+        "Dry run of {:?}: {} chunks, inputs written to {:?}",
+        sim.prototype.filename,
+        chunks.len(),
+        dir
+    );
+    println!("Chunk            Seeds        Ncase  Particles");
+    for c in &chunks {
+        let (ixx, jxx) = c.chunk.seed;
+        let particles = match c.particle_range {
+            Some((start, end)) => format!("{}..{}", start, end),
+            None => "-".to_string(),
+        };
+        let seeds = format!("{} {}", ixx, jxx);
+        println!("{:>5} {:>16} {:>12}  {}", c.chunk.index, seeds, c.ncase, particles);
+    }
+    let application = &sim.prototype.application;
+    for c in &chunks {
+        let working_dir = match c.working_dir {
+            Some(ref d) => shell_quote(&d.to_string_lossy()),
+            None => format!("\"$EGS_HOME\"/{}", shell_quote(application)),
+        };
+        let name = c.egsinp.file_name().unwrap_or_default().to_string_lossy();
+        println!("# chunk {}", c.chunk.index);
+        println!(
+            "cd {} && cp {} {} && {}",
+            working_dir,
+            shell_quote(&c.egsinp.to_string_lossy()),
+            shell_quote(&name),
+            c.command
+        );
+    }
+    Ok(())
+}
+
+/// Whether all chunks of `out` succeeded, so that none will be run again.
+/// Until then, the checkpoints refer to the phase space files of their chunks.
+fn is_complete(out: &ParSimReport) -> bool {
+    out.single_runs.iter().all(SingSimReport::succeeded)
+}
+
+/// Remove the checkpoints of a run, unless some of its chunks should be run again.
+fn finish_checkpoints(checkpoints: &Checkpoints, out: &ParSimReport) -> Result<()> {
+    if is_complete(out) {
+        checkpoints.remove()
+    } else {
+        println!(
+            "Kept finished chunks in {:?}, running again only runs the others.",
+            checkpoints.dir()
+        );
+        Ok(())
+    }
+}
+
+/// Fail after the reports of an interrupted run have been written.
+fn check_interrupted() -> Result<()> {
+    match runner::interrupted() {
+        Some(sig) => bail!("Interrupted by {}", runner::signal_name(sig)),
+        None => Ok(()),
     }
 }
 
@@ -329,6 +757,9 @@ arg_enum!{
 struct ShowConfig {
     path: PathBuf,
     what: ShowWhat,
+    timeline: bool,
+    ratio: Option<(String, String)>,
+    expression: Option<String>,
 }
 
 impl SubCmd for ShowConfig {
@@ -336,11 +767,36 @@ impl SubCmd for ShowConfig {
         let path = m.get_abspath("PATH")?;
         // TODO get_enum
         let what = value_t!(m, "WHAT", ShowWhat).chain_err(|| "Could not parse argument")?;
-        Ok(ShowConfig { path, what })
+        let timeline = m.is_present("TIMELINE");
+        let ratio = m.values_of("RATIO").map(|mut v| {
+            let num = v.next().unwrap_or_default().to_string();
+            let den = v.next().unwrap_or_default().to_string();
+            (num, den)
+        });
+        let expression = m.value_of("EXPRESSION").map(str::to_string);
+        Ok(ShowConfig {
+            path,
+            what,
+            timeline,
+            ratio,
+            expression,
+        })
     }
 
     fn run(&self) -> Result<()> {
         let r: ParSimReport = load(&self.path)?;
+        if self.timeline {
+            print!("{}", r.to_string_timeline());
+            return Ok(());
+        }
+        if let Some((ref num, ref den)) = self.ratio {
+            println!("{}", r.to_string_ratio(num, den)?);
+            return Ok(());
+        }
+        if let Some(ref expression) = self.expression {
+            println!("{}", r.to_string_expression(expression)?);
+            return Ok(());
+        }
         let s = match self.what {
             ShowWhat::Smart => r.to_string_smart(),
             ShowWhat::All => r.to_string_all(),
@@ -353,15 +809,27 @@ impl SubCmd for ShowConfig {
 
 #[derive(Debug)]
 struct RunConfig {
-    inputpath: PathBuf,
+    inputpaths: Vec<PathBuf>, // files, directories or glob patterns
     application: String,
     outputpath: PathBuf,
     pegsfile: String,
     seeds: Option<Vec<Seed>>,
     ncases: Option<Vec<u64>>,
     nthreads: usize,
-    dir: bool, // run all files in a directory
+    recursive: bool,
     cleanup: bool,
+    limits: Limits,
+    progress: ProgressMode,
+    retries: usize,
+    jobs: usize,
+    pin_cpus: bool,
+    max_failed_chunks: Option<usize>,
+    env: EgsEnv,
+    extra_args: Option<Vec<String>>,
+    target: Option<Target>,
+    cache: Option<Cache>,
+    backend: Backend,
+    dry_run: bool,
 }
 
 impl RunConfig {
@@ -376,6 +844,9 @@ impl RunConfig {
                 }
             }
         }
+        if self.backend == Backend::Native && self.target.is_some() {
+            bail!("--target-rstd cannot be used with --backend native.");
+        }
         Ok(())
     }
 
@@ -383,17 +854,66 @@ impl RunConfig {
         SingSimInput::from_egsinp_path(&self.application, input_path, &self.pegsfile)
     }
 
-    fn run_par_input(&self, p: &ParSimInput, output_path: &Path) -> Result<()> {
+    fn run_options(&self) -> RunOptions {
+        RunOptions {
+            cleanup: self.cleanup,
+            limits: self.limits.clone(),
+            progress: self.progress,
+            retries: self.retries,
+            jobs: self.jobs,
+            pin_cpus: self.pin_cpus,
+            backend: self.backend,
+            ..RunOptions::new()
+        }
+    }
+
+    /// Run `p` and write its report to `output_path`.
+    fn run_par_input(
+        &self,
+        p: &ParSimInput,
+        output_path: &Path,
+        options: &RunOptions,
+    ) -> Result<ParSimReport> {
         match output_path.parent() {
             None => {}
             Some(d) => fs::create_dir_all(d)
                 .chain_err(|| format!("Cannot create output directory at {:?}", output_path))?,
         };
-        let out = p.run_with_cleanup_option(self.cleanup)
-            .chain_err(|| "Error running parallel simulation")?
-            .report();
-        println!("{}", out);
-        save(output_path, &out)
+        let checkpoints = Checkpoints::for_output(output_path);
+        let nrestored = checkpoints.count();
+        if nrestored > 0 {
+            println!(
+                "Resuming from {} finished chunks in {:?}",
+                nrestored,
+                checkpoints.dir()
+            );
+        }
+        let options = RunOptions {
+            checkpoints: Some(checkpoints.clone()),
+            cache: self.cache.clone(),
+            ..options.clone()
+        };
+        let mut out = match self.target {
+            Some(ref target) => {
+                let run = run_adaptive(p, &options, target)
+                    .chain_err(|| "Error running adaptive simulation")?;
+                run.combine_phsp(output_path, self.cleanup && is_complete(&run.report))?;
+                run.report
+            }
+            None => {
+                let fin = p.run_with_options(&options)
+                    .chain_err(|| "Error running parallel simulation")?;
+                let out = fin.report();
+                fin.combine_phsp(output_path, self.cleanup && is_complete(&out))?;
+                fin.keep_egsdat(output_path)?;
+                out
+            }
+        };
+        out.record_provenance();
+        save(output_path, &out)?;
+        out.save_dose3d(output_path)?;
+        finish_checkpoints(&checkpoints, &out)?;
+        Ok(out)
     }
 
     fn is_input_ext(s: &str) -> bool {
@@ -408,51 +928,169 @@ impl RunConfig {
         Self::is_input_ext(ext)
     }
 
+    /// Input files matching the glob `pattern`, with the directory
+    /// before the first wildcard as their base.
+    fn glob_inputs(pattern: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let base: PathBuf = pattern
+            .components()
+            .take_while(|c| !is_glob(c.as_os_str()))
+            .collect();
+        let spattern = pattern
+            .to_str()
+            .chain_err(|| format!("Cannot convert pattern to_str {:?}", pattern))?;
+        let mut ret = Vec::new();
+        for entry in glob::glob(spattern).chain_err(|| format!("Bad glob pattern {:?}", spattern))? {
+            let path = entry.chain_err(|| cannot_read(&spattern))?;
+            if path.is_file() && Self::has_input_ext(&path) {
+                ret.push((path, base.clone()));
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Pairs of input and output paths. A single input file is written to
+    /// OUTPUT, otherwise OUTPUT is a directory mirroring the input layout.
+    /// Like in a shell, a pattern that matches nothing is taken literally.
     fn create_input_output_paths(&self) -> Result<Vec<(PathBuf, PathBuf)>> {
-        let ret = if self.dir {
-            read_paths_in_dir(&self.inputpath)?
-                .iter()
-                .filter(|p| Self::has_input_ext(p))
-                .map(|inp| {
-                    let filestem = inp.file_stem().unwrap();
-                    let mut outp = self.outputpath.clone();
-                    outp.push(filestem);
-                    outp.set_extension("henout");
-                    (inp.clone(), outp)
-                })
-                .collect()
-        } else {
-            vec![(self.inputpath.clone(), self.outputpath.clone())]
-        };
+        let mut single = self.inputpaths.len() == 1;
+        // each input file with the directory its output path is relative to
+        let mut inputs: Vec<(PathBuf, PathBuf)> = Vec::new();
+        for inp in &self.inputpaths {
+            let matches = if is_glob(inp.as_os_str()) && !inp.exists() {
+                Self::glob_inputs(inp)?
+            } else {
+                Vec::new()
+            };
+            if !matches.is_empty() {
+                single = false;
+                inputs.extend(matches);
+            } else if inp.is_dir() {
+                single = false;
+                for path in read_files_in_dir(inp, self.recursive)? {
+                    if Self::has_input_ext(&path) {
+                        inputs.push((path, inp.clone()));
+                    }
+                }
+            } else {
+                let base = inp.parent().unwrap_or(Path::new("/")).to_path_buf();
+                inputs.push((inp.clone(), base));
+            }
+        }
+        if single {
+            return Ok(vec![(self.inputpaths[0].clone(), self.outputpath.clone())]);
+        }
+        let ret: Vec<(PathBuf, PathBuf)> = inputs
+            .into_iter()
+            .map(|(inp, base)| {
+                let rel = inp.strip_prefix(&base).unwrap_or(&inp).to_path_buf();
+                let outp = self.outputpath.join(rel).with_extension("henout");
+                (inp, outp)
+            })
+            .collect();
+        if !has_unique_elements(ret.iter().map(|(_, outp)| outp)) {
+            bail!("Several inputs would be written to the same output in {:?}", self.outputpath);
+        }
         Ok(ret)
     }
 
+    fn load_input(&self, inp: &Path) -> Result<ParSimInput> {
+        let ext = inp.extension()
+            .unwrap_or(OsStr::new("fail"))
+            .to_str()
+            .unwrap_or("fail");
+        let mut sim: ParSimInput = match ext {
+            "heninp" => load(inp)?,
+            _ => self.create_sing_sim_input(inp)?.split_fancy(
+                self.ncases.clone(),
+                self.seeds.clone(),
+                self.nthreads,
+            )?,
+        };
+        if self.max_failed_chunks.is_some() {
+            sim.max_failed_chunks = self.max_failed_chunks;
+        }
+        sim.prototype.env = self.env
+            .or(&sim.prototype.env)
+            .resolve(&sim.prototype.application);
+        if let Some(ref args) = self.extra_args {
+            sim.prototype.extra_args = args.clone();
+        }
+        Ok(sim)
+    }
+
     fn run(&self) -> Result<()> {
         let paths = self.create_input_output_paths()?;
-        for (inp, outp) in paths {
-            let ext = inp.extension()
-                .unwrap_or(OsStr::new("fail"))
-                .to_str()
-                .unwrap_or("fail");
-            let sim = match ext {
-                "heninp" => load(&inp)?,
-                _ => self.create_sing_sim_input(&inp)?.split_fancy(
-                    self.ncases.clone(),
-                    self.seeds.clone(),
-                    self.nthreads,
-                )?,
-            };
-            self.run_par_input(&sim, &outp)?;
+        // fail on bad inputs before anything runs
+        let sims = paths
+            .iter()
+            .map(|(inp, _)| self.load_input(inp).chain_err(|| format!("Cannot load {:?}", inp)))
+            .collect::<Result<Vec<ParSimInput>>>()?;
+        if self.dry_run {
+            for ((_, outp), sim) in paths.iter().zip(&sims) {
+                print_dry_run(sim, &dry_run_dir(outp))?;
+            }
+            return Ok(());
+        }
+        runner::install_signal_handlers();
+        if let [(_, ref outp)] = paths[..] {
+            let out = self.run_par_input(&sims[0], outp, &self.run_options())?;
+            println!("{}", out);
+            out.check_failures()?;
+            return check_interrupted();
+        }
+        // the chunks of all inputs share the slots, so that no core idles
+        // while the last chunks of an input finish
+        let options = RunOptions {
+            progress: ProgressMode::Off,
+            slots: Some(Arc::new(Slots::new(self.jobs))),
+            ..self.run_options()
+        };
+        let ntotal = paths.len();
+        let ndone = AtomicUsize::new(0);
+        let items: Vec<(&ParSimInput, &PathBuf)> =
+            sims.iter().zip(paths.iter().map(|(_, outp)| outp)).collect();
+        // an input that is running keeps at least one slot busy, and its
+        // chunks only get threads once they got a slot
+        let results = run_queue(&items, self.jobs, |_, &(sim, outp)| {
+            let res = self.run_par_input(sim, outp, &options)
+                .and_then(|out| out.check_failures());
+            let k = ndone.fetch_add(1, Ordering::SeqCst) + 1;
+            match res {
+                Ok(()) => println!("Wrote {:?} ({}/{})", outp, k, ntotal),
+                Err(ref e) => eprintln!("Failed {:?} ({}/{}): {}", outp, k, ntotal, e.display_chain()),
+            }
+            res
+        });
+        check_interrupted()?;
+        let nfailed = results.iter().filter(|r| r.is_err()).count();
+        if nfailed > 0 {
+            bail!("{} of {} inputs failed", nfailed, ntotal);
         }
         Ok(())
     }
 }
 
+/// Whether `s` contains glob wildcards.
+fn is_glob(s: &OsStr) -> bool {
+    s.to_string_lossy().contains(&['*', '?', '['][..])
+}
+
 impl SubCmd for RunConfig {
     fn parse(m: &ArgMatches) -> Result<RunConfig> {
-        let inputpath = m.get_abspath("INPUT")?;
-        let dir = inputpath.is_dir();
-        let outputpath = m.get_abspath("OUTPUT")?;
+        let chunk: Option<usize> = m.get_parse_option("CHUNK")?;
+        // substitute {chunk} in the paths, e.g. for the tasks of a job array
+        let path = |s: &str| -> Result<PathBuf> {
+            match chunk {
+                None => abspath_from_string(s),
+                Some(i) => abspath_from_string(&template::expand(s, &[("chunk", i.to_string())])?),
+            }
+        };
+        let inputpaths = m.values_of("INPUT")
+            .chain_err(|| "ArgMatches do not contain INPUT")?
+            .map(path)
+            .collect::<Result<Vec<PathBuf>>>()?;
+        let recursive = m.is_present("RECURSIVE");
+        let outputpath = path(m.get("OUTPUT")?)?;
         let application = m.get_string("APPLICATION")?;
         let pegsfile = m.get_string("PEGSFILE")?;
         let nthreads = m.get_parse("NTHREADS").unwrap_or(num_cpus::get());
@@ -471,16 +1109,40 @@ impl SubCmd for RunConfig {
             }
         };
         let cleanup = m.get_parse("CLEANUP")?;
+        let limits = parse_limits(m)?;
+        let progress = parse_progress(m)?;
+        let retries = m.get_parse("RETRIES")?;
+        let jobs = parse_jobs(m)?;
+        let pin_cpus = parse_pin_cpus(m)?;
+        let max_failed_chunks = m.get_parse_option("MAX_FAILED_CHUNKS")?;
+        let env = parse_egs_env(m)?;
+        let extra_args = parse_extra_args(m)?;
+        let target = parse_target(m)?;
+        let cache = parse_cache(m)?;
+        let backend = m.get_parse("BACKEND")?;
+        let dry_run = m.is_present("DRY_RUN");
         let ret = RunConfig {
-            inputpath,
+            inputpaths,
             application,
             outputpath,
             pegsfile,
             nthreads,
-            dir,
+            recursive,
             ncases,
             seeds,
             cleanup,
+            limits,
+            progress,
+            retries,
+            jobs,
+            pin_cpus,
+            max_failed_chunks,
+            env,
+            extra_args,
+            target,
+            cache,
+            backend,
+            dry_run,
         };
         ret.validate()?;
         Ok(ret)
@@ -499,9 +1161,23 @@ pub fn app_main() -> Result<()> {
         ("show", Some(m)) => ShowConfig::main(m),
         ("view", Some(m)) => ViewConfig::main(m),
         ("rerun", Some(m)) => RerunConfig::main(m),
+        ("verify", Some(m)) => VerifyConfig::main(m),
         ("fmt", Some(m)) => FormatConfig::main(m),
         ("split", Some(m)) => SplitConfig::main(m),
         ("combine", Some(m)) => CombineConfig::main(m),
+        ("submit", Some(m)) => SubmitConfig::main(m),
+        ("poll", Some(m)) => PollConfig::main(m),
+        ("phsp", Some(m)) => match m.subcommand() {
+            ("stats", Some(m)) => PhspStatsConfig::main(m),
+            ("combine", Some(m)) => PhspCombineConfig::main(m),
+            x => bail!("Unknown phsp subcommand {:?}. Try hen phsp --help", x),
+        },
+        ("cache", Some(m)) => match m.subcommand() {
+            ("info", Some(m)) => CacheInfoConfig::main(m),
+            ("clear", Some(m)) => CacheClearConfig::main(m),
+            ("prune", Some(m)) => CachePruneConfig::main(m),
+            x => bail!("Unknown cache subcommand {:?}. Try hen cache --help", x),
+        },
         ("", _) => Ok(println!(
             "Welcome to hen!\n{}\nTry hen --help",
             HenInfo::new()
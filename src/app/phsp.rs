@@ -0,0 +1,49 @@
+use clap::ArgMatches;
+use std::path::PathBuf;
+use app::util::{abspath_from_string, GetMatch, SubCmd};
+use errors::*;
+use phsp::{self, PhspStats};
+
+#[derive(Debug)]
+pub struct PhspStatsConfig {
+    inputpath: PathBuf,
+}
+
+impl SubCmd for PhspStatsConfig {
+    fn parse(m: &ArgMatches) -> Result<Self> {
+        let inputpath = m.get_abspath("INPUT")?;
+        Ok(PhspStatsConfig { inputpath })
+    }
+
+    fn run(&self) -> Result<()> {
+        let stats = PhspStats::from_path(&self.inputpath)?;
+        println!("{}", stats);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct PhspCombineConfig {
+    inputpaths: Vec<PathBuf>,
+    outputpath: PathBuf,
+}
+
+impl SubCmd for PhspCombineConfig {
+    fn parse(m: &ArgMatches) -> Result<Self> {
+        let inputpaths = m.values_of("INPUT")
+            .ok_or("ArgMatches do not contain INPUT")?
+            .map(abspath_from_string)
+            .collect::<Result<Vec<PathBuf>>>()?;
+        let outputpath = m.get_abspath("OUTPUT")?;
+        Ok(PhspCombineConfig {
+            inputpaths,
+            outputpath,
+        })
+    }
+
+    fn run(&self) -> Result<()> {
+        let header = phsp::combine(&self.inputpaths, &self.outputpath)?;
+        println!("Wrote {} particles to {:?}", header.nparticles, self.outputpath);
+        Ok(())
+    }
+}
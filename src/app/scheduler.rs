@@ -0,0 +1,66 @@
+use clap::ArgMatches;
+use std::path::PathBuf;
+use app::util::{GetMatch, SubCmd};
+use errors::*;
+use scheduler::Job;
+use util::{load, save};
+
+#[derive(Debug)]
+pub struct SubmitConfig {
+    path: PathBuf,
+    submit_cmd: Option<String>,
+}
+
+impl SubCmd for SubmitConfig {
+    fn parse(m: &ArgMatches) -> Result<Self> {
+        let path = m.get_abspath("JOB")?;
+        let submit_cmd = m.value_of("SUBMIT_CMD").map(str::to_string);
+        Ok(SubmitConfig { path, submit_cmd })
+    }
+
+    fn run(&self) -> Result<()> {
+        let mut job: Job = load(&self.path)?;
+        if let Some(ref cmd) = self.submit_cmd {
+            job.submit_cmd = cmd.clone();
+        }
+        if let Some(ref id) = job.job_id {
+            bail!("Job {:?} was already submitted as {}", self.path, id);
+        }
+        let id = job.submit()?;
+        save(&self.path, &job)?;
+        println!("Submitted {} with job id {}", job.name, id);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct PollConfig {
+    path: PathBuf,
+    poll_cmd: Option<String>,
+}
+
+impl SubCmd for PollConfig {
+    fn parse(m: &ArgMatches) -> Result<Self> {
+        let path = m.get_abspath("JOB")?;
+        let poll_cmd = m.value_of("POLL_CMD").map(str::to_string);
+        Ok(PollConfig { path, poll_cmd })
+    }
+
+    fn run(&self) -> Result<()> {
+        let mut job: Job = load(&self.path)?;
+        if let Some(ref cmd) = self.poll_cmd {
+            job.poll_cmd = cmd.clone();
+        }
+        print!("{}", job.poll()?);
+        let missing = job.missing_outputs();
+        println!(
+            "Finished chunks: {}/{}",
+            job.outputs.len() - missing.len(),
+            job.outputs.len()
+        );
+        for path in missing {
+            println!("Missing: {:?}", path);
+        }
+        Ok(())
+    }
+}
@@ -3,6 +3,14 @@ use std::path::{Path, PathBuf};
 use std::env::current_dir;
 use std;
 use errors::*;
+use runner::Limits;
+use progress::ProgressMode;
+use environment::EgsEnv;
+use template;
+use adaptive::Target;
+use cache::Cache;
+use num_cpus;
+use regex::Regex;
 
 pub fn arg_input() -> Arg<'static, 'static> {
     Arg::with_name("INPUT")
@@ -48,6 +56,307 @@ pub fn arg_application() -> Arg<'static, 'static> {
         .takes_value(true)
 }
 
+pub fn arg_timeout() -> Arg<'static, 'static> {
+    Arg::with_name("TIMEOUT")
+        .long("timeout")
+        .help("Wall-clock time in seconds after which a chunk is killed.")
+        .takes_value(true)
+}
+
+pub fn arg_max_memory() -> Arg<'static, 'static> {
+    Arg::with_name("MAX_MEMORY")
+        .long("max-memory")
+        .help("Maximal memory in MB that each chunk may allocate.")
+        .takes_value(true)
+}
+
+pub fn arg_max_cpu_time() -> Arg<'static, 'static> {
+    Arg::with_name("MAX_CPU_TIME")
+        .long("max-cpu-time")
+        .help("Maximal cpu time in seconds of each chunk.")
+        .takes_value(true)
+}
+
+pub fn parse_limits(m: &ArgMatches) -> Result<Limits> {
+    let timeout: Option<f64> = m.get_parse_option("TIMEOUT")?;
+    if let Some(t) = timeout {
+        if !(t.is_finite() && t > 0.) {
+            bail!("TIMEOUT must be a positive number of seconds, got {}", t);
+        }
+    }
+    let memory = match m.get_parse_option::<u64>("MAX_MEMORY")? {
+        Some(mb) => Some(mb.checked_mul(1024 * 1024)
+            .ok_or_else(|| format!("MAX_MEMORY of {} MB is too large", mb))?),
+        None => None,
+    };
+    let cpu_time = m.get_parse_option("MAX_CPU_TIME")?;
+    let nice = m.get_parse_option("NICE")?;
+    Ok(Limits {
+        timeout,
+        memory,
+        cpu_time,
+        nice,
+        cpu: None,
+    })
+}
+
+pub fn arg_nice() -> Arg<'static, 'static> {
+    Arg::with_name("NICE")
+        .long("nice")
+        .help("Run the application processes with this niceness, e.g. 10.")
+        .takes_value(true)
+}
+
+pub fn args_jobs() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("JOBS")
+            .long("jobs")
+            .short("j")
+            .help("Maximum number of chunks that run at the same time. Defaults to the number of cores.")
+            .takes_value(true),
+        Arg::with_name("PIN_CPUS")
+            .long("pin-cpus")
+            .help("Pin each running chunk to its own CPU. Only supported on Linux."),
+    ]
+}
+
+pub fn parse_jobs(m: &ArgMatches) -> Result<usize> {
+    let jobs = m.get_parse_option("JOBS")?.unwrap_or_else(num_cpus::get);
+    if jobs == 0 {
+        bail!("JOBS > 0 must hold.");
+    }
+    Ok(jobs)
+}
+
+pub fn parse_pin_cpus(m: &ArgMatches) -> Result<bool> {
+    let pin_cpus = m.is_present("PIN_CPUS");
+    if pin_cpus && !cfg!(target_os = "linux") {
+        bail!("--pin-cpus is only supported on Linux.");
+    }
+    Ok(pin_cpus)
+}
+
+pub fn arg_no_progress() -> Arg<'static, 'static> {
+    Arg::with_name("NO_PROGRESS")
+        .long("no-progress")
+        .help("Do not report progress while the chunks run.")
+}
+
+pub fn arg_progress_interval() -> Arg<'static, 'static> {
+    Arg::with_name("PROGRESS_INTERVAL")
+        .long("progress-interval")
+        .help("Seconds between progress lines when stdout is not a terminal.")
+        .takes_value(true)
+        .default_value("60")
+}
+
+pub fn parse_progress(m: &ArgMatches) -> Result<ProgressMode> {
+    if m.is_present("NO_PROGRESS") {
+        return Ok(ProgressMode::Off);
+    }
+    let interval: f64 = m.get_parse("PROGRESS_INTERVAL")?;
+    if interval.is_nan() || interval <= 0. {
+        bail!("PROGRESS_INTERVAL > 0 must hold.");
+    }
+    Ok(ProgressMode::auto(interval))
+}
+
+pub fn arg_backend() -> Arg<'static, 'static> {
+    Arg::with_name("BACKEND")
+        .long("backend")
+        .help("hen runs each chunk with seeds of its own and combines the parsed doses. native runs the chunks as jobs of the application's parallel mode and combines their .egsdat files with the application, which are kept next to the output. rerun uses the backend of the report.")
+        .possible_values(&["hen", "native"])
+        .default_value("hen")
+        .takes_value(true)
+}
+
+pub fn arg_dry_run() -> Arg<'static, 'static> {
+    Arg::with_name("DRY_RUN")
+        .long("dry-run")
+        .help("Do not run anything, but write the input of each chunk to a .dryrun directory next to the output and print how it would be run.")
+}
+
+pub fn arg_retries() -> Arg<'static, 'static> {
+    Arg::with_name("RETRIES")
+        .long("retries")
+        .help("How often a failed chunk is rerun with a fresh seed.")
+        .takes_value(true)
+        .default_value("0")
+}
+
+pub fn arg_max_failed_chunks() -> Arg<'static, 'static> {
+    Arg::with_name("MAX_FAILED_CHUNKS")
+        .long("max-failed-chunks")
+        .help("Number of chunks that may still fail after all retries or be killed. They are excluded from the statistics. By default no chunk may.")
+        .takes_value(true)
+}
+
+pub fn arg_extra_args() -> Arg<'static, 'static> {
+    Arg::with_name("ARG")
+        .long("arg")
+        .help("Extra argument for the application, may be repeated. Placeholders {chunk}, {seed1}, {seed2}, {name}, {input}, {pegsfile} and {application} are filled in for each chunk.")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .allow_hyphen_values(true)
+}
+
+pub fn parse_extra_args(m: &ArgMatches) -> Result<Option<Vec<String>>> {
+    let args: Vec<String> = match m.values_of("ARG") {
+        Some(values) => values.map(|s| s.to_string()).collect(),
+        None => return Ok(None),
+    };
+    for arg in &args {
+        template::validate(arg)?;
+    }
+    Ok(Some(args))
+}
+
+/// Options selecting the EGSnrc installation.
+pub fn args_egs_env() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("EGS_HOME")
+            .long("egs-home")
+            .help("EGS_HOME of the applications, instead of the environment variable.")
+            .takes_value(true),
+        Arg::with_name("HEN_HOUSE")
+            .long("hen-house")
+            .help("HEN_HOUSE of the EGSnrc installation, instead of the environment variable.")
+            .takes_value(true),
+        Arg::with_name("EGS_CONFIG")
+            .long("egs-config")
+            .help("EGS_CONFIG of the EGSnrc installation, instead of the environment variable.")
+            .takes_value(true),
+        Arg::with_name("EXECUTABLE")
+            .long("executable")
+            .help("Path of the application binary, instead of looking it up in PATH.")
+            .takes_value(true),
+        Arg::with_name("EGS_ENV_FILE")
+            .long("egs-env-file")
+            .env("HEN_EGS_ENV")
+            .help("JSON file with the settings below, e.g. {\"egs_home\": \"/opt/egs_home\", \"vars\": [[\"LANG\", \"C\"]]}. Options given on the command line take precedence.")
+            .takes_value(true),
+        Arg::with_name("ENV")
+            .long("env")
+            .help("Additional environment variable KEY=VALUE for the application.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    ]
+}
+
+pub fn parse_egs_env(m: &ArgMatches) -> Result<EgsEnv> {
+    let path = |key: &str| -> Result<Option<PathBuf>> {
+        match m.value_of(key) {
+            Some(s) => abspath_from_string(s).map(Some),
+            None => Ok(None),
+        }
+    };
+    let mut vars = Vec::new();
+    for s in m.values_of("ENV").into_iter().flatten() {
+        match s.find('=') {
+            Some(i) if i > 0 => vars.push((s[..i].to_string(), s[i + 1..].to_string())),
+            _ => bail!("Expected KEY=VALUE, got {:?}", s),
+        }
+    }
+    let cli = EgsEnv {
+        egs_home: path("EGS_HOME")?,
+        hen_house: path("HEN_HOUSE")?,
+        egs_config: path("EGS_CONFIG")?,
+        executable: path("EXECUTABLE")?,
+        vars,
+    };
+    match path("EGS_ENV_FILE")? {
+        Some(file) => Ok(cli.or(&EgsEnv::load(&file)?)),
+        None => Ok(cli),
+    }
+}
+
+pub fn args_target() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("TARGET_RSTD")
+            .long("target-rstd")
+            .help("Run more chunks until the relative uncertainty of the dose is at most this, e.g. 0.005.")
+            .takes_value(true),
+        Arg::with_name("GEOMETRY")
+            .long("geometry")
+            .help("Only consider the dose in geometries matching this regex for --target-rstd.")
+            .requires("TARGET_RSTD")
+            .takes_value(true),
+        Arg::with_name("CPU_BUDGET")
+            .long("cpu-budget")
+            .help("Maximum total CPU time in seconds of all chunks of a --target-rstd run.")
+            .requires("TARGET_RSTD")
+            .takes_value(true),
+    ]
+}
+
+pub fn parse_target(m: &ArgMatches) -> Result<Option<Target>> {
+    let rstd: f64 = match m.get_parse_option("TARGET_RSTD")? {
+        Some(rstd) => rstd,
+        None => return Ok(None),
+    };
+    if rstd.is_nan() || rstd <= 0. {
+        bail!("TARGET_RSTD > 0 must hold.");
+    }
+    let geometry = match m.value_of("GEOMETRY") {
+        Some(s) => Some(Regex::new(s).chain_err(|| format!("Cannot parse GEOMETRY {:?}", s))?),
+        None => None,
+    };
+    let cpu_budget = m.get_parse_option("CPU_BUDGET")?;
+    Ok(Some(Target {
+        rstd,
+        geometry,
+        cpu_budget,
+    }))
+}
+
+pub fn arg_cache_dir() -> Arg<'static, 'static> {
+    Arg::with_name("CACHE_DIR")
+        .long("cache-dir")
+        .env("HEN_CACHE_DIR")
+        .help("Reuse results of identical chunks stored in this directory, and store new ones.")
+        .takes_value(true)
+}
+
+pub fn args_cache() -> Vec<Arg<'static, 'static>> {
+    vec![
+        arg_cache_dir(),
+        Arg::with_name("NO_CACHE")
+            .long("no-cache")
+            .help("Neither reuse nor store results in the cache directory."),
+    ]
+}
+
+pub fn parse_cache(m: &ArgMatches) -> Result<Option<Cache>> {
+    if m.is_present("NO_CACHE") || !m.is_present("CACHE_DIR") {
+        return Ok(None);
+    }
+    let dir = m.get_abspath("CACHE_DIR")?;
+    Ok(Some(Cache::new(&dir)))
+}
+
+pub fn arg_job() -> Arg<'static, 'static> {
+    Arg::with_name("JOB")
+        .help("Path to a .henjob file written by hen split --scheduler.")
+        .required(true)
+        .index(1)
+}
+
+pub fn arg_submit_cmd() -> Arg<'static, 'static> {
+    Arg::with_name("SUBMIT_CMD")
+        .long("submit-cmd")
+        .help("Shell command submitting the job script {script}. It must print the job id.")
+        .takes_value(true)
+}
+
+pub fn arg_poll_cmd() -> Arg<'static, 'static> {
+    Arg::with_name("POLL_CMD")
+        .long("poll-cmd")
+        .help("Shell command showing the state of the job {job}.")
+        .takes_value(true)
+}
+
 pub fn arg_report() -> Arg<'static, 'static> {
     Arg::with_name("PATH")
         .help("Path to a .henout file containing simulation report.")
@@ -88,6 +397,16 @@ pub trait GetMatch {
             Err(_) => bail!("Cannot parse {} from {}", key, s),
         }
     }
+
+    fn get_parse_option<T>(&self, key: &str) -> Result<Option<T>>
+    where
+        T: std::str::FromStr,
+    {
+        match self.get(key) {
+            Err(_) => Ok(None),
+            Ok(_) => self.get_parse(key).map(Some),
+        }
+    }
 }
 
 impl<'t> GetMatch for ArgMatches<'t> {
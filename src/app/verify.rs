@@ -0,0 +1,136 @@
+use clap::ArgMatches;
+use std::fs;
+use std::path::PathBuf;
+use app::util::{parse_egs_env, parse_limits, GetMatch, SubCmd};
+use environment::EgsEnv;
+use errors::*;
+use omittable::Omittable;
+use provenance::Provenance;
+use runner::Limits;
+use simulation::{Chunk, ParSimReport, SingSimReport};
+use util::load;
+
+#[derive(Debug)]
+pub struct VerifyConfig {
+    path: PathBuf,
+    chunks: Vec<usize>,
+    limits: Limits,
+    env: EgsEnv,
+}
+
+/// Differences between the results of a chunk and its rerun. If the report
+/// recorded the digest of the printed results, they must match bit for bit,
+/// not only after parsing.
+fn compare(recorded: &SingSimReport, rerun: &SingSimReport) -> Vec<String> {
+    let mut ret = Vec::new();
+    if let (Omittable::Available(a), Omittable::Available(b)) = (&recorded.dose, &rerun.dose) {
+        if a.len() != b.len() {
+            ret.push(format!("{} doses instead of {}", b.len(), a.len()));
+        }
+        for ((name_a, a), (name_b, b)) in a.iter().zip(b.iter()) {
+            if name_a != name_b || a != b {
+                ret.push(format!("{}: {} instead of {}: {}", name_b, b, name_a, a));
+            }
+        }
+    } else if recorded.dose != rerun.dose {
+        ret.push(format!("Dose is {:?} instead of {:?}", rerun.dose, recorded.dose));
+    }
+    if recorded.dose3d != rerun.dose3d {
+        ret.push("3d dose differs".to_string());
+    }
+    if let (Some(a), Some(b)) = (&recorded.result_digest, &rerun.result_digest) {
+        if a != b && ret.is_empty() {
+            ret.push("Printed results differ, though they parse to the same values".to_string());
+        }
+    }
+    ret
+}
+
+impl SubCmd for VerifyConfig {
+    fn parse(m: &ArgMatches) -> Result<Self> {
+        let path = m.get_abspath("PATH")?;
+        let mut chunks = Vec::new();
+        for s in m.values_of("CHUNKS").into_iter().flatten() {
+            chunks.push(s.parse()
+                .chain_err(|| format!("Expected a chunk index, got {:?}", s))?);
+        }
+        if chunks.is_empty() {
+            chunks.push(0);
+        }
+        let limits = parse_limits(m)?;
+        let env = parse_egs_env(m)?;
+        Ok(VerifyConfig {
+            path,
+            chunks,
+            limits,
+            env,
+        })
+    }
+
+    fn run(&self) -> Result<()> {
+        let report: ParSimReport = load(&self.path)?;
+        if report.native_combine.is_some() {
+            bail!("Reports of the native backend cannot be verified chunk by chunk");
+        }
+        let mut sim = report.input.clone();
+        let application = sim.prototype.application.clone();
+        sim.prototype.env = self.env.or(&sim.prototype.env).resolve(&application);
+        if let Some(ref recorded) = report.provenance {
+            let diffs = recorded.differences(&Provenance::collect(&sim.prototype, ""));
+            if !diffs.is_empty() {
+                eprintln!("Warning: This machine differs from the one recorded in {:?}:", self.path);
+                for d in diffs {
+                    eprintln!("    {}", d);
+                }
+            }
+        }
+        if self.chunks
+            .iter()
+            .filter_map(|&i| report.single_runs.get(i))
+            .any(|r| r.result_digest.is_none())
+        {
+            eprintln!(
+                "Warning: {:?} records no digest of the printed results, comparing parsed doses only.",
+                self.path
+            );
+        }
+        let mut nmismatch = 0;
+        for &index in &self.chunks {
+            let recorded = report.single_runs.get(index).ok_or_else(|| {
+                format!("There is no chunk {}, only {}", index, report.single_runs.len())
+            })?;
+            if !recorded.succeeded() {
+                bail!("Chunk {} did not succeed in {:?}", index, self.path);
+            }
+            let input = sim.chunk_input(index)?;
+            let chunk = Chunk {
+                index,
+                seed: sim.seeds[index],
+            };
+            let fin = input.run_monitored(Some(&chunk), &self.limits, |_| {});
+            fin.cleanup();
+            for path in &fin.phsp_files {
+                fs::remove_file(path).chain_err(|| cannot_remove(path))?;
+            }
+            if !fin.succeeded() {
+                println!("Chunk {}: rerun failed", index);
+                nmismatch += 1;
+                continue;
+            }
+            let diffs = compare(recorded, &fin.report());
+            if diffs.is_empty() {
+                println!("Chunk {}: identical", index);
+            } else {
+                println!("Chunk {}: differs", index);
+                for d in diffs {
+                    println!("    {}", d);
+                }
+                nmismatch += 1;
+            }
+        }
+        if nmismatch > 0 {
+            bail!("{} of {} chunks do not match", nmismatch, self.chunks.len());
+        }
+        Ok(())
+    }
+}
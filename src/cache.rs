@@ -0,0 +1,242 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use sha3;
+use sha3::Digest;
+use errors::*;
+use simulation::{SingSimFinished, SingSimInput};
+use util::{load, save};
+
+/// Results of chunks, stored by a hash of everything the result depends on,
+/// so that identical chunks of different runs are simulated only once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+/// Number and total size of the entries of a cache.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+impl Cache {
+    pub fn new(dir: &Path) -> Self {
+        Cache {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Hash of the chunk content, application, pegsfile, extra arguments
+    /// and EGSnrc environment of `input`. Extra arguments may refer to the
+    /// chunk, so its `index` is part of the key if there are any.
+    pub fn key(input: &SingSimInput, index: usize) -> String {
+        let mut hasher = sha3::Sha3_256::default();
+        let mut feed = |s: &str| {
+            hasher.input(s.as_bytes());
+            hasher.input(b"\0");
+        };
+        feed(&input.checksum);
+        feed(&input.application);
+        feed(&input.pegsfile);
+        feed(&input.env.fingerprint(&input.application));
+        if !input.extra_args.is_empty() {
+            feed(&index.to_string());
+            for arg in &input.extra_args {
+                feed(arg);
+            }
+        }
+        format!("{:x}", hasher.result())
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(&key[..2]).join(format!("{}.json", key))
+    }
+
+    /// The cached result of running chunk `index` of `input`, if any.
+    pub fn get(&self, input: &SingSimInput, index: usize) -> Option<SingSimFinished> {
+        let path = self.path(&Cache::key(input, index));
+        if !path.exists() {
+            return None;
+        }
+        let mut fin: SingSimFinished = load(&path).ok()?;
+        if fin.input.checksum != input.checksum {
+            return None;
+        }
+        // the same content may come from a differently named file
+        fin.input = input.clone();
+        // mark as recently used for pruning
+        if let Ok(file) = fs::File::open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Some(fin)
+    }
+
+    /// Store `fin`, if it finished and does not refer to files outside the cache.
+    pub fn put(&self, fin: &SingSimFinished, index: usize) -> Result<()> {
+        if !fin.succeeded() || !fin.phsp_files.is_empty() {
+            return Ok(());
+        }
+        let path = self.path(&Cache::key(&fin.input, index));
+        let dir = path.parent().ok_or("Bad cache path")?;
+        fs::create_dir_all(dir).chain_err(|| cannot_create(&dir))?;
+        let tmp = path.with_extension("json.tmp");
+        save(&tmp, fin)?;
+        fs::rename(&tmp, &path).chain_err(|| cannot_write(&path))
+    }
+
+    /// Paths, sizes and last use of all entries, least recently used first.
+    fn entries(&self) -> Result<Vec<(PathBuf, u64, SystemTime)>> {
+        let mut ret = Vec::new();
+        if !self.dir.exists() {
+            return Ok(ret);
+        }
+        for sub in fs::read_dir(&self.dir).chain_err(|| cannot_read(&self.dir))? {
+            let sub = sub.chain_err(|| cannot_read(&self.dir))?.path();
+            if !sub.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&sub).chain_err(|| cannot_read(&sub))? {
+                let path = entry.chain_err(|| cannot_read(&sub))?.path();
+                if path.extension().is_none_or(|ext| ext != "json") {
+                    continue;
+                }
+                let meta = fs::metadata(&path).chain_err(|| cannot_read(&path))?;
+                let used = meta.modified().chain_err(|| cannot_read(&path))?;
+                ret.push((path, meta.len(), used));
+            }
+        }
+        ret.sort_by_key(|&(_, _, used)| used);
+        Ok(ret)
+    }
+
+    pub fn stats(&self) -> Result<CacheStats> {
+        let entries = self.entries()?;
+        Ok(CacheStats {
+            entries: entries.len(),
+            bytes: entries.iter().map(|&(_, len, _)| len).sum(),
+        })
+    }
+
+    /// Remove entries not used for `max_age`, then the least recently used
+    /// ones until at most `max_bytes` remain. Returns the number removed.
+    pub fn prune(&self, max_age: Option<Duration>, max_bytes: Option<u64>) -> Result<usize> {
+        let now = SystemTime::now();
+        let entries = self.entries()?;
+        let mut bytes: u64 = entries.iter().map(|&(_, len, _)| len).sum();
+        let mut removed = 0;
+        for (path, len, used) in entries {
+            let too_old = match max_age {
+                Some(age) => now.duration_since(used).map(|d| d > age).unwrap_or(false),
+                None => false,
+            };
+            let too_big = max_bytes.is_some_and(|max| bytes > max);
+            if !too_old && !too_big {
+                continue;
+            }
+            fs::remove_file(&path).chain_err(|| cannot_remove(&path))?;
+            bytes -= len;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Remove all entries, and the cache directory if nothing else is left
+    /// in it. Returns the number removed.
+    pub fn clear(&self) -> Result<usize> {
+        let entries = self.entries()?;
+        for &(ref path, _, _) in &entries {
+            fs::remove_file(path).chain_err(|| cannot_remove(path))?;
+        }
+        if self.dir.exists() {
+            for sub in fs::read_dir(&self.dir).chain_err(|| cannot_read(&self.dir))? {
+                let sub = sub.chain_err(|| cannot_read(&self.dir))?.path();
+                if sub.is_dir() {
+                    // fails if the directory is not empty, which is fine
+                    let _ = fs::remove_dir(&sub);
+                }
+            }
+            let _ = fs::remove_dir(&self.dir);
+        }
+        Ok(entries.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use util::asset_path;
+
+    fn finished(filename: &str) -> SingSimFinished {
+        let mut input = SingSimInput::from_egsinp_path(
+            "egs_chamber",
+            &asset_path().join("three_calc_geos.egsinp"),
+            "521icru",
+        ).unwrap();
+        input.filename = filename.to_string();
+        let stdout = fs::read_to_string(asset_path().join("statistical_accuracy_reached.log"))
+            .unwrap();
+        SingSimFinished {
+            input,
+            stderr: String::new(),
+            stdout,
+            exit_status: 0,
+            dose3d: None,
+            phsp_files: Vec::new(),
+            killed: None,
+            error: None,
+            working_name: Some("abc".to_string()),
+            accounting: None,
+        }
+    }
+
+    #[test]
+    fn test_cache() {
+        let dir = tempdir().unwrap();
+        let cache = Cache::new(&dir.path().join("cache"));
+        let fin = finished("a.egsinp");
+        assert_eq!(cache.get(&fin.input, 0), None);
+        cache.put(&fin, 0).unwrap();
+        assert_eq!(cache.get(&fin.input, 0), Some(fin.clone()));
+        assert_eq!(cache.get(&fin.input, 1), Some(fin.clone()));
+
+        let renamed = finished("b.egsinp");
+        assert_eq!(cache.get(&renamed.input, 0), Some(renamed.clone()));
+
+        let mut other = fin.input.clone();
+        other.pegsfile = "700icru".to_string();
+        assert_eq!(cache.get(&other, 0), None);
+        other = fin.input.clone();
+        other.extra_args = vec!["{chunk}".to_string()];
+        assert_ne!(Cache::key(&other, 0), Cache::key(&other, 1));
+
+        let mut failed = finished("a.egsinp");
+        failed.exit_status = 1;
+        failed.input.pegsfile = "700icru".to_string();
+        cache.put(&failed, 0).unwrap();
+        assert_eq!(cache.stats().unwrap().entries, 1);
+
+        assert_eq!(cache.prune(Some(Duration::from_secs(3600)), None).unwrap(), 0);
+        assert_eq!(cache.prune(None, Some(0)).unwrap(), 1);
+        assert_eq!(cache.stats().unwrap().entries, 0);
+        cache.put(&fin, 0).unwrap();
+        assert_eq!(cache.clear().unwrap(), 1);
+        assert!(!cache.dir().exists());
+
+        // clearing a directory that is not only a cache keeps everything else
+        let shared = Cache::new(dir.path());
+        fs::create_dir(dir.path().join("notes")).unwrap();
+        fs::write(dir.path().join("notes/todo.txt"), "").unwrap();
+        fs::write(dir.path().join("data.txt"), "").unwrap();
+        shared.put(&fin, 0).unwrap();
+        assert_eq!(shared.clear().unwrap(), 1);
+        assert!(dir.path().join("notes/todo.txt").exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,168 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use errors::*;
+use simulation::{Seed, SingSimFinished, SingSimInput};
+use util::{load, save};
+
+/// Finished chunks of a run, written as soon as they complete, so that an
+/// interrupted run can be resumed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoints {
+    dir: PathBuf,
+}
+
+/// A finished chunk and the seed it ran with. A retried chunk ran with
+/// another seed than the one of the input it is stored under.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Checkpoint {
+    seed: Seed,
+    finished: SingSimFinished,
+}
+
+impl Checkpoints {
+    /// The sidecar directory `<output>.chunks` next to `output_path`.
+    pub fn for_output(output_path: &Path) -> Self {
+        let mut name = output_path
+            .file_name()
+            .map(|s| s.to_os_string())
+            .unwrap_or_default();
+        name.push(".chunks");
+        Checkpoints {
+            dir: output_path.with_file_name(name),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, seed: Seed) -> PathBuf {
+        let (ixx, jxx) = seed;
+        self.dir.join(format!("chunk_{}_{}.json", ixx, jxx))
+    }
+
+    fn checkpoint(&self, key: Seed) -> Option<Checkpoint> {
+        let path = self.path(key);
+        if !path.exists() {
+            return None;
+        }
+        let checkpoint: Checkpoint = load(&path).ok()?;
+        if checkpoint.finished.succeeded() {
+            Some(checkpoint)
+        } else {
+            None
+        }
+    }
+
+    /// The seed the chunk stored under the input seed `key` finished with.
+    pub fn seed(&self, key: Seed) -> Option<Seed> {
+        self.checkpoint(key).map(|c| c.seed)
+    }
+
+    /// The finished chunk stored under the input seed `key`, if it ran
+    /// exactly `input`.
+    pub fn load(&self, input: &SingSimInput, key: Seed) -> Option<SingSimFinished> {
+        self.checkpoint(key)
+            .map(|c| c.finished)
+            .filter(|fin| fin.input == *input)
+    }
+
+    /// Store `fin`, which ran with `seed`, under the seed `key` the input
+    /// of the run gave its chunk.
+    pub fn save(&self, fin: &SingSimFinished, key: Seed, seed: Seed) -> Result<()> {
+        fs::create_dir_all(&self.dir).chain_err(|| cannot_create(&self.dir))?;
+        let path = self.path(key);
+        // an interrupted write must not leave a truncated checkpoint
+        let tmp = path.with_extension("json.tmp");
+        let checkpoint = Checkpoint {
+            seed,
+            finished: fin.clone(),
+        };
+        save(&tmp, &checkpoint)?;
+        fs::rename(&tmp, &path).chain_err(|| cannot_write(&path))
+    }
+
+    /// Number of chunks written so far.
+    pub fn count(&self) -> usize {
+        match fs::read_dir(&self.dir) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .filter(|e| e.path().extension().is_some_and(|ext| ext == "json"))
+                .count(),
+            Err(_) => 0,
+        }
+    }
+
+    pub fn remove(&self) -> Result<()> {
+        if self.dir.exists() {
+            fs::remove_dir_all(&self.dir).chain_err(|| cannot_remove(&self.dir))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simulation::SingSimInputBuilder;
+    use tempfile::tempdir;
+    use util::asset_path;
+
+    #[test]
+    fn test_checkpoints() {
+        let dir = tempdir().unwrap();
+        let checkpoints = Checkpoints::for_output(&dir.path().join("out.henout"));
+        assert_eq!(checkpoints.dir(), dir.path().join("out.henout.chunks"));
+        assert_eq!(checkpoints.count(), 0);
+
+        let input = SingSimInput::from_egsinp_path(
+            "egs_chamber",
+            &asset_path().join("three_calc_geos.egsinp"),
+            "521icru",
+        ).unwrap();
+        let stdout = fs::read_to_string(asset_path().join("statistical_accuracy_reached.log"))
+            .unwrap();
+        let fin = SingSimFinished {
+            input: input.clone(),
+            stderr: String::new(),
+            stdout,
+            exit_status: 0,
+            dose3d: None,
+            phsp_files: Vec::new(),
+            killed: None,
+            error: None,
+            working_name: Some("abc".to_string()),
+            accounting: None,
+        };
+        checkpoints.save(&fin, (42, 1), (42, 1)).unwrap();
+        assert_eq!(checkpoints.count(), 1);
+        assert_eq!(checkpoints.load(&input, (42, 1)), Some(fin.clone()));
+        assert_eq!(checkpoints.seed((42, 1)), Some((42, 1)));
+        assert_eq!(checkpoints.load(&input, (42, 2)), None);
+        assert_eq!(checkpoints.seed((42, 2)), None);
+
+        // a retried chunk is found under the seed of the input
+        checkpoints.save(&fin, (42, 4), (43, 4)).unwrap();
+        assert_eq!(checkpoints.seed((42, 4)), Some((43, 4)));
+        assert_eq!(checkpoints.load(&input, (42, 4)), Some(fin.clone()));
+        let other = SingSimInputBuilder::new()
+            .application("egs_chamber")
+            .content("other")
+            .pegsfile("521icru")
+            .filename("other.egsinp")
+            .build()
+            .unwrap();
+        assert_eq!(checkpoints.load(&other, (42, 1)), None);
+
+        let failed = SingSimFinished {
+            exit_status: 1,
+            ..fin
+        };
+        checkpoints.save(&failed, (42, 3), (42, 3)).unwrap();
+        assert_eq!(checkpoints.load(&input, (42, 3)), None);
+        assert_eq!(checkpoints.seed((42, 3)), None);
+
+        checkpoints.remove().unwrap();
+        assert!(!checkpoints.dir().exists());
+    }
+}
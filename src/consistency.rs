@@ -0,0 +1,217 @@
+use uncertain::Uf64;
+
+/// Chunks further than this many standard deviations from the mean are
+/// flagged as outliers. With many chunks and geometries, smaller deviations
+/// happen by chance.
+pub const OUTLIER_Z: f64 = 4.;
+/// Below this p-value the spread of the chunks does not fit their reported
+/// uncertainties.
+pub const MIN_P_VALUE: f64 = 1e-3;
+/// If more than this fraction of the chunks are outliers, the uncertainties
+/// are off rather than a few chunks, and none are rejected.
+pub const MAX_REJECTED_FRACTION: f64 = 0.25;
+
+const MAX_ITERATIONS: usize = 1000;
+const EPS: f64 = 1e-14;
+const TINY: f64 = 1e-300;
+
+/// How well the chunks agree with each other in one scoring geometry.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Consistency {
+    pub geometry: String,
+    /// Sum of the squared z-scores of the chunks.
+    pub chi2: f64,
+    /// Degrees of freedom, one less than the number of chunks tested.
+    pub dof: usize,
+    /// Probability of a chi-square at least this large if the chunks agree.
+    pub p_value: f64,
+    /// Indices and z-scores of the chunks beyond `OUTLIER_Z`, each compared
+    /// with the mean of the other chunks.
+    pub outliers: Vec<(usize, f64)>,
+}
+
+impl Consistency {
+    /// Compare the results of `chunks`, given by index, with their combined
+    /// `mean`. Chunks without a finite, positive variance are skipped, since
+    /// nothing tells how far they may deviate.
+    ///
+    /// A chunk is an outlier if it deviates from the inverse-variance mean
+    /// of the others, so that it cannot pull the mean towards itself.
+    pub fn test(geometry: &str, chunks: &[(usize, Uf64)], mean: f64) -> Self {
+        let tested: Vec<(usize, Uf64)> = chunks
+            .iter()
+            .cloned()
+            .filter(|(_, r)| r.var().is_finite() && r.var() > 0.)
+            .collect();
+        let total_weight: f64 = tested.iter().map(|(_, r)| 1. / r.var()).sum();
+        let total: f64 = tested.iter().map(|(_, r)| r.value() / r.var()).sum();
+        let mut chi2 = 0.;
+        let mut outliers = Vec::new();
+        for &(i, result) in &tested {
+            chi2 += (result.value() - mean).powi(2) / result.var();
+            let others_weight = total_weight - 1. / result.var();
+            if tested.len() < 2 || others_weight <= 0. {
+                continue;
+            }
+            let others_mean = (total - result.value() / result.var()) / others_weight;
+            let z = (result.value() - others_mean) / (result.var() + 1. / others_weight).sqrt();
+            if z.abs() > OUTLIER_Z {
+                outliers.push((i, z));
+            }
+        }
+        let dof = tested.len().max(1) - 1;
+        Consistency {
+            geometry: geometry.to_string(),
+            chi2,
+            dof,
+            p_value: chi2_survival(chi2, dof),
+            outliers,
+        }
+    }
+
+    pub fn is_consistent(&self) -> bool {
+        self.p_value >= MIN_P_VALUE && self.outliers.is_empty()
+    }
+}
+
+/// Probability that a chi-square distributed variable with `dof` degrees
+/// of freedom exceeds `chi2`.
+pub fn chi2_survival(chi2: f64, dof: usize) -> f64 {
+    if dof == 0 {
+        return 1.;
+    }
+    gamma_q(dof as f64 / 2., chi2 / 2.)
+}
+
+/// The regularized upper incomplete gamma function Q(a, x).
+fn gamma_q(a: f64, x: f64) -> f64 {
+    if x <= 0. {
+        1.
+    } else if x < a + 1. {
+        1. - gamma_p_series(a, x)
+    } else {
+        gamma_q_fraction(a, x)
+    }
+}
+
+/// P(a, x) by its series, which converges quickly for x < a + 1.
+fn gamma_p_series(a: f64, x: f64) -> f64 {
+    let mut ap = a;
+    let mut term = 1. / a;
+    let mut sum = term;
+    for _ in 0..MAX_ITERATIONS {
+        ap += 1.;
+        term *= x / ap;
+        sum += term;
+        if term.abs() < sum.abs() * EPS {
+            break;
+        }
+    }
+    sum * (-x + a * x.ln() - ln_gamma(a)).exp()
+}
+
+/// Q(a, x) by its continued fraction, which converges quickly for x > a + 1.
+fn gamma_q_fraction(a: f64, x: f64) -> f64 {
+    let mut b = x + 1. - a;
+    let mut c = 1. / TINY;
+    let mut d = 1. / b;
+    let mut h = d;
+    for i in 1..MAX_ITERATIONS {
+        let an = -(i as f64) * (i as f64 - a);
+        b += 2.;
+        d = an * d + b;
+        if d.abs() < TINY {
+            d = TINY;
+        }
+        c = b + an / c;
+        if c.abs() < TINY {
+            c = TINY;
+        }
+        d = 1. / d;
+        let delta = d * c;
+        h *= delta;
+        if (delta - 1.).abs() < EPS {
+            break;
+        }
+    }
+    (-x + a * x.ln() - ln_gamma(a)).exp() * h
+}
+
+/// Logarithm of the gamma function for positive `x`, by the Lanczos
+/// approximation.
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [
+        76.18009172947146,
+        -86.50532032941677,
+        24.01409824083091,
+        -1.231739572450155,
+        0.1208650973866179e-2,
+        -0.5395239384953e-5,
+    ];
+    let mut y = x;
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let mut series = 1.000000000190015;
+    for c in &COEFFICIENTS {
+        y += 1.;
+        series += c / y;
+    }
+    -tmp + (2.5066282746310005 * series / x).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chi2_survival() {
+        assert_relative_eq!(ln_gamma(5.), 24_f64.ln(), max_relative = 1e-10);
+        // two degrees of freedom have an exponential distribution
+        assert_relative_eq!(chi2_survival(2., 2), (-1_f64).exp(), max_relative = 1e-10);
+        assert_relative_eq!(chi2_survival(30., 2), (-15_f64).exp(), max_relative = 1e-10);
+        assert_relative_eq!(chi2_survival(3.841459, 1), 0.05, max_relative = 1e-5);
+        assert_relative_eq!(chi2_survival(18.307038, 10), 0.05, max_relative = 1e-5);
+        assert_eq!(chi2_survival(0., 3), 1.);
+        assert_eq!(chi2_survival(5., 0), 1.);
+    }
+
+    #[test]
+    fn test_consistency() {
+        let chunks: Vec<(usize, Uf64)> = [1.0, 1.1, 0.9, 1.0]
+            .iter()
+            .enumerate()
+            .map(|(i, &x)| (i, Uf64::from_value_std(x, 0.1)))
+            .collect();
+        let c = Consistency::test("geo", &chunks, 1.);
+        assert_relative_eq!(c.chi2, 2., max_relative = 1e-12);
+        assert_eq!(c.dof, 3);
+        assert!(c.is_consistent());
+
+        let mut bad = chunks.clone();
+        bad.push((7, Uf64::from_value_std(2., 0.1)));
+        bad.push((8, Uf64::from_value(5.)));
+        let c = Consistency::test("geo", &bad, 1.);
+        assert_eq!(c.dof, 4);
+        assert_eq!(c.outliers.len(), 1);
+        assert_eq!(c.outliers[0].0, 7);
+        // 1 above the mean of the others, which is uncertain by 0.1 / 2
+        assert_relative_eq!(c.outliers[0].1, 1. / 0.0125_f64.sqrt(), max_relative = 1e-12);
+        assert!(c.p_value < MIN_P_VALUE);
+        assert!(!c.is_consistent());
+
+        // too small uncertainties show up in the chi-square, not as outliers
+        let tight: Vec<(usize, Uf64)> = chunks
+            .iter()
+            .map(|&(i, r)| (i, Uf64::from_value_std(r.value(), 0.03)))
+            .collect();
+        let c = Consistency::test("geo", &tight, 1.);
+        assert!(c.outliers.is_empty());
+        assert!(!c.is_consistent());
+
+        // an unknown uncertainty cannot make a chunk look consistent
+        let mut unknown = chunks.clone();
+        unknown.push((9, Uf64::from_value_var(3., f64::INFINITY)));
+        let c = Consistency::test("geo", &unknown, 1.);
+        assert_eq!(c.dof, 3);
+    }
+}
@@ -0,0 +1,197 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use uncertain::Uf64;
+use errors::*;
+
+/// Voxel dose distribution as stored in a .3ddose file.
+///
+/// The file consists of the number of voxels in each direction,
+/// the voxel boundaries in x, y and z, the doses and the relative
+/// uncertainties. The x index varies fastest.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Dose3d {
+    pub xs: Vec<f64>,
+    pub ys: Vec<f64>,
+    pub zs: Vec<f64>,
+    pub dose: Vec<Uf64>,
+}
+
+fn parse_next<'a, T, I>(words: &mut I, what: &str) -> Result<T>
+where
+    T: FromStr,
+    I: Iterator<Item = &'a str>,
+{
+    let word = words
+        .next()
+        .chain_err(|| format!("Unexpected end of 3ddose while reading {}", what))?;
+    match word.parse::<T>() {
+        Ok(x) => Ok(x),
+        Err(_) => bail!("Cannot parse {} from {:?}", what, word),
+    }
+}
+
+fn parse_many<'a, T, I>(words: &mut I, n: usize, what: &str) -> Result<Vec<T>>
+where
+    T: FromStr,
+    I: Iterator<Item = &'a str>,
+{
+    // `n` comes from the header, so it is not trusted to preallocate
+    let mut ret = Vec::new();
+    for _ in 0..n {
+        ret.push(parse_next(words, what)?);
+    }
+    Ok(ret)
+}
+
+fn write_line(f: &mut fmt::Formatter, xs: &[f64]) -> fmt::Result {
+    let line: Vec<String> = xs.iter().map(|x| format!("{:e}", x)).collect();
+    writeln!(f, "{}", line.join(" "))
+}
+
+impl Dose3d {
+    pub fn nx(&self) -> usize {
+        self.xs.len().saturating_sub(1)
+    }
+
+    pub fn ny(&self) -> usize {
+        self.ys.len().saturating_sub(1)
+    }
+
+    pub fn nz(&self) -> usize {
+        self.zs.len().saturating_sub(1)
+    }
+
+    pub fn parse_string(s: &str) -> Result<Dose3d> {
+        let mut words = s.split_whitespace();
+        let nx: usize = parse_next(&mut words, "nx")?;
+        let ny: usize = parse_next(&mut words, "ny")?;
+        let nz: usize = parse_next(&mut words, "nz")?;
+        let nvoxels = nx.checked_mul(ny)
+            .and_then(|n| n.checked_mul(nz))
+            .chain_err(|| format!("Too many voxels: {} x {} x {}", nx, ny, nz))?;
+        let xs = parse_many(&mut words, nx.saturating_add(1), "x boundaries")?;
+        let ys = parse_many(&mut words, ny.saturating_add(1), "y boundaries")?;
+        let zs = parse_many(&mut words, nz.saturating_add(1), "z boundaries")?;
+        let values: Vec<f64> = parse_many(&mut words, nvoxels, "doses")?;
+        let rstds: Vec<f64> = parse_many(&mut words, nvoxels, "uncertainties")?;
+        let dose = values
+            .iter()
+            .zip(rstds)
+            .map(|(&value, rstd)| Uf64::from_value_rstd(value, rstd))
+            .collect();
+        Ok(Dose3d { xs, ys, zs, dose })
+    }
+
+    #[allow(dead_code)]
+    pub fn load(path: &Path) -> Result<Dose3d> {
+        let mut content = String::new();
+        File::open(path)
+            .chain_err(|| cannot_read(&path))?
+            .read_to_string(&mut content)
+            .chain_err(|| cannot_read(&path))?;
+        Dose3d::parse_string(&content).chain_err(|| cannot_read(&path))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut file = File::create(path).chain_err(|| cannot_create(&path))?;
+        file.write_all(self.to_string().as_bytes())
+            .chain_err(|| cannot_write(&path))
+    }
+
+    fn has_same_grid(&self, other: &Dose3d) -> bool {
+        (self.xs == other.xs) & (self.ys == other.ys) & (self.zs == other.zs)
+    }
+
+    /// Average the chunks voxel by voxel, chunk `i` with weight `weights[i]`.
+    pub fn combine(doses: &[Dose3d], weights: &[f64]) -> Result<Dose3d> {
+        let first = match doses.first() {
+            Some(d) => d,
+            None => bail!("Cannot combine empty collection of 3ddose distributions."),
+        };
+        if doses.len() != weights.len() {
+            bail!("Got {} 3ddose distributions, but {} weights", doses.len(), weights.len());
+        }
+        let mut ret = first.clone();
+        for score in &mut ret.dose {
+            *score = *score * weights[0];
+        }
+        for (d, &w) in doses[1..].iter().zip(&weights[1..]) {
+            if !ret.has_same_grid(d) {
+                bail!("Cannot combine 3ddose distributions with different voxel grids.");
+            }
+            for (acc, inc) in ret.dose.iter_mut().zip(&d.dose) {
+                *acc = *acc + *inc * w;
+            }
+        }
+        Ok(ret)
+    }
+}
+
+impl fmt::Display for Dose3d {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} {} {}", self.nx(), self.ny(), self.nz())?;
+        write_line(f, &self.xs)?;
+        write_line(f, &self.ys)?;
+        write_line(f, &self.zs)?;
+        let values: Vec<f64> = self.dose.iter().map(|d| d.value()).collect();
+        let rstds: Vec<f64> = self.dose.iter().map(|d| d.rstd()).collect();
+        write_line(f, &values)?;
+        write_line(f, &rstds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::asset_path;
+
+    #[test]
+    fn test_parse_3ddose() {
+        let d = Dose3d::load(&asset_path().join("small.3ddose")).unwrap();
+        assert_eq!((d.nx(), d.ny(), d.nz()), (3, 2, 2));
+        assert_eq!(d.xs, vec![-1.5, -0.5, 0.5, 1.5]);
+        assert_eq!(d.zs, vec![0., 1., 2.]);
+        assert_eq!(d.dose.len(), 12);
+        assert_eq!(d.dose[0], Uf64::from_value_rstd(1.0e-14, 0.05));
+        assert_eq!(d.dose[11], Uf64::from_value_rstd(0., 1.));
+        assert_eq!(Dose3d::parse_string(&d.to_string()).unwrap(), d);
+
+        let huge = format!("{0} {0} 2\n0 1\n", usize::MAX);
+        assert!(Dose3d::parse_string(&huge).is_err());
+        assert!(Dose3d::parse_string("1000000 1000000 1000 0 1").is_err());
+        let empty = Dose3d {
+            xs: Vec::new(),
+            ys: Vec::new(),
+            zs: Vec::new(),
+            dose: Vec::new(),
+        };
+        assert_eq!((empty.nx(), empty.ny(), empty.nz()), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_combine_3ddose() {
+        let d = Dose3d::load(&asset_path().join("small.3ddose")).unwrap();
+        let c = Dose3d::combine(&[d.clone(), d.clone()], &[0.5, 0.5]).unwrap();
+        assert_relative_eq!(c.dose[0].value(), d.dose[0].value());
+        assert_relative_eq!(c.dose[0].rstd(), d.dose[0].rstd() / 2_f64.sqrt());
+        assert_eq!(c.dose[11], Uf64::from_value_rstd(0., 1.));
+
+        let mut double = d.clone();
+        double.dose[0] = Uf64::from_value_rstd(2.0e-14, 0.05);
+        let c = Dose3d::combine(&[d.clone(), double], &[0.75, 0.25]).unwrap();
+        assert_relative_eq!(c.dose[0].value(), 1.25e-14);
+        assert_relative_eq!(
+            c.dose[0].std(),
+            (0.75_f64.powi(2) * 0.5e-15_f64.powi(2) + 0.25_f64.powi(2) * 1.0e-15_f64.powi(2)).sqrt()
+        );
+
+        let mut other = d.clone();
+        other.xs[0] = -2.;
+        assert!(Dose3d::combine(&[d.clone(), other], &[0.5, 0.5]).is_err());
+        assert!(Dose3d::combine(&[d], &[0.5, 0.5]).is_err());
+        assert!(Dose3d::combine(&[], &[]).is_err());
+    }
+}
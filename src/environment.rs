@@ -0,0 +1,295 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::time::UNIX_EPOCH;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use serde_json;
+use errors::*;
+use util;
+
+/// The EGSnrc installation an application is run with.
+///
+/// Unset fields are taken from the environment of the hen process.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EgsEnv {
+    #[serde(default)]
+    pub egs_home: Option<PathBuf>,
+    #[serde(default)]
+    pub hen_house: Option<PathBuf>,
+    #[serde(default)]
+    pub egs_config: Option<PathBuf>,
+    /// The application binary, instead of looking up its name in PATH.
+    #[serde(default)]
+    pub executable: Option<PathBuf>,
+    /// Further variables passed to the application.
+    #[serde(default)]
+    pub vars: Vec<(String, String)>,
+}
+
+fn var_path(key: &str) -> Option<PathBuf> {
+    match env::var_os(key) {
+        Some(ref s) if !s.is_empty() => Some(PathBuf::from(s)),
+        _ => None,
+    }
+}
+
+fn describe(p: &Option<PathBuf>) -> String {
+    match *p {
+        Some(ref p) => format!("{:?}", p),
+        None => "unset".to_string(),
+    }
+}
+
+impl EgsEnv {
+    /// Read settings from a JSON file like
+    /// `{"egs_home": "/opt/egs/egs_home", "vars": [["OMP_NUM_THREADS", "1"]]}`.
+    /// Relative paths are taken from the directory of the file.
+    pub fn load(path: &Path) -> Result<EgsEnv> {
+        let file = fs::File::open(path).chain_err(|| cannot_read(&path))?;
+        let mut ret: EgsEnv = serde_json::from_reader(file).chain_err(|| cannot_read(&path))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        for p in [
+            &mut ret.egs_home,
+            &mut ret.hen_house,
+            &mut ret.egs_config,
+            &mut ret.executable,
+        ].iter_mut()
+        {
+            if let Some(ref mut p) = **p {
+                *p = dir.join(&p);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Take each setting from `self`, or from `other` if it is unset.
+    pub fn or(&self, other: &EgsEnv) -> EgsEnv {
+        let mut vars = other.vars.clone();
+        for (k, v) in &self.vars {
+            vars.retain(|(key, _)| key != k);
+            vars.push((k.clone(), v.clone()));
+        }
+        EgsEnv {
+            egs_home: self.egs_home.clone().or_else(|| other.egs_home.clone()),
+            hen_house: self.hen_house.clone().or_else(|| other.hen_house.clone()),
+            egs_config: self.egs_config.clone().or_else(|| other.egs_config.clone()),
+            executable: self.executable.clone().or_else(|| other.executable.clone()),
+            vars,
+        }
+    }
+
+    /// Fill in unset settings from this process, so that the result no
+    /// longer depends on the machine it is used on.
+    pub fn resolve(&self, application: &str) -> EgsEnv {
+        let here = EgsEnv {
+            egs_home: var_path("EGS_HOME"),
+            hen_house: var_path("HEN_HOUSE"),
+            egs_config: var_path("EGS_CONFIG"),
+            executable: util::find_executable(application),
+            vars: Vec::new(),
+        };
+        self.or(&here)
+    }
+
+    /// Human readable differences to `other`, e.g. the current machine.
+    pub fn differences(&self, other: &EgsEnv) -> Vec<String> {
+        let mut ret = Vec::new();
+        {
+            let mut cmp = |name: &str, a: &Option<PathBuf>, b: &Option<PathBuf>| {
+                if a != b {
+                    ret.push(format!("{} is {} instead of {}", name, describe(b), describe(a)));
+                }
+            };
+            cmp("EGS_HOME", &self.egs_home, &other.egs_home);
+            cmp("HEN_HOUSE", &self.hen_house, &other.hen_house);
+            cmp("EGS_CONFIG", &self.egs_config, &other.egs_config);
+            cmp("Executable", &self.executable, &other.executable);
+        }
+        if self.vars != other.vars {
+            ret.push(format!("Variables are {:?} instead of {:?}", other.vars, self.vars));
+        }
+        ret
+    }
+
+    /// Differences of this machine to `self`, recorded earlier, that remain
+    /// when running `application` again with `overrides` on top of `self`.
+    pub fn differences_here(&self, overrides: &EgsEnv, application: &str) -> Vec<String> {
+        let expected = overrides.or(self);
+        let here = EgsEnv {
+            vars: expected.vars.clone(),
+            ..overrides.resolve(application)
+        };
+        expected.differences(&here)
+    }
+
+    /// Identifies the installation, including the size and modification time
+    /// of the application binary, so that rebuilding it changes the result.
+    pub fn fingerprint(&self, application: &str) -> String {
+        let env = self.resolve(application);
+        let binary = env.executable
+            .as_ref()
+            .and_then(|p| fs::metadata(p).ok())
+            .map(|m| {
+                let modified = m.modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs());
+                format!("{} bytes, modified {:?}", m.len(), modified)
+            });
+        format!("{:?} {:?}", env, binary)
+    }
+
+    /// The egs_home setting, falling back to EGS_HOME of this process.
+    pub fn egs_home(&self) -> Option<PathBuf> {
+        self.egs_home.clone().or_else(|| var_path("EGS_HOME"))
+    }
+
+    /// Variables set for the application, on top of those of this process.
+    fn settings(&self) -> Vec<(String, OsString)> {
+        let paths = [
+            ("EGS_HOME", &self.egs_home),
+            ("HEN_HOUSE", &self.hen_house),
+            ("EGS_CONFIG", &self.egs_config),
+        ];
+        let mut ret = Vec::new();
+        for &(key, value) in paths.iter() {
+            if let Some(ref value) = *value {
+                ret.push((key.to_string(), value.clone().into_os_string()));
+            }
+        }
+        ret.extend(self.vars.iter().map(|(k, v)| (k.clone(), OsString::from(v))));
+        ret
+    }
+
+    fn program(&self, application: &str) -> PathBuf {
+        match self.executable {
+            Some(ref path) => path.clone(),
+            None => PathBuf::from(application),
+        }
+    }
+
+    /// Command running `application` in this environment.
+    pub fn command(&self, application: &str) -> Command {
+        let mut cmd = Command::new(self.program(application));
+        for (key, value) in self.settings() {
+            cmd.env(key, value);
+        }
+        cmd
+    }
+
+    /// `command(application)` with `args` as a line for a POSIX shell.
+    pub fn command_line(&self, application: &str, args: &[String]) -> String {
+        let mut words: Vec<String> = self.settings()
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, util::shell_quote(&value.to_string_lossy())))
+            .collect();
+        words.push(util::shell_quote(&self.program(application).to_string_lossy()));
+        words.extend(args.iter().map(|arg| util::shell_quote(arg)));
+        words.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_egs_env_or() {
+        let cli = EgsEnv {
+            egs_home: Some(PathBuf::from("/new/egs_home")),
+            vars: vec![("OMP_NUM_THREADS".to_string(), "1".to_string())],
+            ..EgsEnv::default()
+        };
+        let stored = EgsEnv {
+            egs_home: Some(PathBuf::from("/old/egs_home")),
+            hen_house: Some(PathBuf::from("/old/HEN_HOUSE")),
+            vars: vec![
+                ("OMP_NUM_THREADS".to_string(), "4".to_string()),
+                ("LANG".to_string(), "C".to_string()),
+            ],
+            ..EgsEnv::default()
+        };
+        let env = cli.or(&stored);
+        assert_eq!(env.egs_home, Some(PathBuf::from("/new/egs_home")));
+        assert_eq!(env.hen_house, Some(PathBuf::from("/old/HEN_HOUSE")));
+        assert_eq!(
+            env.vars,
+            vec![
+                ("LANG".to_string(), "C".to_string()),
+                ("OMP_NUM_THREADS".to_string(), "1".to_string()),
+            ]
+        );
+
+        let diff = stored.differences(&env);
+        assert_eq!(diff.len(), 2);
+        assert!(diff[0].starts_with("EGS_HOME is \"/new/egs_home\""));
+        assert!(stored.differences(&stored).is_empty());
+    }
+
+    #[test]
+    fn test_egs_env_load() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("egs.json");
+        fs::write(
+            &path,
+            r#"{"egs_home": "egs_home", "hen_house": "/opt/HEN_HOUSE", "vars": [["LANG", "C"]]}"#,
+        ).unwrap();
+        let env = EgsEnv::load(&path).unwrap();
+        assert_eq!(env.egs_home, Some(dir.path().join("egs_home")));
+        assert_eq!(env.hen_house, Some(PathBuf::from("/opt/HEN_HOUSE")));
+        assert_eq!(env.egs_config, None);
+        assert_eq!(env.vars, vec![("LANG".to_string(), "C".to_string())]);
+        fs::write(&path, r#"{"egs_home": 1}"#).unwrap();
+        assert!(EgsEnv::load(&path).is_err());
+    }
+
+    #[test]
+    fn test_differences_here() {
+        let here = EgsEnv::default().resolve("sh");
+        let recorded = EgsEnv {
+            egs_home: Some(PathBuf::from("/old/egs_home")),
+            vars: vec![("LANG".to_string(), "C".to_string())],
+            ..here.clone()
+        };
+        let diffs = recorded.differences_here(&EgsEnv::default(), "sh");
+        assert_eq!(diffs.len(), 1);
+        assert!(diffs[0].starts_with("EGS_HOME is"));
+        // what is given on the command line is no surprise
+        let cli = EgsEnv {
+            egs_home: Some(PathBuf::from("/new/egs_home")),
+            ..EgsEnv::default()
+        };
+        assert!(recorded.differences_here(&cli, "sh").is_empty());
+    }
+
+    #[test]
+    fn test_command_line() {
+        let env = EgsEnv {
+            egs_home: Some(PathBuf::from("/egs home")),
+            vars: vec![("OMP_NUM_THREADS".to_string(), "1".to_string())],
+            ..EgsEnv::default()
+        };
+        let args = vec!["-i".to_string(), "it's".to_string()];
+        assert_eq!(
+            env.command_line("egs_chamber", &args),
+            "EGS_HOME='/egs home' OMP_NUM_THREADS='1' 'egs_chamber' '-i' 'it'\\''s'"
+        );
+    }
+
+    #[test]
+    fn test_egs_env_resolve() {
+        let env = EgsEnv {
+            executable: Some(PathBuf::from("/opt/egs/bin/egs_chamber")),
+            ..EgsEnv::default()
+        };
+        let resolved = env.resolve("sh");
+        assert_eq!(resolved.executable, env.executable);
+        assert!(EgsEnv::default().resolve("sh").executable.is_some());
+        assert!(EgsEnv::default()
+            .resolve("hen_no_such_application")
+            .executable
+            .is_none());
+    }
+}
@@ -0,0 +1,401 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::ops::Neg;
+use std::str::Chars;
+use errors::*;
+use uncertain::{Correlated, Uf64};
+
+/// Characters that end a geometry name, unless it is quoted.
+const OPERATORS: &str = "+-*/^()";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    Sqrt,
+    Ln,
+    Exp,
+}
+
+/// An arithmetic expression of the doses in scoring geometries,
+/// e.g. `sqrt(a / b)` or `ln("my geometry") - 1`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Geometry(String),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+    Call(Function, Box<Expr>),
+}
+
+/// A quantity with an uncertainty that expressions can be evaluated with.
+pub trait Quantity: Sized + Neg<Output = Self> {
+    /// `self op other`, which fails if the two cannot be combined.
+    fn combine(self, op: Op, other: Self) -> Result<Self>;
+    /// `self op x`
+    fn scalar(self, op: Op, x: f64) -> Self;
+    fn call(self, f: Function) -> Self;
+}
+
+impl Quantity for Uf64 {
+    fn combine(self, op: Op, other: Self) -> Result<Self> {
+        Ok(match op {
+            Op::Add => self + other,
+            Op::Sub => self - other,
+            Op::Mul => self * other,
+            Op::Div => self / other,
+            Op::Pow => (other * self.ln()).exp(),
+        })
+    }
+
+    fn scalar(self, op: Op, x: f64) -> Self {
+        match op {
+            Op::Add => self + x,
+            Op::Sub => self - x,
+            Op::Mul => self * x,
+            Op::Div => self / x,
+            Op::Pow => self.powf(x),
+        }
+    }
+
+    fn call(self, f: Function) -> Self {
+        match f {
+            Function::Sqrt => self.sqrt(),
+            Function::Ln => self.ln(),
+            Function::Exp => self.exp(),
+        }
+    }
+}
+
+impl Quantity for Correlated {
+    fn combine(self, op: Op, other: Self) -> Result<Self> {
+        match op {
+            Op::Add => self + other,
+            Op::Sub => self - other,
+            Op::Mul => self * other,
+            Op::Div => self / other,
+            Op::Pow => Ok((other * self.ln())?.exp()),
+        }
+    }
+
+    fn scalar(self, op: Op, x: f64) -> Self {
+        match op {
+            Op::Add => self + x,
+            Op::Sub => self - x,
+            Op::Mul => self * x,
+            Op::Div => self / x,
+            Op::Pow => self.powf(x),
+        }
+    }
+
+    fn call(self, f: Function) -> Self {
+        match f {
+            Function::Sqrt => self.sqrt(),
+            Function::Ln => self.ln(),
+            Function::Exp => self.exp(),
+        }
+    }
+}
+
+/// The value of an expression, which has no uncertainty if it names no
+/// geometry.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value<T> {
+    Number(f64),
+    Quantity(T),
+}
+
+impl<T: fmt::Display> fmt::Display for Value<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Number(x) => write!(f, "{}", x),
+            Value::Quantity(ref q) => q.fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Symbol(char),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut ret = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if OPERATORS.contains(c) {
+            chars.next();
+            ret.push(Token::Symbol(c));
+        } else if c == '"' {
+            chars.next();
+            ret.push(Token::Name(quoted(&mut chars, s)?));
+        } else {
+            let word = bare_word(&mut chars);
+            ret.push(match word.parse() {
+                Ok(x) => Token::Number(x),
+                Err(_) => Token::Name(word),
+            });
+        }
+    }
+    Ok(ret)
+}
+
+fn quoted(chars: &mut Peekable<Chars>, s: &str) -> Result<String> {
+    let mut ret = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(ret),
+            Some(c) => ret.push(c),
+            None => bail!("Unterminated name \"{} in {:?}", ret, s),
+        }
+    }
+}
+
+/// A geometry name or a number, where the sign of an exponent like in
+/// `1e-3` does not end the word.
+fn bare_word(chars: &mut Peekable<Chars>) -> String {
+    let mut ret = String::new();
+    while let Some(&c) = chars.peek() {
+        let exponent_sign = (c == '+' || c == '-')
+            && (ret.ends_with('e') || ret.ends_with('E'))
+            && ret[..ret.len() - 1].parse::<f64>().is_ok();
+        if c.is_whitespace() || c == '"' || (OPERATORS.contains(c) && !exponent_sign) {
+            break;
+        }
+        ret.push(c);
+        chars.next();
+    }
+    ret
+}
+
+/// Recursive descent over the tokens, with the usual precedence and a
+/// right associative `^`.
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let ret = self.tokens.get(self.pos);
+        self.pos += 1;
+        ret
+    }
+
+    fn eat(&mut self, symbol: char) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr> {
+        let mut ret = self.product()?;
+        loop {
+            let op = if self.eat('+') {
+                Op::Add
+            } else if self.eat('-') {
+                Op::Sub
+            } else {
+                return Ok(ret);
+            };
+            ret = Expr::Binary(op, Box::new(ret), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr> {
+        let mut ret = self.unary()?;
+        loop {
+            let op = if self.eat('*') {
+                Op::Mul
+            } else if self.eat('/') {
+                Op::Div
+            } else {
+                return Ok(ret);
+            };
+            ret = Expr::Binary(op, Box::new(ret), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat('-') {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        let base = self.atom()?;
+        if self.eat('^') {
+            return Ok(Expr::Binary(Op::Pow, Box::new(base), Box::new(self.unary()?)));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(&Token::Number(x)) => Ok(Expr::Number(x)),
+            Some(Token::Name(name)) => {
+                if !self.eat('(') {
+                    return Ok(Expr::Geometry(name.clone()));
+                }
+                let f = match name.as_str() {
+                    "sqrt" => Function::Sqrt,
+                    "ln" => Function::Ln,
+                    "exp" => Function::Exp,
+                    _ => bail!("Unknown function {:?}, expected sqrt, ln or exp", name),
+                };
+                let arg = self.sum()?;
+                if !self.eat(')') {
+                    bail!("Expected ')' after the argument of {}", name);
+                }
+                Ok(Expr::Call(f, Box::new(arg)))
+            }
+            Some(&Token::Symbol('(')) => {
+                let ret = self.sum()?;
+                if !self.eat(')') {
+                    bail!("Expected ')'");
+                }
+                Ok(ret)
+            }
+            Some(&Token::Symbol(c)) => bail!("Unexpected {:?}", c),
+            None => bail!("Unexpected end of expression"),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Expr> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+        };
+        let ret = parser
+            .sum()
+            .chain_err(|| format!("Cannot parse expression {:?}", s))?;
+        if parser.pos < tokens.len() {
+            bail!("Cannot parse expression {:?}, unexpected {:?}", s, tokens[parser.pos]);
+        }
+        Ok(ret)
+    }
+
+    /// Evaluate with the quantities of the geometries from `lookup`.
+    pub fn eval<T, F>(&self, lookup: &F) -> Result<Value<T>>
+    where
+        T: Quantity,
+        F: Fn(&str) -> Result<T>,
+    {
+        Ok(match *self {
+            Expr::Number(x) => Value::Number(x),
+            Expr::Geometry(ref name) => Value::Quantity(lookup(name)?),
+            Expr::Neg(ref a) => match a.eval(lookup)? {
+                Value::Number(x) => Value::Number(-x),
+                Value::Quantity(q) => Value::Quantity(-q),
+            },
+            Expr::Call(f, ref a) => match a.eval(lookup)? {
+                Value::Number(x) => Value::Number(match f {
+                    Function::Sqrt => x.sqrt(),
+                    Function::Ln => x.ln(),
+                    Function::Exp => x.exp(),
+                }),
+                Value::Quantity(q) => Value::Quantity(q.call(f)),
+            },
+            Expr::Binary(op, ref a, ref b) => match (a.eval(lookup)?, b.eval(lookup)?) {
+                (Value::Number(x), Value::Number(y)) => Value::Number(match op {
+                    Op::Add => x + y,
+                    Op::Sub => x - y,
+                    Op::Mul => x * y,
+                    Op::Div => x / y,
+                    Op::Pow => x.powf(y),
+                }),
+                (Value::Quantity(q), Value::Number(y)) => Value::Quantity(q.scalar(op, y)),
+                (Value::Number(x), Value::Quantity(q)) => Value::Quantity(match op {
+                    Op::Add | Op::Mul => q.scalar(op, x),
+                    Op::Sub => (-q).scalar(Op::Add, x),
+                    Op::Div => q.scalar(Op::Pow, -1.).scalar(Op::Mul, x),
+                    Op::Pow => q.scalar(Op::Mul, x.ln()).call(Function::Exp),
+                }),
+                (Value::Quantity(p), Value::Quantity(q)) => Value::Quantity(p.combine(op, q)?),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Result<Uf64> {
+        match name {
+            "a" => Ok(Uf64::from_value_var(4., 1.)),
+            "geo 2" => Ok(Uf64::from_value_var(2., 0.)),
+            "Messwelt_13.01" => Ok(Uf64::from_value(1.)),
+            _ => bail!("There is no geometry {:?}", name),
+        }
+    }
+
+    fn eval(s: &str) -> Value<Uf64> {
+        Expr::parse(s).unwrap().eval(&lookup).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let a = || Box::new(Expr::Geometry("a".to_string()));
+        assert_eq!(
+            Expr::parse("-a^2^3").unwrap(),
+            Expr::Neg(Box::new(Expr::Binary(
+                Op::Pow,
+                a(),
+                Box::new(Expr::Binary(
+                    Op::Pow,
+                    Box::new(Expr::Number(2.)),
+                    Box::new(Expr::Number(3.))
+                ))
+            )))
+        );
+        assert_eq!(
+            Expr::parse("1 - a - 2e-1").unwrap(),
+            Expr::Binary(
+                Op::Sub,
+                Box::new(Expr::Binary(Op::Sub, Box::new(Expr::Number(1.)), a())),
+                Box::new(Expr::Number(0.2))
+            )
+        );
+        assert_eq!(
+            Expr::parse("ln(\"geo 2\")").unwrap(),
+            Expr::Call(Function::Ln, Box::new(Expr::Geometry("geo 2".to_string())))
+        );
+        for bad in &["", "a +", "(a", "sin(a)", "a b", "\"a", "sqrt a"] {
+            assert!(Expr::parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_eval() {
+        let a = lookup("a").unwrap();
+        assert_eq!(eval("1 + 2 * 3"), Value::Number(7.));
+        assert_eq!(eval("sqrt(a)"), Value::Quantity(a.sqrt()));
+        assert_eq!(eval("a^0.5"), Value::Quantity(a.sqrt()));
+        assert_eq!(eval("ln(exp(a))"), Value::Quantity(a));
+        assert_eq!(eval("2 - a"), Value::Quantity(Uf64::from_value_var(-2., 1.)));
+        assert_eq!(eval("8 / a"), Value::Quantity(Uf64::from_value_var(2., 0.25)));
+        assert_eq!(eval("a / \"geo 2\" * Messwelt_13.01"), Value::Quantity(a / 2.));
+        assert_eq!(format!("{}", eval("exp(0)")), "1");
+        let err = Expr::parse("a / b").unwrap().eval(&lookup).unwrap_err();
+        assert_eq!(err.to_string(), "There is no geometry \"b\"");
+    }
+}
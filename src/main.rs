@@ -1,8 +1,9 @@
 extern crate itertools;
+extern crate libc;
 extern crate num_cpus;
 extern crate rand;
-extern crate rayon;
 extern crate regex;
+extern crate glob;
 extern crate sha3;
 
 #[cfg(test)]
@@ -38,6 +39,19 @@ mod uncertain;
 mod omittable;
 mod app;
 mod errors;
+mod dose3d;
+mod phsp;
+mod runner;
+mod progress;
+mod environment;
+mod template;
+mod scheduler;
+mod adaptive;
+mod checkpoint;
+mod cache;
+mod provenance;
+mod consistency;
+mod expression;
 
 #[cfg(test)]
 mod tests;
@@ -24,6 +24,12 @@ impl<T> From<StubResult<T>> for Omittable<T> {
     }
 }
 
+impl<T> Default for Omittable<T> {
+    fn default() -> Self {
+        Omittable::Omitted
+    }
+}
+
 impl<T> Omittable<T> {
     pub fn is_available(&self) -> bool {
         match self {
@@ -3,153 +3,357 @@ use regex::Regex;
 use uncertain::Uf64;
 use simulation::SingSimParsedOutput;
 use errors::*;
-use std::path::Path;
-
-fn parse_dot_separated_key_value(s: &str) -> Option<(String, String)> {
-    let re = Regex::new(r"^(.*[^\.])\.\.\.*(.*)$").unwrap();
-    let caps = re.captures(&s)?;
-    let cap_key = caps.get(1)?;
-    let cap_val = caps.get(2)?;
-    let key = cap_key.as_str().to_string();
-    let val = cap_val.as_str().to_string();
-    return Some((key, val));
+
+/// Regular expressions used by the parser, compiled once per parser.
+#[derive(Debug, Clone)]
+pub struct Patterns {
+    separator: Regex,
+    dot_separated_key_value: Regex,
+    finished: Regex,
+    total_cpu_time: Regex,
+    many_minus: Regex,
+    geometry_dose: Regex,
+    finish_simulation: Regex,
+    running_histories: Regex,
+    batch: Regex,
+    last_case: Regex,
 }
 
-fn read_line_until(reader: &mut BufRead, re: &Regex) -> Option<String> {
-    let line = read_line(reader)?;
-    if re.is_match(&line) {
-        return Some(line);
-    } else {
-        return read_line_until(reader, re);
+impl Patterns {
+    pub fn new() -> Self {
+        // the patterns are constant, so compiling them cannot fail at runtime
+        let re = |s: &str| Regex::new(s).unwrap();
+        Patterns {
+            separator: re("^==(=*)"),
+            dot_separated_key_value: re(r"^(.*[^\.])\.\.\.*(.*)$"),
+            finished: re("^Finished simulation"),
+            total_cpu_time: re(r"^Total cpu time for this run:\s*(.*) \(sec.\)"),
+            many_minus: re("^---*"),
+            geometry_dose: re(r"^\s*(.*)\s\s*(.*) \+/\- (.*)%"),
+            finish_simulation: re("finishSimulation"),
+            running_histories: re(r"^\s*Running (\d+) histories"),
+            batch: re(r"^\s*(\d+)\s+(\S+)\s+(\S+)\s+(\S+)\s*$"),
+            last_case: re(r"^\s*last case\s*=\s*(\d+)"),
+        }
+    }
+
+    fn parse_running_histories(&self, line: &str) -> Option<u64> {
+        let caps = self.running_histories.captures(line)?;
+        caps.get(1)?.as_str().parse().ok()
+    }
+
+    fn parse_last_case(&self, line: &str) -> Option<u64> {
+        let caps = self.last_case.captures(line)?;
+        caps.get(1)?.as_str().parse().ok()
+    }
+
+    fn parse_batch(&self, line: &str) -> Option<Batch> {
+        let caps = self.batch.captures(line)?;
+        let field = |i: usize| caps.get(i).map(|m| m.as_str());
+        let ret = Batch {
+            batch: field(1)?.parse().ok()?,
+            cpu_time: field(2)?.parse().ok()?,
+            result: field(3)?.parse().ok()?,
+            rstd: field(4)?.parse::<f64>().ok()? / 100.,
+        };
+        Some(ret)
+    }
+
+    fn parse_dot_separated_key_value(&self, s: &str) -> Option<(String, String)> {
+        let caps = self.dot_separated_key_value.captures(s)?;
+        let key = caps.get(1)?.as_str().to_string();
+        let val = caps.get(2)?.as_str().to_string();
+        Some((key, val))
     }
-}
 
-fn read_line(reader: &mut BufRead) -> Option<String> {
-    let mut line = String::new();
-    if reader.read_line(&mut line).unwrap() == 0 {
-        return None;
-    } else {
-        return Some(line);
+    fn parse_total_cpu_time(&self, line: &str) -> StubResult<f64> {
+        let err = format!("Cannot parse total cpu time from {}", line).to_string();
+        let s = self.total_cpu_time
+            .captures(line)
+            .ok_or_else(|| err.clone())?
+            .get(1)
+            .ok_or_else(|| err.clone())?
+            .as_str();
+        s.parse::<f64>().map_err(|_| err.clone())
     }
+
+    fn parse_geometry_dose(&self, line: &str) -> StubResult<(String, Uf64)> {
+        let re = &self.geometry_dose;
+        let caps = re.captures(line)
+            .ok_or(format!("Cannot match {:?} on {:?}.", re, line))?;
+        let name = caps.get(1)
+            .ok_or(format!("Cannot parse geometry from {:?}", line))?
+            .as_str()
+            .trim()
+            .to_string();
+        let svalue = caps.get(2)
+            .ok_or(format!("Cannot parse dose value from {:?}", line))?
+            .as_str();
+        let value = svalue
+            .trim()
+            .parse::<f64>()
+            .map_err(|err| format!("Cannot parse f64 from {:?} {:?}", svalue, err))?;
+        let srstd = caps.get(3)
+            .ok_or(format!("Cannot parse dose rstd from {:?}", line))?
+            .as_str();
+        let rstd_percent = srstd
+            .trim()
+            .parse::<f64>()
+            .map_err(|err| format!("Cannot parse f64 from {:?} {:?}", srstd, err))?;
+        let rstd = rstd_percent / 100.;
+        // an empty geometry must not discard the others, so an unknown
+        // uncertainty is kept as an infinite variance
+        let score = if rstd.is_finite() {
+            Uf64::from_value_rstd(value, rstd)
+        } else {
+            Uf64::from_value_var(value, f64::INFINITY)
+        };
+        Ok((name, score))
+    }
+}
+
+/// A line of the batch table that egs++ applications print while running.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Batch {
+    pub batch: usize,
+    pub cpu_time: f64,
+    pub result: f64,
+    pub rstd: f64,
+}
+
+/// How far a running simulation got.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// Number of histories announced by the application.
+    pub histories: Option<u64>,
+    pub last_batch: Option<Batch>,
+    pub finished: bool,
 }
 
-fn parse_total_cpu_time(line: &str) -> StubResult<f64> {
-    let re = Regex::new(r"^Total cpu time for this run:\s*(.*) \(sec.\)").unwrap();
-    let err = format!("Cannot parse total cpu time from {}", line).to_string();
-    let s = re.captures(&line)
-        .ok_or_else(|| err.clone())?
-        .get(1)
-        .ok_or_else(|| err.clone())?
-        .as_str();
-    let ret: f64 = s.parse::<f64>().map_err(|_| err.clone())?;
-    return Ok(ret);
+/// Position of the parser within the output of an application.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParserState {
+    /// Before the separator line that opens the header.
+    Preamble { separators: usize },
+    /// Inside the `key.....value` header block.
+    Header,
+    /// Waiting for `Finished simulation`.
+    Running,
+    /// Waiting for `Total cpu time for this run`.
+    CpuTime,
+    /// Waiting for the `-----` line above the dose table.
+    DoseTableStart,
+    /// Reading the dose table.
+    DoseTable,
+    /// Waiting for `finishSimulation`.
+    Finishing,
+    /// After `finishSimulation`.
+    Done { trailing_lines: usize },
 }
 
-fn parse_geometry_dose(line: &str) -> StubResult<(String, Uf64)> {
-    let re = Regex::new(r"^\s*(.*)\s\s*(.*) \+/\- (.*)%").unwrap();
-    let caps = re.captures(&line)
-        .ok_or(format!("Cannot match {:?} on {:?}.", re, line))?;
-    let name = caps.get(1)
-        .ok_or(format!("Cannot parse geometry from {:?}", line))?
-        .as_str()
-        .trim()
-        .to_string();
-    let svalue = caps.get(2)
-        .ok_or(format!("Cannot parse dose value from {:?}", line))?
-        .as_str();
-    let value = svalue
-        .trim()
-        .parse::<f64>()
-        .map_err(|err| format!("Cannot parse f64 from {:?} {:?}", svalue, err))?;
-    let srstd = caps.get(3)
-        .ok_or(format!("Cannot parse dose rstd from {:?}", line))?
-        .as_str();
-    let rstd_percent = srstd
-        .trim()
-        .parse::<f64>()
-        .map_err(|err| format!("Cannot parse f64 from {:?} {:?}", srstd, err))?;
-    let rstd = rstd_percent / 100.;
-    assert!(rstd.is_finite());
-    let score = Uf64::from_value_rstd(value, rstd);
-    Ok((name, score))
+/// Incremental parser for the output of an egs++ application.
+///
+/// Lines are fed one at a time, so the same parser works on finished logs
+/// and on the stdout of a running process. It never fails: sections that
+/// cannot be parsed are reported as errors in the result.
+#[derive(Debug, Clone)]
+pub struct OutputParser {
+    patterns: Patterns,
+    state: ParserState,
+    header: Vec<(String, String)>,
+    header_errors: Vec<String>,
+    total_cpu_time: Option<StubResult<f64>>,
+    dose: Vec<(String, Uf64)>,
+    /// The lines of the dose table, as printed.
+    dose_table: String,
+    dose_error: Option<String>,
+    histories: Option<u64>,
+    last_batch: Option<Batch>,
+    last_case: Option<u64>,
 }
 
-pub fn parse_simulation_output(reader: &mut BufRead) -> Result<SingSimParsedOutput> {
-    let re = Regex::new("^==(=*)").unwrap();
-    read_line_until(reader, &re);
-    read_line_until(reader, &re);
-    let mut line = read_line(reader).ok_or({
-        let err: Error = "Unexpected end of file".into();
-        err
-    })?;
-    while !(re.is_match(&line)) {
-        let _kv = parse_dot_separated_key_value(line.trim()).unwrap();
-        line = read_line(reader).ok_or({
-            let err: Error = "Unexpected end of file".into();
-            err
-        })?;
-    }
-    read_line_until(reader, &Regex::new("^Finished simulation").unwrap());
-
-    let mut mline: Option<String> =
-        read_line_until(reader, &Regex::new("^Total cpu time for this run").unwrap());
-    let total_cpu_time = match mline {
-        None => Err("Cannot find Total cpu time for this run".to_string()),
-        Some(l) => parse_total_cpu_time(&l),
-    };
-
-    // // parse number of histories etc.
-    // while !(line.trim() == "".to_string()) {
-    //     line = read_line(reader).unwrap();
-    //     println!("{}", line);
-    // }
-
-    let re_many_minus = Regex::new("^---*").unwrap();
-    mline = read_line_until(reader, &re_many_minus);
-    let dose = match mline {
-        None => Err("Cannot find dose".to_string()),
-        Some(_) => {
-            let mut v = Vec::new();
-            loop {
-                mline = read_line(reader);
-                if mline.is_none() {
-                    break Ok(v);
+impl OutputParser {
+    pub fn new() -> Self {
+        OutputParser {
+            patterns: Patterns::new(),
+            state: ParserState::Preamble { separators: 0 },
+            header: Vec::new(),
+            header_errors: Vec::new(),
+            total_cpu_time: None,
+            dose: Vec::new(),
+            dose_table: String::new(),
+            dose_error: None,
+            histories: None,
+            last_batch: None,
+            last_case: None,
+        }
+    }
+
+    pub fn progress(&self) -> Progress {
+        let finished = match self.state {
+            ParserState::Running | ParserState::Preamble { .. } | ParserState::Header => false,
+            _ => true,
+        };
+        Progress {
+            histories: self.histories,
+            last_batch: self.last_batch,
+            finished,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn state(&self) -> ParserState {
+        self.state
+    }
+
+    /// Key value pairs of the header, like `user code` or `pegs file`.
+    #[allow(dead_code)]
+    pub fn header(&self) -> &[(String, String)] {
+        &self.header
+    }
+
+    pub fn feed_line(&mut self, line: &str) {
+        let p = &self.patterns;
+        self.state = match self.state {
+            ParserState::Preamble { separators } => {
+                if !p.separator.is_match(line) {
+                    self.state
+                } else if separators == 0 {
+                    ParserState::Preamble { separators: 1 }
+                } else {
+                    ParserState::Header
                 }
-                line = mline.unwrap();
-                if line.trim().is_empty() {
-                    break Ok(v);
+            }
+            ParserState::Header => {
+                if p.separator.is_match(line) {
+                    ParserState::Running
+                } else {
+                    match p.parse_dot_separated_key_value(line.trim()) {
+                        Some(kv) => self.header.push(kv),
+                        None => self.header_errors
+                            .push(format!("Cannot parse header line {:?}", line)),
+                    }
+                    self.state
+                }
+            }
+            ParserState::Running => {
+                if p.finished.is_match(line) {
+                    ParserState::CpuTime
+                } else {
+                    if let Some(n) = p.parse_running_histories(line) {
+                        self.histories = Some(n);
+                    } else if let Some(batch) = p.parse_batch(line) {
+                        self.last_batch = Some(batch);
+                    }
+                    self.state
                 }
-                let edose1 = parse_geometry_dose(&line);
-                match edose1 {
-                    Ok(dose1) => {
-                        v.push(dose1);
+            }
+            ParserState::CpuTime => {
+                if p.total_cpu_time.is_match(line) {
+                    self.total_cpu_time = Some(p.parse_total_cpu_time(line));
+                    ParserState::DoseTableStart
+                } else {
+                    self.state
+                }
+            }
+            ParserState::DoseTableStart => {
+                if p.many_minus.is_match(line) {
+                    ParserState::DoseTable
+                } else {
+                    if let Some(n) = p.parse_last_case(line) {
+                        self.last_case = Some(n);
                     }
-                    Err(e) => {
-                        break Err(e);
+                    self.state
+                }
+            }
+            ParserState::DoseTable => {
+                if line.trim().is_empty() {
+                    ParserState::Finishing
+                } else {
+                    match p.parse_geometry_dose(line) {
+                        Ok(dose) => {
+                            self.dose.push(dose);
+                            self.dose_table.push_str(line.trim_end());
+                            self.dose_table.push('\n');
+                            self.state
+                        }
+                        Err(e) => {
+                            self.dose_error = Some(e);
+                            ParserState::Finishing
+                        }
                     }
                 }
             }
-        }
-    };
-
-    // parse_geometry_dose
-    mline = read_line_until(reader, &Regex::new("finishSimulation").unwrap());
-    let simulation_finished = match mline {
-        None => Err("Cannot find SingSimFinished".to_string()),
-        Some(_) => {
-            if read_line(reader).is_none() {
-                Ok(true)
-            } else {
-                Ok(false)
+            ParserState::Finishing => {
+                if p.finish_simulation.is_match(line) {
+                    ParserState::Done { trailing_lines: 0 }
+                } else {
+                    self.state
+                }
             }
+            ParserState::Done { trailing_lines } => ParserState::Done {
+                trailing_lines: trailing_lines + 1,
+            },
         }
-    };
-    let ret = SingSimParsedOutput {
-        dose,
-        total_cpu_time,
-        simulation_finished,
-    };
-    Ok(ret)
+    }
+
+    fn missing(&self, what: &str) -> String {
+        let mut msg = format!("Cannot find {}. Output ended in state {:?}", what, self.state);
+        if let Some(e) = self.header_errors.first() {
+            msg.push_str(&format!(". {}", e));
+        }
+        msg
+    }
+
+    /// The results gathered so far.
+    pub fn result(&self) -> SingSimParsedOutput {
+        let reached_dose_table = matches!(
+            self.state,
+            ParserState::DoseTable | ParserState::Finishing | ParserState::Done { .. }
+        );
+        let dose = match self.dose_error {
+            Some(ref e) => Err(e.clone()),
+            None if reached_dose_table => Ok(self.dose.clone()),
+            None => Err(self.missing("dose")),
+        };
+        let total_cpu_time = match self.total_cpu_time {
+            Some(ref t) => t.clone(),
+            None => Err(self.missing("Total cpu time for this run")),
+        };
+        let simulation_finished = match self.state {
+            ParserState::Done { trailing_lines } => Ok(trailing_lines == 0),
+            _ => Err(self.missing("SingSimFinished")),
+        };
+        SingSimParsedOutput {
+            total_cpu_time,
+            simulation_finished,
+            histories: self.last_case,
+            dose_table: dose.as_ref().ok().map(|_| self.dose_table.clone()),
+            dose,
+        }
+    }
+}
+
+/// Read lines until end of file. Invalid UTF-8 is replaced instead of
+/// failing the whole parse.
+pub fn for_each_line<F: FnMut(&str)>(reader: &mut dyn BufRead, mut f: F) -> Result<()> {
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        let n = reader
+            .read_until(b'\n', &mut buf)
+            .chain_err(|| "Cannot read simulation output")?;
+        if n == 0 {
+            return Ok(());
+        }
+        f(&String::from_utf8_lossy(&buf));
+    }
+}
+
+pub fn parse_simulation_output(reader: &mut dyn BufRead) -> Result<SingSimParsedOutput> {
+    let mut parser = OutputParser::new();
+    for_each_line(reader, |line| parser.feed_line(line))?;
+    Ok(parser.result())
 }
 
 #[cfg(test)]
@@ -157,38 +361,53 @@ mod tests {
     use super::*;
     use util::asset_path;
     use uncertain::Uf64;
+    use std::path::Path;
+    use std::fs::File;
+    use std::io::{BufReader, Read};
+
     fn parse_simulation_output_from_file(path: &Path) -> SingSimParsedOutput {
-        use std::fs::File;
-        use std::io::BufReader;
         let f = File::open(path).unwrap();
         let mut r = BufReader::new(f);
         let out = parse_simulation_output(&mut r).unwrap();
         out
     }
 
+    fn parse_str(s: &str) -> SingSimParsedOutput {
+        parse_simulation_output(&mut BufReader::new(s.as_bytes())).unwrap()
+    }
+
     #[test]
     fn test_parse_dot_separated_key_value() {
         let s = "configuration...linux64";
         assert_eq!(
-            parse_dot_separated_key_value(s),
+            Patterns::new().parse_dot_separated_key_value(s),
             Some(("configuration".to_string(), "linux64".to_string()))
         );
     }
 
     #[test]
     fn test_parse_geometry_dose() {
+        let p = Patterns::new();
         let line = "Block_                    0.0000e+00 +/- 100.000% \n";
         assert_eq!(
-            parse_geometry_dose(&line),
+            p.parse_geometry_dose(&line),
             Ok(("Block_".to_string(), Uf64::from_value_rstd(0., 1.)))
         );
 
         let line = "Block_                    2.1867e-16 +/- 54.499 % \n";
         let score = Uf64::from_value_rstd(0.00000000000000021867, 0.54499);
         assert_eq!(
-            parse_geometry_dose(&line),
+            p.parse_geometry_dose(&line),
             Ok(("Block_".to_string(), score))
         );
+
+        let line = "Block_                    2.1867e-16 +/- inf % \n";
+        let (_, score) = p.parse_geometry_dose(line).unwrap();
+        assert_eq!(score.value(), 2.1867e-16);
+        assert_eq!(score.rstd(), f64::INFINITY);
+        let line = "Block_                    0.0000e+00 +/- nan % \n";
+        let (_, score) = p.parse_geometry_dose(line).unwrap();
+        assert_eq!(score.rstd(), f64::INFINITY);
     }
 
     #[test]
@@ -197,6 +416,10 @@ mod tests {
         let out = parse_simulation_output_from_file(&path);
         assert_eq!(out.total_cpu_time.unwrap(), 1997.04);
         assert_eq!(out.simulation_finished.unwrap(), true);
+        assert_eq!(out.histories, Some(1400000));
+        let table = out.dose_table.unwrap();
+        assert_eq!(table.lines().count(), 82);
+        assert!(table.lines().all(|line| line.contains(" +/- ")));
         let dose = out.dose.unwrap();
         let dose0 = ("PSS_Box".to_string(), Uf64::from_value_rstd(0.0, 1.0));
         let dose1 = (
@@ -220,6 +443,7 @@ mod tests {
         for path in [path1, path2].iter() {
             let out = parse_simulation_output_from_file(&path);
             assert!(out.simulation_finished.unwrap());
+            assert_eq!(out.histories, Some(125000));
             let dose = out.dose.unwrap();
             assert_eq!(dose.len(), 1);
             assert_eq!(
@@ -231,4 +455,85 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_parse_partial_simulation_output() {
+        let mut log = String::new();
+        File::open(asset_path().join("Wasservoxel.log"))
+            .unwrap()
+            .read_to_string(&mut log)
+            .unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+
+        // killed while running
+        let out = parse_str(&lines[..500].join("\n"));
+        assert!(out.dose.is_err());
+        assert!(out.total_cpu_time.is_err());
+        assert!(out.simulation_finished.is_err());
+
+        // killed while writing the dose table
+        let out = parse_str(&lines[..830].join("\n"));
+        assert_eq!(out.total_cpu_time.unwrap(), 1997.04);
+        assert_eq!(out.dose.unwrap().len(), 5);
+        assert!(out.simulation_finished.is_err());
+
+        // garbage in the dose table
+        let mut garbled = lines.clone();
+        garbled[827] = "garbage";
+        let out = parse_str(&garbled.join("\n"));
+        assert_eq!(out.total_cpu_time.unwrap(), 1997.04);
+        assert!(out.dose.is_err());
+        assert!(out.simulation_finished.unwrap());
+    }
+
+    #[test]
+    fn test_parse_long_output() {
+        let mut parser = OutputParser::new();
+        for _ in 0..200_000 {
+            parser.feed_line("some line that does not match anything\n");
+        }
+        assert_eq!(parser.state(), ParserState::Preamble { separators: 0 });
+        assert!(parser.result().dose.is_err());
+    }
+
+    #[test]
+    fn test_parse_progress() {
+        let mut log = String::new();
+        File::open(asset_path().join("Wasservoxel.log"))
+            .unwrap()
+            .read_to_string(&mut log)
+            .unwrap();
+        let mut parser = OutputParser::new();
+        for line in log.lines().take(712) {
+            parser.feed_line(line);
+        }
+        let progress = parser.progress();
+        assert_eq!(progress.histories, Some(1400000));
+        let batch = Batch {
+            batch: 2,
+            cpu_time: 38.70,
+            result: 4.96147e-13,
+            rstd: 0.0604,
+        };
+        assert_eq!(progress.last_batch, Some(batch));
+        assert!(!progress.finished);
+        for line in log.lines().skip(712) {
+            parser.feed_line(line);
+        }
+        assert_eq!(parser.progress().last_batch.unwrap().batch, 100);
+        assert!(parser.progress().finished);
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let path = asset_path().join("Wasservoxel.log");
+        let mut parser = OutputParser::new();
+        let mut r = BufReader::new(File::open(path).unwrap());
+        for_each_line(&mut r, |line| parser.feed_line(line)).unwrap();
+        assert_eq!(
+            parser.header()[1],
+            ("user code".to_string(), "egs_chamber".to_string())
+        );
+        assert_eq!(parser.header().len(), 6);
+    }
 }
@@ -0,0 +1,448 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::io;
+use std::path::{Path, PathBuf};
+use errors::*;
+
+/// Electron rest mass in MeV. Charged particle energies in a phase space
+/// file are total energies.
+const REST_MASS: f32 = 0.511;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PhspMode {
+    Mode0,
+    Mode2,
+}
+
+impl PhspMode {
+    pub fn record_length(&self) -> usize {
+        match *self {
+            PhspMode::Mode0 => 28,
+            PhspMode::Mode2 => 32,
+        }
+    }
+
+    fn tag(&self) -> &'static [u8; 5] {
+        match *self {
+            PhspMode::Mode0 => b"MODE0",
+            PhspMode::Mode2 => b"MODE2",
+        }
+    }
+
+    fn from_tag(tag: &[u8]) -> Result<PhspMode> {
+        match tag {
+            b"MODE0" => Ok(PhspMode::Mode0),
+            b"MODE2" => Ok(PhspMode::Mode2),
+            _ => bail!(
+                "Unknown phase space mode {:?}",
+                String::from_utf8_lossy(tag)
+            ),
+        }
+    }
+}
+
+/// Header of an .egsphsp file. It occupies the first record of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PhspHeader {
+    pub mode: PhspMode,
+    pub nparticles: i32,
+    pub nphotons: i32,
+    pub emax: f32,
+    pub emin_electrons: f32,
+    pub nincident: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PhspRecord {
+    pub latch: u32,
+    pub energy: f32,
+    pub x: f32,
+    pub y: f32,
+    pub u: f32,
+    pub v: f32,
+    pub weight: f32,
+    pub zlast: Option<f32>,
+}
+
+fn get_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn get_f32(buf: &[u8], offset: usize) -> f32 {
+    f32::from_bits(get_u32(buf, offset))
+}
+
+fn put_u32(buf: &mut [u8], offset: usize, x: u32) {
+    buf[offset..offset + 4].copy_from_slice(&x.to_le_bytes());
+}
+
+fn put_f32(buf: &mut [u8], offset: usize, x: f32) {
+    put_u32(buf, offset, x.to_bits());
+}
+
+impl PhspHeader {
+    pub fn read<R: Read>(reader: &mut R) -> Result<PhspHeader> {
+        let mut tag = [0; 5];
+        reader
+            .read_exact(&mut tag)
+            .chain_err(|| "Cannot read phase space header")?;
+        let mode = PhspMode::from_tag(&tag)?;
+        let mut buf = vec![0; mode.record_length() - tag.len()];
+        reader
+            .read_exact(&mut buf)
+            .chain_err(|| "Cannot read phase space header")?;
+        let ret = PhspHeader {
+            mode,
+            nparticles: get_u32(&buf, 0) as i32,
+            nphotons: get_u32(&buf, 4) as i32,
+            emax: get_f32(&buf, 8),
+            emin_electrons: get_f32(&buf, 12),
+            nincident: get_f32(&buf, 16),
+        };
+        Ok(ret)
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut buf = vec![0; self.mode.record_length()];
+        buf[0..5].copy_from_slice(self.mode.tag());
+        put_u32(&mut buf, 5, self.nparticles as u32);
+        put_u32(&mut buf, 9, self.nphotons as u32);
+        put_f32(&mut buf, 13, self.emax);
+        put_f32(&mut buf, 17, self.emin_electrons);
+        put_f32(&mut buf, 21, self.nincident);
+        writer.write_all(&buf)
+    }
+
+    /// Header of the concatenation of phase spaces with the given headers.
+    pub fn combine(headers: &[PhspHeader]) -> Result<PhspHeader> {
+        let mut ret = match headers.first() {
+            Some(h) => *h,
+            None => bail!("Cannot combine empty collection of phase spaces."),
+        };
+        // f32 loses whole histories beyond 2^24, so sum in f64
+        let mut nincident = f64::from(ret.nincident);
+        for h in &headers[1..] {
+            if h.mode != ret.mode {
+                bail!(
+                    "Cannot combine phase spaces of modes {:?} and {:?}",
+                    ret.mode,
+                    h.mode
+                );
+            }
+            ret.nparticles = ret.nparticles
+                .checked_add(h.nparticles)
+                .chain_err(|| "Too many particles for a single phase space file")?;
+            ret.nphotons = ret.nphotons
+                .checked_add(h.nphotons)
+                .chain_err(|| "Too many photons for a single phase space file")?;
+            ret.emax = ret.emax.max(h.emax);
+            ret.emin_electrons = ret.emin_electrons.min(h.emin_electrons);
+            nincident += f64::from(h.nincident);
+        }
+        ret.nincident = nincident as f32;
+        Ok(ret)
+    }
+}
+
+impl PhspRecord {
+    pub fn read<R: Read>(reader: &mut R, mode: PhspMode) -> Result<Option<PhspRecord>> {
+        let mut buf = vec![0; mode.record_length()];
+        match reader.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e).chain_err(|| "Cannot read phase space record"),
+        }
+        let zlast = match mode {
+            PhspMode::Mode0 => None,
+            PhspMode::Mode2 => Some(get_f32(&buf, 28)),
+        };
+        let ret = PhspRecord {
+            latch: get_u32(&buf, 0),
+            energy: get_f32(&buf, 4),
+            x: get_f32(&buf, 8),
+            y: get_f32(&buf, 12),
+            u: get_f32(&buf, 16),
+            v: get_f32(&buf, 20),
+            weight: get_f32(&buf, 24),
+            zlast,
+        };
+        Ok(Some(ret))
+    }
+
+    #[allow(dead_code)]
+    pub fn write<W: Write>(&self, writer: &mut W, mode: PhspMode) -> io::Result<()> {
+        let mut buf = vec![0; mode.record_length()];
+        put_u32(&mut buf, 0, self.latch);
+        put_f32(&mut buf, 4, self.energy);
+        put_f32(&mut buf, 8, self.x);
+        put_f32(&mut buf, 12, self.y);
+        put_f32(&mut buf, 16, self.u);
+        put_f32(&mut buf, 20, self.v);
+        put_f32(&mut buf, 24, self.weight);
+        if let (PhspMode::Mode2, Some(z)) = (mode, self.zlast) {
+            put_f32(&mut buf, 28, z);
+        }
+        writer.write_all(&buf)
+    }
+
+    /// Charge encoded in LATCH: bit 30 marks electrons, bit 29 positrons.
+    pub fn charge(&self) -> i32 {
+        if self.latch & (1 << 30) != 0 {
+            -1
+        } else if self.latch & (1 << 29) != 0 {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// A negative energy marks the first particle of a new history.
+    pub fn is_new_history(&self) -> bool {
+        self.energy.is_sign_negative()
+    }
+
+    pub fn kinetic_energy(&self) -> f32 {
+        let e = self.energy.abs();
+        if self.charge() == 0 {
+            e
+        } else {
+            e - REST_MASS
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).chain_err(|| cannot_read(&path))?;
+    Ok(BufReader::new(file))
+}
+
+pub fn read_header(path: &Path) -> Result<PhspHeader> {
+    PhspHeader::read(&mut open(path)?).chain_err(|| cannot_read(&path))
+}
+
+/// Concatenate the phase space files `inputs` into `output`.
+pub fn combine(inputs: &[PathBuf], output: &Path) -> Result<PhspHeader> {
+    let headers = inputs
+        .iter()
+        .map(|p| read_header(p))
+        .collect::<Result<Vec<PhspHeader>>>()?;
+    let header = PhspHeader::combine(&headers)?;
+    let file = File::create(output).chain_err(|| cannot_create(&output))?;
+    let mut writer = BufWriter::new(file);
+    header
+        .write(&mut writer)
+        .chain_err(|| cannot_write(&output))?;
+    let reclen = header.mode.record_length();
+    for (path, h) in inputs.iter().zip(&headers) {
+        let mut reader = open(path)?;
+        reader
+            .seek(SeekFrom::Start(reclen as u64))
+            .chain_err(|| cannot_read(&path))?;
+        let nbytes = (h.nparticles as u64) * (reclen as u64);
+        let copied = io::copy(&mut reader.take(nbytes), &mut writer)
+            .chain_err(|| cannot_write(&output))?;
+        if copied != nbytes {
+            bail!(
+                "Phase space {:?} is truncated: header announces {} particles",
+                path,
+                h.nparticles
+            );
+        }
+    }
+    writer.flush().chain_err(|| cannot_write(&output))?;
+    Ok(header)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PhspStats {
+    pub header: PhspHeader,
+    pub nrecords: u64,
+    pub nhistories: u64,
+    pub nphotons: u64,
+    pub nelectrons: u64,
+    pub npositrons: u64,
+    pub total_weight: f64,
+    pub mean_kinetic_energy: f64,
+    pub min_kinetic_energy: f32,
+    pub max_kinetic_energy: f32,
+}
+
+impl PhspStats {
+    pub fn from_path(path: &Path) -> Result<PhspStats> {
+        let mut reader = open(path)?;
+        let header = PhspHeader::read(&mut reader).chain_err(|| cannot_read(&path))?;
+        let mut ret = PhspStats {
+            header,
+            nrecords: 0,
+            nhistories: 0,
+            nphotons: 0,
+            nelectrons: 0,
+            npositrons: 0,
+            total_weight: 0.,
+            mean_kinetic_energy: 0.,
+            min_kinetic_energy: f32::INFINITY,
+            max_kinetic_energy: f32::NEG_INFINITY,
+        };
+        let mut weighted_energy = 0.;
+        while let Some(rec) = PhspRecord::read(&mut reader, header.mode)
+            .chain_err(|| cannot_read(&path))?
+        {
+            ret.nrecords += 1;
+            if rec.is_new_history() {
+                ret.nhistories += 1;
+            }
+            match rec.charge() {
+                0 => ret.nphotons += 1,
+                -1 => ret.nelectrons += 1,
+                _ => ret.npositrons += 1,
+            }
+            let ekin = rec.kinetic_energy();
+            let wt = f64::from(rec.weight.abs());
+            ret.total_weight += wt;
+            weighted_energy += wt * f64::from(ekin);
+            ret.min_kinetic_energy = ret.min_kinetic_energy.min(ekin);
+            ret.max_kinetic_energy = ret.max_kinetic_energy.max(ekin);
+        }
+        if ret.total_weight > 0. {
+            ret.mean_kinetic_energy = weighted_energy / ret.total_weight;
+        }
+        Ok(ret)
+    }
+}
+
+impl fmt::Display for PhspStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let h = &self.header;
+        writeln!(f, "Mode: {:?}", h.mode)?;
+        writeln!(f, "Particles (header): {}", h.nparticles)?;
+        writeln!(f, "Photons (header): {}", h.nphotons)?;
+        writeln!(f, "Max kinetic energy (header): {} MeV", h.emax)?;
+        writeln!(f, "Min electron kinetic energy (header): {} MeV", h.emin_electrons)?;
+        writeln!(f, "Incident particles (header): {}", h.nincident)?;
+        writeln!(f, "Records: {}", self.nrecords)?;
+        writeln!(f, "Histories: {}", self.nhistories)?;
+        writeln!(f, "Photons: {}", self.nphotons)?;
+        writeln!(f, "Electrons: {}", self.nelectrons)?;
+        writeln!(f, "Positrons: {}", self.npositrons)?;
+        writeln!(f, "Total weight: {}", self.total_weight)?;
+        writeln!(f, "Mean kinetic energy: {} MeV", self.mean_kinetic_energy)?;
+        writeln!(
+            f,
+            "Kinetic energy range: {} - {} MeV",
+            self.min_kinetic_energy, self.max_kinetic_energy
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn record(latch: u32, energy: f32, zlast: Option<f32>) -> PhspRecord {
+        PhspRecord {
+            latch,
+            energy,
+            x: 1.,
+            y: -2.,
+            u: 0.,
+            v: 0.5,
+            weight: 1.,
+            zlast,
+        }
+    }
+
+    fn write_phsp(path: &Path, header: &PhspHeader, records: &[PhspRecord]) {
+        let mut file = File::create(path).unwrap();
+        header.write(&mut file).unwrap();
+        for rec in records {
+            rec.write(&mut file, header.mode).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_phsp() {
+        for &(mode, zlast) in [(PhspMode::Mode0, None), (PhspMode::Mode2, Some(3.))].iter() {
+            let dir = tempdir().unwrap();
+            let path = dir.path().join("a.egsphsp1");
+            let header = PhspHeader {
+                mode,
+                nparticles: 2,
+                nphotons: 1,
+                emax: 6.,
+                emin_electrons: 0.7,
+                nincident: 10.,
+            };
+            let records = [record(0, -6., zlast), record(1 << 29, 1.211, zlast)];
+            write_phsp(&path, &header, &records);
+            let mut reader = open(&path).unwrap();
+            assert_eq!(PhspHeader::read(&mut reader).unwrap(), header);
+            for rec in records.iter() {
+                assert_eq!(PhspRecord::read(&mut reader, mode).unwrap(), Some(*rec));
+            }
+            assert_eq!(PhspRecord::read(&mut reader, mode).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_combine_phsp() {
+        let dir = tempdir().unwrap();
+        let path1 = dir.path().join("1.egsphsp1");
+        let path2 = dir.path().join("2.egsphsp1");
+        let path = dir.path().join("combined.egsphsp1");
+        let header1 = PhspHeader {
+            mode: PhspMode::Mode0,
+            nparticles: 2,
+            nphotons: 1,
+            emax: 6.,
+            emin_electrons: 0.7,
+            nincident: 10.,
+        };
+        let header2 = PhspHeader {
+            nparticles: 1,
+            nphotons: 1,
+            emax: 5.,
+            emin_electrons: 1.,
+            nincident: 7.,
+            ..header1
+        };
+        write_phsp(
+            &path1,
+            &header1,
+            &[record(0, -6., None), record(1 << 29, 1.211, None)],
+        );
+        write_phsp(&path2, &header2, &[record(0, -5., None)]);
+
+        let header = combine(&[path1, path2], &path).unwrap();
+        assert_eq!(header.nparticles, 3);
+        assert_eq!(header.nphotons, 2);
+        assert_eq!(header.emax, 6.);
+        assert_eq!(header.emin_electrons, 0.7);
+        assert_eq!(header.nincident, 17.);
+
+        let stats = PhspStats::from_path(&path).unwrap();
+        assert_eq!(stats.header, header);
+        assert_eq!(stats.nrecords, 3);
+        assert_eq!(stats.nhistories, 2);
+        assert_eq!(stats.nphotons, 2);
+        assert_eq!(stats.npositrons, 1);
+        assert_eq!(stats.nelectrons, 0);
+        assert_eq!(record(1 << 30, 1.211, None).charge(), -1);
+
+        // beyond 2^24 adding one history at a time is lost in f32
+        let big = PhspHeader {
+            nincident: 16777216.,
+            ..header1
+        };
+        let one = PhspHeader {
+            nincident: 1.,
+            ..header1
+        };
+        assert_eq!(PhspHeader::combine(&[big, one, one]).unwrap().nincident, 16777218.);
+        assert_eq!(stats.max_kinetic_energy, 6.);
+        assert_relative_eq!(stats.min_kinetic_energy, 0.7, epsilon = 1e-6);
+    }
+}
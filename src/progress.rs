@@ -0,0 +1,258 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use libc;
+use output_parser::Progress;
+use uncertain::Uf64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressMode {
+    Off,
+    /// Redraw a per-chunk display in place.
+    Tty,
+    /// Print a status line every `interval` seconds.
+    Plain { interval: f64 },
+}
+
+impl ProgressMode {
+    /// Redraw in place on a terminal, plain status lines otherwise.
+    pub fn auto(interval: f64) -> Self {
+        if unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1 {
+            ProgressMode::Tty
+        } else {
+            ProgressMode::Plain { interval }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ChunkStatus {
+    ncase: u64,
+    progress: Option<Progress>,
+}
+
+impl ChunkStatus {
+    fn fraction(&self, nbatch: usize) -> f64 {
+        match self.progress {
+            Some(Progress { finished: true, .. }) => 1.,
+            Some(Progress {
+                last_batch: Some(b),
+                ..
+            }) if nbatch > 0 => (b.batch as f64 / nbatch as f64).min(1.),
+            _ => 0.,
+        }
+    }
+
+    fn estimate(&self) -> Option<Uf64> {
+        let b = self.progress?.last_batch?;
+        Some(Uf64::from_value_rstd(b.result, b.rstd))
+    }
+}
+
+/// Collects the progress of all chunks of a run and periodically prints it.
+#[derive(Debug)]
+pub struct ProgressMonitor {
+    mode: ProgressMode,
+    nbatch: usize,
+    start: Instant,
+    chunks: Mutex<Vec<ChunkStatus>>,
+    done: AtomicBool,
+}
+
+const TTY_INTERVAL: f64 = 1.;
+const BAR_WIDTH: usize = 20;
+
+fn format_duration(secs: f64) -> String {
+    if !secs.is_finite() {
+        return "--:--:--".to_string();
+    }
+    let s = secs.round() as u64;
+    format!("{:02}:{:02}:{:02}", s / 3600, (s / 60) % 60, s % 60)
+}
+
+fn bar(fraction: f64) -> String {
+    let n = (fraction * BAR_WIDTH as f64).round() as usize;
+    format!("[{}{}]", "#".repeat(n), ".".repeat(BAR_WIDTH - n))
+}
+
+impl ProgressMonitor {
+    pub fn new(mode: ProgressMode, ncases: &[u64], nbatch: usize) -> Arc<Self> {
+        let chunks = ncases
+            .iter()
+            .map(|&ncase| ChunkStatus {
+                ncase,
+                progress: None,
+            })
+            .collect();
+        Arc::new(ProgressMonitor {
+            mode,
+            nbatch,
+            start: Instant::now(),
+            chunks: Mutex::new(chunks),
+            done: AtomicBool::new(false),
+        })
+    }
+
+    pub fn update(&self, chunk: usize, progress: Progress) {
+        if let Ok(mut chunks) = self.chunks.lock() {
+            if let Some(c) = chunks.get_mut(chunk) {
+                c.progress = Some(progress);
+            }
+        }
+    }
+
+    fn summary(&self, chunks: &[ChunkStatus]) -> String {
+        let total: u64 = chunks.iter().map(|c| c.ncase).sum();
+        let done: f64 = chunks
+            .iter()
+            .map(|c| c.fraction(self.nbatch) * c.ncase as f64)
+            .sum();
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = if elapsed > 0. { done / elapsed } else { 0. };
+        let eta = (total as f64 - done) / rate;
+        let fraction = if total > 0 { done / total as f64 } else { 0. };
+        let estimates: Vec<Uf64> = chunks.iter().filter_map(|c| c.estimate()).collect();
+        let rstd = match estimates.len() {
+            0 => "--".to_string(),
+            n => {
+                let sum = estimates[1..].iter().fold(estimates[0], |acc, &x| acc + x);
+                let mean = sum * Uf64::from_value(1. / n as f64);
+                format!("{:.2}%", mean.rstd() * 100.)
+            }
+        };
+        format!(
+            "{} {:5.1}% {:.0}/{} histories, {:.3e} histories/h, elapsed {}, ETA {}, rstd {}",
+            bar(fraction),
+            fraction * 100.,
+            done,
+            total,
+            rate * 3600.,
+            format_duration(elapsed),
+            format_duration(eta),
+            rstd
+        )
+    }
+
+    fn chunk_line(&self, i: usize, c: &ChunkStatus) -> String {
+        let fraction = c.fraction(self.nbatch);
+        let last_batch = c.progress.and_then(|p| p.last_batch);
+        let batch = last_batch.map(|b| b.batch).unwrap_or(0);
+        let rstd = match last_batch {
+            Some(b) => format!("{:.2}%", b.rstd * 100.),
+            None => "--".to_string(),
+        };
+        format!(
+            "chunk {:3} {} {:5.1}% batch {}/{} rstd {}",
+            i,
+            bar(fraction),
+            fraction * 100.,
+            batch,
+            self.nbatch,
+            rstd
+        )
+    }
+
+    /// The lines of the current display.
+    pub fn render(&self) -> Vec<String> {
+        let chunks = match self.chunks.lock() {
+            Ok(chunks) => chunks.clone(),
+            Err(_) => return Vec::new(),
+        };
+        let mut ret = Vec::new();
+        if self.mode == ProgressMode::Tty {
+            for (i, c) in chunks.iter().enumerate() {
+                ret.push(self.chunk_line(i, c));
+            }
+        }
+        ret.push(self.summary(&chunks));
+        ret
+    }
+
+    fn print(&self, nprinted: usize) -> usize {
+        let lines = self.render();
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        if self.mode == ProgressMode::Tty {
+            if nprinted > 0 {
+                let _ = write!(out, "\x1b[{}A", nprinted);
+            }
+            for line in &lines {
+                let _ = writeln!(out, "\x1b[K{}", line);
+            }
+        } else {
+            let _ = writeln!(out, "{}", lines.join("\n"));
+        }
+        let _ = out.flush();
+        lines.len()
+    }
+
+    /// Print the progress periodically until `finish` is called.
+    pub fn spawn(monitor: &Arc<ProgressMonitor>) -> Option<thread::JoinHandle<()>> {
+        let interval = match monitor.mode {
+            ProgressMode::Off => return None,
+            ProgressMode::Tty => TTY_INTERVAL,
+            ProgressMode::Plain { interval } => interval,
+        };
+        let monitor = monitor.clone();
+        let handle = thread::spawn(move || {
+            let interval = Duration::from_secs_f64(interval);
+            let mut last = Instant::now();
+            let mut nprinted = 0;
+            while !monitor.done.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(100));
+                if last.elapsed() >= interval {
+                    nprinted = monitor.print(nprinted);
+                    last = Instant::now();
+                }
+            }
+            monitor.print(nprinted);
+        });
+        Some(handle)
+    }
+
+    pub fn finish(&self, handle: Option<thread::JoinHandle<()>>) {
+        self.done.store(true, Ordering::SeqCst);
+        if let Some(h) = handle {
+            let _ = h.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use output_parser::Batch;
+
+    fn progress(batch: usize, rstd: f64, finished: bool) -> Progress {
+        Progress {
+            histories: Some(1000),
+            last_batch: Some(Batch {
+                batch,
+                cpu_time: 1.,
+                result: 2.,
+                rstd,
+            }),
+            finished,
+        }
+    }
+
+    #[test]
+    fn test_progress_monitor() {
+        let monitor = ProgressMonitor::new(ProgressMode::Tty, &[1000, 1000, 2000], 10);
+        monitor.update(0, progress(5, 0.04, false));
+        monitor.update(1, progress(10, 0.02, true));
+        let lines = monitor.render();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].contains(" 50.0% batch 5/10 rstd 4.00%"));
+        assert!(lines[1].contains("100.0%"));
+        assert!(lines[2].contains("batch 0/10 rstd --"));
+        assert!(lines[3].contains(" 37.5% 1500/4000 histories"));
+        // two chunks with equal results, rstd 4% and 2%
+        assert!(lines[3].contains("rstd 2.24%"));
+
+        let monitor = ProgressMonitor::new(ProgressMode::Plain { interval: 1. }, &[10], 10);
+        assert_eq!(monitor.render().len(), 1);
+    }
+}
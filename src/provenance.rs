@@ -0,0 +1,192 @@
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::env;
+use sha3;
+use sha3::Digest;
+use errors::*;
+use simulation::SingSimInput;
+use util::{self, HenInfo};
+
+/// Number of lines at the start of an application log searched for
+/// information about the EGSnrc build.
+const HEADER_LINES: usize = 200;
+
+/// What a report was produced with, to reproduce it or explain differences.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// The hen command line.
+    pub command_line: Vec<String>,
+    pub hen: HenInfo,
+    pub host: String,
+    pub executable: Option<PathBuf>,
+    /// SHA3-256 of the application binary.
+    pub executable_hash: Option<String>,
+    pub pegs_path: Option<PathBuf>,
+    /// SHA3-256 of the pegs4 data file.
+    pub pegs_hash: Option<String>,
+    /// Name of the EGS_CONFIG file, e.g. linux for .../specs/linux.conf.
+    pub egs_config: Option<String>,
+    /// Lines about the EGSnrc build and compilers from the application log.
+    pub build_info: Vec<String>,
+}
+
+/// SHA3-256 of the content of the file at `path`.
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path).chain_err(|| cannot_read(&path))?;
+    let mut hasher = sha3::Sha3_256::default();
+    let mut buf = [0u8; 1 << 16];
+    loop {
+        let n = file.read(&mut buf).chain_err(|| cannot_read(&path))?;
+        if n == 0 {
+            break;
+        }
+        hasher.input(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// Lines of the log header that describe the EGSnrc build.
+pub fn build_info(log: &str) -> Vec<String> {
+    let mut ret: Vec<String> = Vec::new();
+    for line in log.lines().take(HEADER_LINES) {
+        let lower = line.to_lowercase();
+        if lower.contains("compiler") || lower.contains("egsnrc version") {
+            let line = line.trim().to_string();
+            if !ret.contains(&line) {
+                ret.push(line);
+            }
+        }
+    }
+    ret
+}
+
+/// The pegs4 data file EGSnrc reads for `pegsfile`, looked up in EGS_HOME
+/// before HEN_HOUSE.
+fn find_pegs_file(egs_home: &Option<PathBuf>, hen_house: &Option<PathBuf>, pegsfile: &str) -> Option<PathBuf> {
+    egs_home
+        .iter()
+        .chain(hen_house.iter())
+        .map(|dir| dir.join("pegs4").join("data").join(format!("{}.pegs4dat", pegsfile)))
+        .find(|p| p.is_file())
+}
+
+impl Provenance {
+    /// Collect the provenance of running `input` on this machine. `log` is
+    /// the output of one of its runs, if there is one.
+    pub fn collect(input: &SingSimInput, log: &str) -> Self {
+        let env = input.env.resolve(&input.application);
+        let executable = env.executable.clone();
+        let executable_hash = executable.as_ref().and_then(|p| hash_file(p).ok());
+        let pegs_path = find_pegs_file(&env.egs_home, &env.hen_house, &input.pegsfile);
+        let pegs_hash = pegs_path.as_ref().and_then(|p| hash_file(p).ok());
+        let egs_config = env.egs_config
+            .as_ref()
+            .and_then(|p| p.file_stem())
+            .map(|s| s.to_string_lossy().to_string());
+        Provenance {
+            command_line: env::args().collect(),
+            hen: HenInfo::new(),
+            host: util::hostname(),
+            executable,
+            executable_hash,
+            pegs_path,
+            pegs_hash,
+            egs_config,
+            build_info: build_info(log),
+        }
+    }
+
+    /// Human readable differences to `other`, e.g. the current machine.
+    /// Build information is only compared if both have some.
+    pub fn differences(&self, other: &Provenance) -> Vec<String> {
+        let mut ret = Vec::new();
+        {
+            let mut cmp = |name: &str, a: &Option<String>, b: &Option<String>| {
+                if a != b {
+                    ret.push(format!("{} is {:?} instead of {:?}", name, b, a));
+                }
+            };
+            cmp("Application hash", &self.executable_hash, &other.executable_hash);
+            cmp("Pegs4 data hash", &self.pegs_hash, &other.pegs_hash);
+            cmp("EGS_CONFIG", &self.egs_config, &other.egs_config);
+        }
+        if !self.build_info.is_empty()
+            && !other.build_info.is_empty()
+            && self.build_info != other.build_info
+        {
+            ret.push(format!(
+                "Build is {:?} instead of {:?}",
+                other.build_info, self.build_info
+            ));
+        }
+        ret
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let describe = |x: &Option<String>| x.clone().unwrap_or_else(|| "unknown".to_string());
+        writeln!(f, "Command: {}", self.command_line.join(" "))?;
+        writeln!(f, "Host: {}, hen {} ({})", self.host, self.hen.version, self.hen.commit)?;
+        writeln!(
+            f,
+            "Application: {:?}, sha3 {}",
+            self.executable,
+            describe(&self.executable_hash)
+        )?;
+        writeln!(f, "Pegs4 data: {:?}, sha3 {}", self.pegs_path, describe(&self.pegs_hash))?;
+        writeln!(f, "EGS_CONFIG: {}", describe(&self.egs_config))?;
+        for line in &self.build_info {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use environment::EgsEnv;
+    use tempfile::tempdir;
+    use util::asset_path;
+
+    #[test]
+    fn test_provenance() {
+        let dir = tempdir().unwrap();
+        let data = dir.path().join("egs_home/pegs4/data");
+        fs::create_dir_all(&data).unwrap();
+        fs::write(data.join("521icru.pegs4dat"), "pegs").unwrap();
+        let app = dir.path().join("egs_chamber");
+        fs::write(&app, "binary").unwrap();
+        let mut input = SingSimInput::from_egsinp_path(
+            "egs_chamber",
+            &asset_path().join("three_calc_geos.egsinp"),
+            "521icru",
+        ).unwrap();
+        input.env = EgsEnv {
+            egs_home: Some(dir.path().join("egs_home")),
+            hen_house: Some(dir.path().join("HEN_HOUSE")),
+            egs_config: Some(PathBuf::from("/egs/HEN_HOUSE/specs/linux.conf")),
+            executable: Some(app.clone()),
+            vars: Vec::new(),
+        };
+        let log = "  EGSnrc version 4 for linux\n  C++ compiler   g++ -O2\n  C++ compiler   g++ -O2\nFinished";
+        let p = Provenance::collect(&input, log);
+        assert_eq!(p.executable, Some(app.clone()));
+        assert_eq!(p.executable_hash, Some(hash_file(&app).unwrap()));
+        assert_eq!(p.pegs_path, Some(data.join("521icru.pegs4dat")));
+        assert_eq!(p.egs_config, Some("linux".to_string()));
+        assert_eq!(p.build_info, vec!["EGSnrc version 4 for linux", "C++ compiler   g++ -O2"]);
+        assert_eq!(p.host, util::hostname());
+
+        let here = Provenance::collect(&input, "");
+        assert!(p.differences(&here).is_empty());
+        fs::write(&app, "rebuilt").unwrap();
+        let rebuilt = Provenance::collect(&input, log);
+        let diffs = p.differences(&rebuilt);
+        assert_eq!(diffs.len(), 1);
+        assert!(diffs[0].starts_with("Application hash"));
+    }
+}
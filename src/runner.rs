@@ -0,0 +1,427 @@
+use std::io;
+use std::io::{BufRead, BufReader, Read};
+use std::mem;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use libc;
+use util;
+
+/// Limits that are applied to each application process.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Limits {
+    /// Wall-clock time in seconds after which the process is killed.
+    pub timeout: Option<f64>,
+    /// Maximal address space in bytes.
+    pub memory: Option<u64>,
+    /// Maximal cpu time in seconds.
+    pub cpu_time: Option<u64>,
+    /// Niceness the process runs with.
+    #[serde(default)]
+    pub nice: Option<i32>,
+    /// The only CPU the process may run on.
+    #[serde(default)]
+    pub cpu: Option<usize>,
+}
+
+/// Resources a process used, as measured by hen.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Accounting {
+    /// Seconds since the Unix epoch when the process was started.
+    pub start: f64,
+    /// Seconds since the Unix epoch when the process exited.
+    pub end: f64,
+    /// User cpu time in seconds.
+    pub user_time: f64,
+    /// System cpu time in seconds.
+    pub system_time: f64,
+    /// Peak resident set size in bytes.
+    pub max_rss: u64,
+    pub hostname: String,
+    /// Signal that terminated the process, if any.
+    pub signal: Option<i32>,
+}
+
+impl Accounting {
+    pub fn wall_time(&self) -> f64 {
+        self.end - self.start
+    }
+}
+
+#[derive(Debug)]
+pub struct ProcessOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub status: ExitStatus,
+    /// Why hen or the operating system stopped the process, if it did.
+    pub killed: Option<String>,
+    pub accounting: Accounting,
+}
+
+/// Number of the last SIGINT or SIGTERM received, zero if none.
+static SIGNAL_RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+extern "C" fn handle_signal(sig: libc::c_int) {
+    SIGNAL_RECEIVED.store(sig as usize, Ordering::SeqCst);
+}
+
+/// Remember SIGINT and SIGTERM instead of dying, so that running
+/// applications can be stopped and their files cleaned up.
+pub fn install_signal_handlers() {
+    let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// The signal that asked hen to stop, if any.
+pub fn interrupted() -> Option<i32> {
+    match SIGNAL_RECEIVED.load(Ordering::SeqCst) {
+        0 => None,
+        sig => Some(sig as i32),
+    }
+}
+
+pub fn signal_name(sig: i32) -> String {
+    match sig {
+        libc::SIGINT => "SIGINT".to_string(),
+        libc::SIGTERM => "SIGTERM".to_string(),
+        libc::SIGKILL => "SIGKILL".to_string(),
+        libc::SIGXCPU => "SIGXCPU".to_string(),
+        libc::SIGSEGV => "SIGSEGV".to_string(),
+        libc::SIGABRT => "SIGABRT".to_string(),
+        _ => format!("signal {}", sig),
+    }
+}
+
+fn rlimit(soft: u64, hard: u64) -> libc::rlimit {
+    libc::rlimit {
+        rlim_cur: soft as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    }
+}
+
+fn check_os(ret: libc::c_int) -> io::Result<()> {
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// CPUs this process may run on.
+#[cfg(target_os = "linux")]
+pub fn available_cpus() -> Vec<usize> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        let size = std::mem::size_of::<libc::cpu_set_t>();
+        if libc::sched_getaffinity(0, size, &mut set) != 0 {
+            return Vec::new();
+        }
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+            .collect()
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn available_cpus() -> Vec<usize> {
+    Vec::new()
+}
+
+#[cfg(target_os = "linux")]
+fn pin_to_cpu(cpu: usize) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        let size = std::mem::size_of::<libc::cpu_set_t>();
+        check_os(libc::sched_setaffinity(0, size, &set))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_cpu(_cpu: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "CPU pinning is only supported on Linux",
+    ))
+}
+
+fn apply_limits(cmd: &mut Command, limits: &Limits) {
+    let Limits {
+        memory,
+        cpu_time,
+        nice,
+        cpu,
+        ..
+    } = *limits;
+    if memory.is_none() && cpu_time.is_none() && nice.is_none() && cpu.is_none() {
+        return;
+    }
+    let set_limits = move || {
+        if let Some(m) = memory {
+            check_os(unsafe { libc::setrlimit(libc::RLIMIT_AS, &rlimit(m, m)) })?;
+        }
+        if let Some(t) = cpu_time {
+            // SIGXCPU at the soft limit, SIGKILL a second later
+            check_os(unsafe { libc::setrlimit(libc::RLIMIT_CPU, &rlimit(t, t + 1)) })?;
+        }
+        if let Some(n) = nice {
+            check_os(unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, n) })?;
+        }
+        if let Some(c) = cpu {
+            pin_to_cpu(c)?;
+        }
+        Ok(())
+    };
+    // these are plain system calls, so they may run between fork and exec
+    unsafe {
+        cmd.pre_exec(set_limits);
+    }
+}
+
+fn spawn_reader<R: Read + Send + 'static>(mut r: R) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = r.read_to_end(&mut buf);
+        buf
+    })
+}
+
+/// Hand each line of `r` to `on_line` as it arrives and collect all bytes.
+fn spawn_line_reader<R, F>(r: R, mut on_line: F) -> thread::JoinHandle<Vec<u8>>
+where
+    R: Read + Send + 'static,
+    F: FnMut(&str) + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut reader = BufReader::new(r);
+        let mut buf = Vec::new();
+        let mut line = Vec::new();
+        while let Ok(n) = reader.read_until(b'\n', &mut line) {
+            if n == 0 {
+                break;
+            }
+            on_line(String::from_utf8_lossy(&line).trim_end());
+            buf.append(&mut line);
+        }
+        buf
+    })
+}
+
+fn unix_time(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.)
+}
+
+fn seconds(t: libc::timeval) -> f64 {
+    t.tv_sec as f64 + t.tv_usec as f64 * 1e-6
+}
+
+/// Like `Child::try_wait`, or `Child::wait` if `block`, but also return
+/// the resources the child used. The child is reaped by this, so it must
+/// not be waited for by other means afterwards.
+fn wait4(child: &Child, block: bool) -> io::Result<Option<(ExitStatus, libc::rusage)>> {
+    let flags = if block { 0 } else { libc::WNOHANG };
+    loop {
+        let mut status = 0;
+        let mut usage: libc::rusage = unsafe { mem::zeroed() };
+        let pid = unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, flags, &mut usage) };
+        if pid > 0 {
+            return Ok(Some((ExitStatus::from_raw(status), usage)));
+        }
+        if pid == 0 {
+            return Ok(None);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+fn send_signal(child: &Child, sig: i32) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, sig);
+    }
+}
+
+/// Terminate politely, then kill if the child does not exit in time.
+fn terminate(child: &mut Child) -> io::Result<(ExitStatus, libc::rusage)> {
+    send_signal(child, libc::SIGTERM);
+    let start = Instant::now();
+    while start.elapsed() < GRACE_PERIOD {
+        if let Some(ret) = wait4(child, false)? {
+            return Ok(ret);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    child.kill()?;
+    Ok(wait4(child, true)?.expect("blocking wait returns a status"))
+}
+
+type Waited = (ExitStatus, libc::rusage, Option<String>);
+
+fn wait_with_limits(child: &mut Child, limits: &Limits) -> io::Result<Waited> {
+    let start = Instant::now();
+    let timeout = limits.timeout.map(Duration::from_secs_f64);
+    loop {
+        if let Some((status, usage)) = wait4(child, false)? {
+            let killed = match status.signal() {
+                Some(libc::SIGXCPU) => Some("Cpu time limit exceeded".to_string()),
+                Some(sig) => Some(format!("Terminated by {}", signal_name(sig))),
+                None => None,
+            };
+            return Ok((status, usage, killed));
+        }
+        if let Some(sig) = interrupted() {
+            let (status, usage) = terminate(child)?;
+            let reason = format!("Interrupted by {}", signal_name(sig));
+            return Ok((status, usage, Some(reason)));
+        }
+        if let Some(t) = timeout {
+            if start.elapsed() > t {
+                let (status, usage) = terminate(child)?;
+                let reason = format!("Wall-clock timeout of {} s exceeded", t.as_secs_f64());
+                return Ok((status, usage, Some(reason)));
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Run `cmd` to completion, respecting `limits` and interruption by the user.
+#[allow(dead_code)]
+pub fn run_with_limits(cmd: &mut Command, limits: &Limits) -> io::Result<ProcessOutput> {
+    run_streaming(cmd, limits, |_| {})
+}
+
+/// Like `run_with_limits`, but pass each line of stdout to `on_stdout_line`
+/// while the command runs.
+pub fn run_streaming<F>(cmd: &mut Command, limits: &Limits, on_stdout_line: F) -> io::Result<ProcessOutput>
+where
+    F: FnMut(&str) + Send + 'static,
+{
+    apply_limits(cmd, limits);
+    let start = SystemTime::now();
+    let mut child = cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().map(|r| spawn_line_reader(r, on_stdout_line));
+    let stderr = child.stderr.take().map(spawn_reader);
+    let (status, usage, killed) = wait_with_limits(&mut child, limits)?;
+    let accounting = Accounting {
+        start: unix_time(start),
+        end: unix_time(SystemTime::now()),
+        user_time: seconds(usage.ru_utime),
+        system_time: seconds(usage.ru_stime),
+        // in kilobytes on Linux
+        max_rss: usage.ru_maxrss as u64 * 1024,
+        hostname: util::hostname(),
+        signal: status.signal(),
+    };
+    let join = |h: Option<thread::JoinHandle<Vec<u8>>>| {
+        h.and_then(|h| h.join().ok()).unwrap_or_default()
+    };
+    Ok(ProcessOutput {
+        stdout: join(stdout),
+        stderr: join(stderr),
+        status,
+        killed,
+        accounting,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_run_streaming() {
+        let (tx, rx) = mpsc::channel();
+        let mut cmd = Command::new("printf");
+        cmd.arg("a\\nb\\nc");
+        let out = run_streaming(&mut cmd, &Limits::default(), move |line| {
+            tx.send(line.to_string()).unwrap();
+        }).unwrap();
+        assert_eq!(out.stdout, b"a\nb\nc");
+        let lines: Vec<String> = rx.iter().collect();
+        assert_eq!(lines, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_run_with_limits() {
+        let limits = Limits::default();
+        let out = run_with_limits(Command::new("echo").arg("hello"), &limits).unwrap();
+        assert_eq!(out.stdout, b"hello\n");
+        assert_eq!(out.status.code(), Some(0));
+        assert_eq!(out.killed, None);
+
+        let limits = Limits {
+            timeout: Some(0.2),
+            ..Limits::default()
+        };
+        let start = Instant::now();
+        let out = run_with_limits(Command::new("sleep").arg("10"), &limits).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(out.killed.unwrap().contains("timeout"));
+        assert_eq!(out.status.code(), None);
+
+        let limits = Limits {
+            cpu_time: Some(1),
+            timeout: Some(20.),
+            ..Limits::default()
+        };
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "while true; do :; done"]);
+        let out = run_with_limits(&mut cmd, &limits).unwrap();
+        assert_eq!(out.killed, Some("Cpu time limit exceeded".to_string()));
+        assert_eq!(out.accounting.signal, Some(libc::SIGXCPU));
+        assert!(out.accounting.user_time + out.accounting.system_time > 0.5);
+    }
+
+    #[test]
+    fn test_accounting() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "sleep 0.2; i=0; while [ $i -lt 20000 ]; do i=$((i+1)); done"]);
+        let out = run_with_limits(&mut cmd, &Limits::default()).unwrap();
+        let a = out.accounting;
+        assert!(a.wall_time() >= 0.2);
+        assert!(a.start > 1e9);
+        assert!(a.user_time > 0.);
+        assert!(a.max_rss > 0);
+        assert_eq!(a.signal, None);
+        assert_eq!(a.hostname, util::hostname());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_nice_and_cpu() {
+        let cpus = available_cpus();
+        assert!(!cpus.is_empty());
+        let cpu = *cpus.last().unwrap();
+        let limits = Limits {
+            nice: Some(5),
+            cpu: Some(cpu),
+            ..Limits::default()
+        };
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "nice; grep Cpus_allowed_list /proc/self/status"]);
+        let out = run_with_limits(&mut cmd, &limits).unwrap();
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        let lines: Vec<&str> = stdout.lines().collect();
+        // a process may only lower its priority, so compare to our own
+        let own = unsafe { libc::getpriority(libc::PRIO_PROCESS as _, 0) };
+        assert_eq!(lines[0], (own + 5).min(19).to_string());
+        assert_eq!(lines[1], format!("Cpus_allowed_list:\t{}", cpu));
+    }
+}
@@ -0,0 +1,247 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::result::Result as StdResult;
+use std::str::FromStr;
+use errors::*;
+use template;
+use util::shell_quote;
+
+/// Batch systems for which job array scripts can be written.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Scheduler {
+    Slurm,
+    Pbs,
+    Sge,
+}
+
+impl FromStr for Scheduler {
+    type Err = String;
+    fn from_str(s: &str) -> StdResult<Self, String> {
+        match s.to_lowercase().as_str() {
+            "slurm" => Ok(Scheduler::Slurm),
+            "pbs" => Ok(Scheduler::Pbs),
+            "sge" => Ok(Scheduler::Sge),
+            _ => Err(format!("Unknown scheduler {:?}, expected slurm, pbs or sge", s)),
+        }
+    }
+}
+
+impl fmt::Display for Scheduler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Scheduler::Slurm => "slurm",
+            Scheduler::Pbs => "pbs",
+            Scheduler::Sge => "sge",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Scheduler {
+    fn header(self, name: &str, ntasks: usize, log: &Path) -> String {
+        let log = log.to_string_lossy();
+        match self {
+            Scheduler::Slurm => format!(
+                "#SBATCH --job-name={}\n#SBATCH --array=0-{}\n#SBATCH --output={}_%a.log\n",
+                name,
+                ntasks - 1,
+                log
+            ),
+            Scheduler::Pbs => format!(
+                "#PBS -N {}\n#PBS -J 0-{}\n#PBS -j oe\n#PBS -o {}.log\n",
+                name,
+                ntasks - 1,
+                log
+            ),
+            Scheduler::Sge => format!(
+                "#$ -N {}\n#$ -t 1-{}\n#$ -j y\n#$ -o {}_$TASK_ID.log\n",
+                name, ntasks, log
+            ),
+        }
+    }
+
+    /// Shell expression for the zero based index of the array task.
+    fn task_index(self) -> &'static str {
+        match self {
+            Scheduler::Slurm => "$SLURM_ARRAY_TASK_ID",
+            Scheduler::Pbs => "${PBS_ARRAY_INDEX:-$PBS_ARRAYID}",
+            Scheduler::Sge => "$((SGE_TASK_ID - 1))",
+        }
+    }
+
+    pub fn default_submit_cmd(self) -> &'static str {
+        match self {
+            Scheduler::Slurm => "sbatch --parsable {script}",
+            Scheduler::Pbs => "qsub {script}",
+            Scheduler::Sge => "qsub -terse {script}",
+        }
+    }
+
+    pub fn default_poll_cmd(self) -> &'static str {
+        match self {
+            Scheduler::Slurm => "squeue --noheader --array --jobs {job} --format='%i %T'",
+            Scheduler::Pbs => "qstat -t {job}",
+            Scheduler::Sge => "qstat -j {job}",
+        }
+    }
+
+    /// Job id from the output of the submit command.
+    pub fn parse_job_id(self, stdout: &str) -> Result<String> {
+        let line = stdout
+            .lines()
+            .map(str::trim)
+            .rev()
+            .find(|l| !l.is_empty())
+            .ok_or("Submit command did not print a job id")?;
+        // sbatch --parsable prints "id;cluster", qsub -terse "id.range" for arrays
+        let id = match self {
+            Scheduler::Slurm => line.split(';').next(),
+            Scheduler::Pbs => line.split_whitespace().last(),
+            Scheduler::Sge => line.split('.').next(),
+        };
+        match id {
+            Some(id) if !id.is_empty() => Ok(id.to_string()),
+            _ => bail!("Cannot parse job id from {:?}", line),
+        }
+    }
+}
+
+/// A job array running the files of a split simulation, one per task.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub scheduler: Scheduler,
+    pub name: String,
+    pub script: PathBuf,
+    pub inputs: Vec<PathBuf>,
+    pub outputs: Vec<PathBuf>,
+    pub submit_cmd: String,
+    pub poll_cmd: String,
+    #[serde(default)]
+    pub job_id: Option<String>,
+}
+
+impl Job {
+    /// Job script that runs task i as `hen run <input_i> -o <output_i>`.
+    ///
+    /// `input` and `output` contain a `{chunk}` placeholder.
+    pub fn script_content(&self, hen: &Path, input: &Path, output: &Path) -> String {
+        let log = self.script.with_extension("");
+        let mut ret = String::from("#!/bin/bash\n");
+        ret.push_str(&self.scheduler.header(&self.name, self.inputs.len(), &log));
+        ret.push_str("set -e\n");
+        ret.push_str(&format!("CHUNK={}\n", self.scheduler.task_index()));
+        ret.push_str(&format!(
+            "exec {} run {} -o {} --chunk \"$CHUNK\" --no-progress\n",
+            shell_quote(&hen.to_string_lossy()),
+            shell_quote(&input.to_string_lossy()),
+            shell_quote(&output.to_string_lossy())
+        ));
+        ret
+    }
+
+    fn expand(&self, cmd: &str) -> Result<String> {
+        let values = [
+            ("script", shell_quote(&self.script.to_string_lossy())),
+            ("name", self.name.clone()),
+            ("job", self.job_id.clone().unwrap_or_default()),
+        ];
+        template::expand(cmd, &values)
+    }
+
+    fn shell(&self, cmd: &str) -> Result<String> {
+        let cmd = self.expand(cmd)?;
+        let out = Command::new("sh")
+            .arg("-c")
+            .arg(&cmd)
+            .output()
+            .chain_err(|| format!("Cannot run {:?}", cmd))?;
+        if !out.status.success() {
+            bail!(
+                "{:?} failed with {}:\n{}",
+                cmd,
+                out.status,
+                String::from_utf8_lossy(&out.stderr)
+            );
+        }
+        Ok(String::from_utf8_lossy(&out.stdout).to_string())
+    }
+
+    /// Submit the job script and remember the job id.
+    pub fn submit(&mut self) -> Result<String> {
+        let stdout = self.shell(&self.submit_cmd.clone())?;
+        let id = self.scheduler.parse_job_id(&stdout)?;
+        self.job_id = Some(id.clone());
+        Ok(id)
+    }
+
+    /// What the scheduler says about the job.
+    pub fn poll(&self) -> Result<String> {
+        if self.job_id.is_none() {
+            bail!("Job {:?} was not submitted yet", self.name);
+        }
+        self.shell(&self.poll_cmd)
+    }
+
+    /// Outputs that were not written yet.
+    pub fn missing_outputs(&self) -> Vec<&PathBuf> {
+        self.outputs.iter().filter(|p| !p.exists()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(scheduler: Scheduler) -> Job {
+        Job {
+            scheduler,
+            name: "sim".to_string(),
+            script: PathBuf::from("/work/sim.slurm.sh"),
+            inputs: vec![PathBuf::from("/work/sim_0.heninp"); 4],
+            outputs: vec![PathBuf::from("/work/sim_0.henout"); 4],
+            submit_cmd: "echo '42;cluster'".to_string(),
+            poll_cmd: "echo {job} RUNNING".to_string(),
+            job_id: None,
+        }
+    }
+
+    #[test]
+    fn test_job_script() {
+        let j = job(Scheduler::Slurm);
+        let s = j.script_content(
+            Path::new("/bin/hen"),
+            Path::new("/work/sim_{chunk}.heninp"),
+            Path::new("/work/it's_{chunk}.henout"),
+        );
+        assert!(s.starts_with("#!/bin/bash\n#SBATCH --job-name=sim\n#SBATCH --array=0-3\n"));
+        assert!(s.contains("CHUNK=$SLURM_ARRAY_TASK_ID\n"));
+        assert!(s.contains(
+            "exec '/bin/hen' run '/work/sim_{chunk}.heninp' -o '/work/it'\\''s_{chunk}.henout' --chunk \"$CHUNK\""
+        ));
+        let s = job(Scheduler::Sge).script_content(Path::new("hen"), Path::new("a"), Path::new("b"));
+        assert!(s.contains("#$ -t 1-4\n"));
+        assert!(s.contains("CHUNK=$((SGE_TASK_ID - 1))\n"));
+    }
+
+    #[test]
+    fn test_parse_job_id() {
+        assert_eq!(Scheduler::Slurm.parse_job_id("42;cluster\n").unwrap(), "42");
+        assert_eq!(Scheduler::Pbs.parse_job_id("42[].server\n").unwrap(), "42[].server");
+        assert_eq!(Scheduler::Sge.parse_job_id("42.1-4:1\n").unwrap(), "42");
+        assert!(Scheduler::Slurm.parse_job_id("\n").is_err());
+        assert_eq!("SLURM".parse::<Scheduler>().unwrap(), Scheduler::Slurm);
+        assert!("lsf".parse::<Scheduler>().is_err());
+    }
+
+    #[test]
+    fn test_submit_poll() {
+        let mut j = job(Scheduler::Slurm);
+        assert!(j.poll().is_err());
+        assert_eq!(j.submit().unwrap(), "42");
+        assert_eq!(j.poll().unwrap(), "42 RUNNING\n");
+        assert_eq!(j.missing_outputs().len(), 4);
+        j.submit_cmd = "false".to_string();
+        assert!(j.submit().is_err());
+    }
+}
@@ -2,23 +2,51 @@ use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::io::BufReader;
 use std::io::{Read, Write};
-use std::process::{Command, Output};
 use std::fs;
-use rayon::prelude::*;
+use std::cmp::Reverse;
+use num_cpus;
 use tokenizer::TokenStream;
 use sha3;
 use sha3::Digest;
 use std;
-use uncertain::Uf64;
+use uncertain::{Correlated, Uf64};
 use output_parser;
 use std::fmt;
 use errors::*;
-use util;
+use util::{self, Slots};
 use std::result::Result as StdResult;
+use std::str::FromStr;
 use itertools::Itertools;
 use omittable::Omittable;
+use dose3d::Dose3d;
+use phsp;
+use runner::{self, Accounting, Limits, ProcessOutput};
+use output_parser::{OutputParser, Progress};
+use progress::{ProgressMode, ProgressMonitor};
+use std::sync::Arc;
+use environment::EgsEnv;
+use template;
+use checkpoint::Checkpoints;
+use cache::Cache;
+use provenance::Provenance;
+use consistency::{Consistency, MAX_REJECTED_FRACTION};
+use expression::Expr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub type Seed = (usize, usize); // is this correct integer type?
+pub type ParticleRange = (u64, u64); // zero based, half open
+
+/// Largest first and second seed component ranmar accepts, both start at 1.
+/// EGSnrc silently replaces seeds outside this range by its defaults.
+pub const MAX_IXX: usize = 31328;
+pub const MAX_JXX: usize = 30081;
+
+/// Which part of a parallel simulation a single run is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chunk {
+    pub index: usize,
+    pub seed: Seed,
+}
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SingSimInput {
@@ -27,6 +55,13 @@ pub struct SingSimInput {
     pub pegsfile: String,
     pub checksum: String,
     pub filename: String,
+    /// The EGSnrc installation the application runs in.
+    #[serde(default)]
+    pub env: EgsEnv,
+    /// Arguments passed to the application after `-i` and `-p`.
+    /// They may contain placeholders like `{chunk}`, see `template`.
+    #[serde(default)]
+    pub extra_args: Vec<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -35,6 +70,8 @@ pub struct SingSimInputBuilder {
     content: Option<String>,
     pegsfile: Option<String>,
     filename: Option<String>,
+    env: EgsEnv,
+    extra_args: Vec<String>,
 }
 
 impl SingSimInputBuilder {
@@ -44,9 +81,21 @@ impl SingSimInputBuilder {
             content: None,
             pegsfile: None,
             filename: None,
+            env: EgsEnv::default(),
+            extra_args: Vec::new(),
         }
     }
 
+    pub fn env(mut self, env: &EgsEnv) -> Self {
+        self.env = env.clone();
+        self
+    }
+
+    pub fn extra_args(mut self, args: &[String]) -> Self {
+        self.extra_args = args.to_vec();
+        self
+    }
+
     pub fn application(mut self, app: &str) -> Self {
         self.application = Some(app.to_string());
         self
@@ -88,6 +137,8 @@ impl SingSimInputBuilder {
                 content: Some(content),
                 pegsfile: Some(pegsfile),
                 filename: Some(filename),
+                env,
+                extra_args,
             } => {
                 let sim = SingSimInput {
                     application,
@@ -95,6 +146,8 @@ impl SingSimInputBuilder {
                     pegsfile,
                     checksum,
                     filename,
+                    env,
+                    extra_args,
                 };
                 Ok(sim)
             }
@@ -108,6 +161,14 @@ pub struct ParSimInput {
     pub prototype: SingSimInput,
     pub seeds: Vec<Seed>,
     pub ncases: Vec<u64>,
+    /// Disjoint parts of the phase space file read by each chunk.
+    /// Empty if the input has no phase space source.
+    #[serde(default)]
+    pub particle_ranges: Vec<ParticleRange>,
+    /// How many failed or killed chunks may be excluded from the statistics.
+    /// If `None`, none may, and a failed chunk spoils the whole result.
+    #[serde(default)]
+    pub max_failed_chunks: Option<usize>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -116,6 +177,21 @@ pub struct SingSimFinished {
     pub stderr: String,
     pub stdout: String,
     pub exit_status: i32,
+    #[serde(default)]
+    pub dose3d: Option<String>,
+    #[serde(default)]
+    pub phsp_files: Vec<PathBuf>,
+    /// Why the application was stopped before it could finish, if it was.
+    #[serde(default)]
+    pub killed: Option<String>,
+    /// Why the application could not be run at all, if it could not.
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Name of the input and output files of the application in its directory.
+    #[serde(default)]
+    pub working_name: Option<String>,
+    #[serde(default)]
+    pub accounting: Option<Accounting>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -123,6 +199,10 @@ pub struct SingSimParsedOutput {
     pub dose: StubResult<Vec<(String, Uf64)>>,
     pub total_cpu_time: StubResult<f64>,
     pub simulation_finished: StubResult<bool>,
+    /// Histories that ran, if the application printed `last case = n`.
+    pub histories: Option<u64>,
+    /// The dose table as printed, if it could be parsed.
+    pub dose_table: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -134,12 +214,37 @@ pub struct SingSimReport {
     pub dose: Omittable<Vec<(String, Uf64)>>,
     pub total_cpu_time: Omittable<f64>,
     pub simulation_finished: Omittable<bool>,
+    #[serde(default)]
+    pub dose3d: Omittable<Dose3d>,
+    #[serde(default)]
+    pub killed: Option<String>,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub working_name: Option<String>,
+    #[serde(default)]
+    pub accounting: Option<Accounting>,
+    #[serde(default)]
+    pub histories: Option<u64>,
+    /// Hash of the dose table as printed and of the raw 3ddose file, to
+    /// tell whether a rerun reproduces the results bit for bit.
+    #[serde(default)]
+    pub result_digest: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ParSimFinished {
     pub input: ParSimInput,
     pub outputs: Vec<SingSimFinished>,
+    /// Attempts that failed and were retried with a fresh seed.
+    #[serde(default)]
+    pub failed_attempts: Vec<(usize, SingSimFinished)>,
+    /// The combine step of the application, if it ran in its native parallel mode.
+    #[serde(default)]
+    pub native_combine: Option<SingSimFinished>,
+    /// The .egsdat files of the native parallel jobs and their combination.
+    #[serde(default)]
+    pub egsdat_files: Vec<PathBuf>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -150,48 +255,483 @@ pub struct ParSimReport {
     pub total_cpu_time: Omittable<f64>,
     pub simulation_finished: Omittable<bool>,
     pub dose: Omittable<Vec<(String, Uf64)>>,
+    #[serde(default)]
+    pub dose3d: Omittable<Dose3d>,
+    /// Indices of chunks that were stopped early, and why.
+    #[serde(default)]
+    pub killed: Vec<(usize, String)>,
+    /// Attempts that failed and were retried, kept for auditing only.
+    #[serde(default)]
+    pub failed_attempts: Vec<(usize, SingSimReport)>,
+    /// Indices of chunks left out of the statistics.
+    #[serde(default)]
+    pub excluded: Vec<usize>,
+    #[serde(default)]
+    pub lost_histories: u64,
+    #[serde(default)]
+    pub accounting: Omittable<AccountingSummary>,
+    /// The combine step of the application, if it ran in its native parallel mode.
+    #[serde(default)]
+    pub native_combine: Option<SingSimReport>,
+    /// What the report was produced with.
+    #[serde(default)]
+    pub provenance: Option<Provenance>,
+    /// Histories each chunk ran, as printed by the application or else its ncase.
+    #[serde(default)]
+    pub histories: Vec<u64>,
+    /// Weight of each chunk in the combined dose, zero if it was excluded.
+    #[serde(default)]
+    pub weights: Vec<f64>,
+    /// Whether the chunks agree with each other, per scoring geometry.
+    #[serde(default)]
+    pub consistency: Vec<Consistency>,
+    /// Indices of chunks left out on request, because they disagreed with the others.
+    #[serde(default)]
+    pub rejected: Vec<usize>,
 }
 
-impl ParSimInput {
-    pub fn run(&self) -> Result<ParSimFinished> {
-        let cleanup = true;
-        self.run_with_cleanup_option(cleanup)
+/// Resources used by all chunks of a simulation, see `Accounting`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountingSummary {
+    /// Number of chunks that were measured.
+    pub nchunks: usize,
+    /// Start of the first chunk in seconds since the Unix epoch.
+    pub start: f64,
+    /// End of the last chunk in seconds since the Unix epoch.
+    pub end: f64,
+    pub user_time: f64,
+    pub system_time: f64,
+    /// Largest peak resident set size of any chunk in bytes.
+    pub max_rss: u64,
+    pub mean_wall_time: f64,
+    pub max_wall_time: f64,
+    pub hosts: Vec<String>,
+}
+
+impl AccountingSummary {
+    pub fn wall_time(&self) -> f64 {
+        self.end - self.start
+    }
+
+    /// Wall-clock time of the slowest chunk relative to the mean,
+    /// one if the load is perfectly balanced or no chunk took any time,
+    /// e.g. because all were restored from the cache.
+    pub fn imbalance(&self) -> f64 {
+        if self.mean_wall_time > 0. {
+            self.max_wall_time / self.mean_wall_time
+        } else {
+            1.
+        }
+    }
+}
+
+/// How the chunks of a simulation are run and combined.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Backend {
+    /// Each chunk is a run of its own with its own seeds, hen combines
+    /// the doses parsed from their outputs.
+    Hen,
+    /// The chunks are the jobs of the application's parallel mode and the
+    /// application combines their .egsdat files.
+    Native,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+    fn from_str(s: &str) -> StdResult<Self, String> {
+        match s.to_lowercase().as_str() {
+            "hen" => Ok(Backend::Hen),
+            "native" => Ok(Backend::Native),
+            _ => Err(format!("Unknown backend {:?}, expected hen or native", s)),
+        }
     }
+}
+
+/// How one chunk would be run, see `ParSimInput::dry_run`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DryRunChunk {
+    pub chunk: Chunk,
+    pub ncase: u64,
+    pub particle_range: Option<ParticleRange>,
+    /// The generated input of the chunk.
+    pub egsinp: PathBuf,
+    /// Where the application reads and writes its files, `None` if
+    /// EGS_HOME is not known.
+    pub working_dir: Option<PathBuf>,
+    /// The command line, with the working files named as in `egsinp`.
+    pub command: String,
+}
 
-    pub fn run_with_cleanup_option(&self, cleanup: bool) -> Result<ParSimFinished> {
+/// Options controlling how the chunks of a simulation are executed.
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    pub cleanup: bool,
+    pub limits: Limits,
+    pub progress: ProgressMode,
+    /// How often a failed chunk is rerun with a fresh seed.
+    pub retries: usize,
+    /// Where finished chunks are persisted and restored from.
+    pub checkpoints: Option<Checkpoints>,
+    /// Results of identical chunks of earlier runs.
+    pub cache: Option<Cache>,
+    /// How many chunks may run at the same time.
+    pub jobs: usize,
+    /// Whether each running chunk gets a CPU of its own.
+    pub pin_cpus: bool,
+    /// Slots shared with other simulations running at the same time.
+    /// If `None`, the chunks get `jobs` slots of their own.
+    pub slots: Option<Arc<Slots>>,
+    pub backend: Backend,
+}
+
+impl RunOptions {
+    pub fn new() -> Self {
+        RunOptions {
+            cleanup: true,
+            limits: Limits::default(),
+            progress: ProgressMode::Off,
+            retries: 0,
+            checkpoints: None,
+            cache: None,
+            jobs: num_cpus::get(),
+            pin_cpus: false,
+            slots: None,
+            backend: Backend::Hen,
+        }
+    }
+}
+
+/// A seed that is not in `used`, derived from the seed of a failed chunk
+/// by increasing its first component.
+///
+/// The second component is kept, so derived seeds cannot collide with
+/// those derived elsewhere, e.g. in the other files of a split simulation,
+/// as long as the input seeds differ in their second component.
+pub fn fresh_seed(seed: Seed, used: &[Seed]) -> Result<Seed> {
+    let (ixx, jxx) = seed;
+    match (ixx + 1..MAX_IXX + 1).map(|i| (i, jxx)).find(|s| !used.contains(s)) {
+        Some(s) => Ok(s),
+        None => bail!(
+            "No unused seed left after {:?}, the first component must not exceed {}",
+            seed,
+            MAX_IXX
+        ),
+    }
+}
+
+impl ParSimInput {
+    pub fn run_with_options(&self, options: &RunOptions) -> Result<ParSimFinished> {
+        if options.backend == Backend::Native {
+            return self.run_native(options);
+        }
         self.validate()?;
+        self.prototype.check_environment()?;
         let stream = TokenStream::parse_string(&(self.prototype.content))?;
-        let streams = stream.split(&self.seeds, &self.ncases)?;
-        let application = &self.prototype.application;
-        let pegsfile = &self.prototype.pegsfile;
-        let compute_single_output = |content: String| {
-            let sim = SingSimInputBuilder::new()
-                .application(application)
-                .content(&content)
-                .pegsfile(pegsfile)
-                .filename(&self.prototype.filename)
-                .build()
-                .unwrap();
-            if cleanup {
-                sim.run_and_cleanup()
-            } else {
-                sim.run()
+        let monitor = ProgressMonitor::new(options.progress, &self.ncases, stream.get_nbatch());
+        let ticker = ProgressMonitor::spawn(&monitor);
+        let all: Vec<usize> = (0..self.seeds.len()).collect();
+        let mut input = self.clone();
+        if let Some(ref checkpoints) = options.checkpoints {
+            // chunks that finished in a retry are restored with their seed
+            for (seed, &key) in input.seeds.iter_mut().zip(&self.seeds) {
+                if let Some(restored) = checkpoints.seed(key) {
+                    *seed = restored;
+                }
+            }
+        }
+        let result = input.run_chunks(&all, &self.seeds, options, &monitor);
+        let mut outputs = match result {
+            Ok(outputs) => outputs,
+            Err(e) => {
+                monitor.finish(ticker);
+                return Err(e);
             }
         };
-
-        let outputs: Vec<SingSimFinished> = streams
-            .par_iter()
-            .map(TokenStream::to_string)
-            .map(compute_single_output)
-            .collect();
+        let mut used = self.seeds.clone();
+        used.extend(&input.seeds);
+        let mut failed_attempts = Vec::new();
+        for _ in 0..options.retries {
+            let failed: Vec<usize> = outputs
+                .iter()
+                .enumerate()
+                .filter(|(_, o)| !o.succeeded())
+                .map(|(i, _)| i)
+                .collect();
+            if failed.is_empty() || runner::interrupted().is_some() {
+                break;
+            }
+            let mut failed = failed;
+            failed.retain(|&i| match fresh_seed(input.seeds[i], &used) {
+                Ok(seed) => {
+                    used.push(seed);
+                    input.seeds[i] = seed;
+                    true
+                }
+                Err(e) => {
+                    eprintln!("Warning: Cannot retry chunk {}: {}", i, e);
+                    false
+                }
+            });
+            if failed.is_empty() {
+                break;
+            }
+            let retried = match input.run_chunks(&failed, &self.seeds, options, &monitor) {
+                Ok(retried) => retried,
+                Err(e) => {
+                    monitor.finish(ticker);
+                    return Err(e);
+                }
+            };
+            for (i, out) in failed.into_iter().zip(retried) {
+                let attempt = std::mem::replace(&mut outputs[i], out);
+                failed_attempts.push((i, attempt));
+            }
+        }
+        monitor.finish(ticker);
 
         let ret = ParSimFinished {
-            input: self.clone(),
+            input,
             outputs,
+            failed_attempts,
+            native_combine: None,
+            egsdat_files: Vec::new(),
         };
         Ok(ret)
     }
 
+    /// Run the chunks as the jobs of the application's own parallel mode
+    /// and combine their .egsdat files with the application.
+    ///
+    /// All jobs read the same input with the histories of all chunks and
+    /// the first seed, the application derives the seeds of the jobs.
+    /// Retries, checkpoints and the cache do not apply.
+    pub fn run_native(&self, options: &RunOptions) -> Result<ParSimFinished> {
+        self.validate()?;
+        if !self.particle_ranges.is_empty() {
+            bail!("The native backend cannot split a phase space source");
+        }
+        self.prototype.check_environment()?;
+        let total: u64 = self.ncases.iter().sum();
+        let stream = TokenStream::parse_string(&(self.prototype.content))?;
+        let content = stream.split(&vec![self.seeds[0]], &vec![total])?[0].to_string();
+        let sim = self.prototype.with_content(&content)?;
+        let files = WorkingFiles::claim(sim.app_dir()?, &sim.checksum)?;
+        let ret = self.run_native_jobs(&sim, &files, options);
+        files.release();
+        // the .egsdat files are kept, see `ParSimFinished::keep_egsdat`
+        if options.cleanup {
+            let _ = fs::remove_file(files.path("egsinp"));
+            for job in 1..self.seeds.len() + 1 {
+                let _ = fs::remove_file(files.job_path(job, "egslog"));
+            }
+        }
+        ret
+    }
+
+    fn run_native_jobs(
+        &self,
+        sim: &SingSimInput,
+        files: &WorkingFiles,
+        options: &RunOptions,
+    ) -> Result<ParSimFinished> {
+        sim.write_input(files)?;
+        let njobs = self.seeds.len();
+        let args = sim.app_args(files, None)?;
+        let nbatch = TokenStream::parse_string(&sim.content)?.get_nbatch();
+        let monitor = ProgressMonitor::new(options.progress, &self.ncases, nbatch);
+        let ticker = ProgressMonitor::spawn(&monitor);
+        let own_slots;
+        let slots = match options.slots {
+            Some(ref slots) => &**slots,
+            None => {
+                own_slots = Slots::new(options.jobs);
+                &own_slots
+            }
+        };
+        let jobs: Vec<usize> = (0..njobs).collect();
+        let outputs = slots.run(&jobs, |_, &i| {
+            if let Some(sig) = runner::interrupted() {
+                let reason = format!("Not started, interrupted by {}", runner::signal_name(sig));
+                return SingSimFinished::not_started(sim.clone(), reason);
+            }
+            // EGSnrc numbers parallel jobs from one
+            let mut job_args = args.clone();
+            job_args.extend(native_job_args(i + 1, njobs));
+            let monitor = monitor.clone();
+            let out = sim.run_cmd(&job_args, &options.limits, move |p| monitor.update(i, p));
+            let mut ret = sim.finished(files, out);
+            ret.dose3d = None;
+            ret.phsp_files = Vec::new();
+            ret
+        });
+        monitor.finish(ticker);
+        let mut combine_args = args.clone();
+        combine_args.extend(native_combine_args(njobs));
+        let native_combine = if outputs.iter().any(SingSimFinished::succeeded) {
+            let out = sim.run_cmd(&combine_args, &options.limits, |_| {});
+            Some(sim.finished(files, out))
+        } else {
+            None
+        };
+        let egsdat_files = (1..njobs + 1)
+            .map(|job| files.job_path(job, "egsdat"))
+            .chain(Some(files.path("egsdat")))
+            .filter(|p| p.exists())
+            .collect();
+        Ok(ParSimFinished {
+            input: self.clone(),
+            outputs,
+            failed_attempts: Vec::new(),
+            native_combine,
+            egsdat_files,
+        })
+    }
+
+    fn chunk_streams(&self) -> Result<Vec<TokenStream>> {
+        let stream = TokenStream::parse_string(&(self.prototype.content))?;
+        let mut streams = stream.split(&self.seeds, &self.ncases)?;
+        if !self.particle_ranges.is_empty() {
+            streams = streams
+                .iter()
+                .zip(&self.particle_ranges)
+                .map(|(s, range)| s.with_particle_range(range))
+                .collect::<Result<Vec<TokenStream>>>()?;
+        }
+        Ok(streams)
+    }
+
+    /// Write the input of each chunk to `dir` and describe how it would be
+    /// run, without running anything. Instead of the unique names of a real
+    /// run, the working files of chunk i are named `<stem>_<i>`.
+    pub fn dry_run(&self, dir: &Path) -> Result<Vec<DryRunChunk>> {
+        self.validate()?;
+        fs::create_dir_all(dir).chain_err(|| cannot_create(&dir))?;
+        let streams = self.chunk_streams()?;
+        let stem = Path::new(&self.prototype.filename)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("chunk");
+        let working_dir = self.prototype.app_dir().ok();
+        streams
+            .iter()
+            .enumerate()
+            .map(|(i, stream)| {
+                let files = WorkingFiles {
+                    dir: working_dir.clone().unwrap_or_default(),
+                    name: format!("{}_{}", stem, i),
+                };
+                let egsinp = dir.join(format!("{}.egsinp", files.name));
+                fs::write(&egsinp, stream.to_string()).chain_err(|| cannot_write(&egsinp))?;
+                let chunk = Chunk {
+                    index: i,
+                    seed: self.seeds[i],
+                };
+                let args = self.prototype.app_args(&files, Some(&chunk))?;
+                Ok(DryRunChunk {
+                    chunk,
+                    ncase: self.ncases[i],
+                    particle_range: self.particle_ranges.get(i).cloned(),
+                    egsinp,
+                    working_dir: working_dir.clone(),
+                    command: self.prototype
+                        .env
+                        .command_line(&self.prototype.application, &args),
+                })
+            })
+            .collect()
+    }
+
+    /// The input of chunk `i`, as it is run.
+    pub fn chunk_input(&self, i: usize) -> Result<SingSimInput> {
+        let streams = self.chunk_streams()?;
+        let stream = streams
+            .get(i)
+            .ok_or_else(|| format!("There is no chunk {}, only {}", i, streams.len()))?;
+        self.prototype.with_content(&stream.to_string())
+    }
+
+    /// Run the chunks with the given indices in parallel. Their checkpoints
+    /// are stored under `keys`, the seeds the chunks had before any retry.
+    fn run_chunks(
+        &self,
+        indices: &[usize],
+        keys: &[Seed],
+        options: &RunOptions,
+        monitor: &Arc<ProgressMonitor>,
+    ) -> Result<Vec<SingSimFinished>> {
+        let streams = self.chunk_streams()?;
+        let compute_single_output = |i: usize, limits: &Limits| -> Result<SingSimFinished> {
+            let sim = self.prototype.with_content(&streams[i].to_string())?;
+            let seed = self.seeds[i];
+            let restored = options
+                .checkpoints
+                .as_ref()
+                .and_then(|c| c.load(&sim, keys[i]))
+                .or_else(|| options.cache.as_ref().and_then(|c| c.get(&sim, i)));
+            if let Some(ret) = restored {
+                let done = Progress {
+                    histories: None,
+                    last_batch: None,
+                    finished: true,
+                };
+                monitor.update(i, done);
+                return Ok(ret);
+            }
+            if let Some(sig) = runner::interrupted() {
+                let reason = format!("Not started, interrupted by {}", runner::signal_name(sig));
+                return Ok(SingSimFinished::not_started(sim, reason));
+            }
+            let monitor = monitor.clone();
+            let chunk = Chunk { index: i, seed };
+            let ret = sim.run_monitored(Some(&chunk), limits, move |p| {
+                monitor.update(i, p)
+            });
+            if options.cleanup {
+                ret.cleanup();
+            }
+            if let Some(ref checkpoints) = options.checkpoints {
+                if ret.succeeded() {
+                    if let Err(e) = checkpoints.save(&ret, keys[i], seed) {
+                        eprintln!("Warning: Cannot save checkpoint of chunk {}: {}", i, e);
+                    }
+                }
+            }
+            if let Some(ref cache) = options.cache {
+                if let Err(e) = cache.put(&ret, i) {
+                    eprintln!("Warning: Cannot cache chunk {}: {}", i, e);
+                }
+            }
+            Ok(ret)
+        };
+        // longest first, so that no long chunk starts when the others are done
+        let mut order: Vec<usize> = (0..indices.len()).collect();
+        order.sort_by_key(|&k| Reverse(self.ncases[indices[k]]));
+        let cpus = if options.pin_cpus {
+            runner::available_cpus()
+        } else {
+            Vec::new()
+        };
+        let own_slots;
+        let slots = match options.slots {
+            Some(ref slots) => &**slots,
+            None => {
+                own_slots = Slots::new(options.jobs);
+                &own_slots
+            }
+        };
+        let results = slots.run(&order, |slot, &k| {
+            let mut limits = options.limits.clone();
+            if !cpus.is_empty() {
+                limits.cpu = Some(cpus[slot % cpus.len()]);
+            }
+            compute_single_output(indices[k], &limits)
+        });
+        let mut results: Vec<(usize, Result<SingSimFinished>)> =
+            order.into_iter().zip(results).collect();
+        results.sort_by_key(|&(k, _)| k);
+        results.into_iter().map(|(_, r)| r).collect()
+    }
+
     pub fn validate(&self) -> Result<()> {
         let len_seeds = self.seeds.len();
         let len_ncases = self.ncases.len();
@@ -199,10 +739,35 @@ impl ParSimInput {
             bail!("Got {} seeds, but {} ncases", len_seeds, len_ncases);
         }
 
+        for &(ixx, jxx) in &self.seeds {
+            if !(1..=MAX_IXX).contains(&ixx) || !(1..=MAX_JXX).contains(&jxx) {
+                bail!(
+                    "Invalid seed ({}, {}), expected 1 <= ixx <= {} and 1 <= jxx <= {}",
+                    ixx, jxx, MAX_IXX, MAX_JXX
+                );
+            }
+        }
+
         if !util::has_unique_elements(self.seeds.clone()) {
             bail!("Duplicate seeds {:?}", self.seeds);
         }
 
+        for arg in &self.prototype.extra_args {
+            template::validate(arg)?;
+        }
+
+        let len_ranges = self.particle_ranges.len();
+        if (len_ranges != 0) & (len_ranges != len_seeds) {
+            bail!("Got {} seeds, but {} particle ranges", len_seeds, len_ranges);
+        }
+        let mut ranges = self.particle_ranges.clone();
+        ranges.sort();
+        for w in ranges.windows(2) {
+            if w[0].1 > w[1].0 {
+                bail!("Overlapping particle ranges {:?} and {:?}", w[0], w[1]);
+            }
+        }
+
         Ok(())
     }
 
@@ -227,27 +792,126 @@ impl ParSimInput {
         let prototype = inps[0].prototype.clone();
         let mut seeds = Vec::new();
         let mut ncases = Vec::new();
+        let mut particle_ranges = Vec::new();
         for inp in inps {
             ncases.extend(&inp.ncases);
             seeds.extend(&inp.seeds);
+            particle_ranges.extend(&inp.particle_ranges);
         }
+        let max_failed_chunks = inps.iter()
+            .map(|inp| inp.max_failed_chunks)
+            .try_fold(0, |acc, m| Some(acc + m?));
         let ret = ParSimInput {
             prototype,
             seeds,
             ncases,
+            particle_ranges,
+            max_failed_chunks,
         };
         ret.validate()?;
         Ok(ret)
     }
 }
 
-fn egs_home_path() -> PathBuf {
-    let mut path = PathBuf::new();
-    let segs_home = std::env::var("EGS_HOME").expect("Cannot find EGS_HOME");
-    path.push(segs_home);
-    path
+/// Arguments that make the application run job `job` of `njobs` of its
+/// parallel mode. Each job runs its share of the histories on its own,
+/// without a job control file.
+fn native_job_args(job: usize, njobs: usize) -> Vec<String> {
+    vec![
+        "-P".to_string(),
+        njobs.to_string(),
+        "-j".to_string(),
+        job.to_string(),
+        "-s".to_string(),
+    ]
+}
+
+/// Arguments that make the application combine the .egsdat files of its
+/// `njobs` parallel jobs.
+fn native_combine_args(njobs: usize) -> Vec<String> {
+    vec!["-P".to_string(), njobs.to_string(), "-c".to_string()]
+}
+
+static WORKING_NAME_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// The files `<dir>/<name>.<ext>` of one run of an application.
+///
+/// Names are unique to the host and process. A lock file claims the name,
+/// so that hen processes sharing EGS_HOME never use each other's files.
+#[derive(Debug)]
+struct WorkingFiles {
+    dir: PathBuf,
+    name: String,
+}
+
+impl WorkingFiles {
+    fn claim(dir: PathBuf, checksum: &str) -> Result<Self> {
+        let prefix: String = checksum.chars().take(16).collect();
+        let host = util::hostname();
+        let pid = std::process::id();
+        loop {
+            let n = WORKING_NAME_COUNTER.fetch_add(1, Ordering::SeqCst);
+            let ret = WorkingFiles {
+                dir: dir.clone(),
+                name: format!("{}_{}_{}_{}", prefix, host, pid, n),
+            };
+            let lock = ret.path("lock");
+            match fs::OpenOptions::new().write(true).create_new(true).open(&lock) {
+                Ok(_) => return Ok(ret),
+                Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e).chain_err(|| cannot_create(&lock)),
+            }
+        }
+    }
+
+    fn path(&self, ext: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", self.name, ext))
+    }
+
+    /// File of job `job` of a native parallel run, e.g. <name>_w1.egsdat.
+    fn job_path(&self, job: usize, ext: &str) -> PathBuf {
+        self.dir.join(format!("{}_w{}.{}", self.name, job, ext))
+    }
+
+    fn release(&self) {
+        let _ = fs::remove_file(self.path("lock"));
+    }
+
+    fn read_dose3d(&self) -> Option<String> {
+        let mut content = String::new();
+        File::open(self.path("3ddose"))
+            .and_then(|mut file| file.read_to_string(&mut content))
+            .ok()?;
+        Some(content)
+    }
+
+    /// Phase space files written by the application, e.g. <name>.egsphsp1.
+    fn phsp_paths(&self) -> Vec<PathBuf> {
+        let paths = match util::read_paths_in_dir(&self.dir) {
+            Ok(paths) => paths,
+            Err(_) => return Vec::new(),
+        };
+        paths
+            .into_iter()
+            .filter(|p| {
+                let stem = p.file_stem().and_then(|s| s.to_str());
+                let ext = p.extension().and_then(|s| s.to_str()).unwrap_or("");
+                (stem == Some(self.name.as_str())) & ext.starts_with("egsphsp")
+            })
+            .collect()
+    }
+
+    fn cleanup(&self) {
+        for ext in ["egsinp", "egsdat", "ptracks", "3ddose"].iter() {
+            let path = self.path(ext);
+            if path.exists() {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
 }
 
+
 impl SingSimInput {
     pub fn from_egsinp_path(application: &str, path: &Path, pegsfile: &str) -> Result<Self> {
         let mut file = File::open(path).chain_err(|| cannot_read(&path))?;
@@ -255,67 +919,167 @@ impl SingSimInput {
         let filename = path.file_name()
             .ok_or("Error getting file_name")?
             .to_str()
-            .unwrap();
+            .ok_or_else(|| format!("File name {:?} is not valid unicode", path))?;
         file.read_to_string(&mut content)
             .chain_err(|| cannot_read(&path))?;
-        let sim = SingSimInputBuilder::new()
+        SingSimInputBuilder::new()
             .pegsfile(pegsfile)
             .filename(filename)
             .application(application)
             .content(&content)
             .build()
-            .unwrap();
-        Ok(sim)
     }
 
-    fn run_cmd(&self) -> std::io::Result<Output> {
-        let mut file = fs::File::create(self.path_exec_with_ext("egsinp"))?;
-        file.write_all(self.content.as_bytes()).unwrap();
+    /// The same simulation with other input content.
+    pub fn with_content(&self, content: &str) -> Result<SingSimInput> {
+        SingSimInputBuilder::new()
+            .application(&self.application)
+            .content(content)
+            .pegsfile(&self.pegsfile)
+            .filename(&self.filename)
+            .env(&self.env)
+            .extra_args(&self.extra_args)
+            .build()
+    }
 
-        let ret = Command::new(self.application.clone())
-            .args(&["-i", self.checksum.as_str(), "-p", self.pegsfile.as_str()])
-            .output();
+    /// Check that the application can be started at all, before any
+    /// chunk is run.
+    pub fn check_environment(&self) -> Result<()> {
+        let app_dir = self.app_dir()?;
+        if !app_dir.is_dir() {
+            bail!(
+                "Application directory {:?} does not exist. Is EGS_HOME correct?",
+                app_dir
+            );
+        }
+        match self.env.executable {
+            Some(ref path) => if util::find_executable(&path.to_string_lossy()).is_none() {
+                bail!("Application binary {:?} is not an executable file.", path);
+            },
+            None => if util::find_executable(&self.application).is_none() {
+                bail!(
+                    "Cannot find executable {:?}. Is it compiled and in PATH?",
+                    self.application
+                );
+            },
+        }
+        Ok(())
+    }
 
-        ret
+    /// Extra arguments with their placeholders filled in.
+    fn expand_extra_args(&self, files: &WorkingFiles, chunk: Option<&Chunk>) -> Result<Vec<String>> {
+        let mut values = vec![
+            ("name", files.name.clone()),
+            ("input", files.path("egsinp").to_string_lossy().to_string()),
+            ("pegsfile", self.pegsfile.clone()),
+            ("application", self.application.clone()),
+        ];
+        if let Some(chunk) = chunk {
+            values.push(("chunk", chunk.index.to_string()));
+            values.push(("seed1", chunk.seed.0.to_string()));
+            values.push(("seed2", chunk.seed.1.to_string()));
+        }
+        self.extra_args
+            .iter()
+            .map(|arg| template::expand(arg, &values))
+            .collect()
     }
 
-    pub fn run(&self) -> SingSimFinished {
-        let out = self.run_cmd().unwrap();
-        let ret = SingSimFinished {
-            input: self.clone(),
-            stdout: String::from_utf8_lossy(&out.stdout).to_string(),
-            stderr: String::from_utf8_lossy(&out.stderr).to_string(),
-            exit_status: out.status.code().unwrap_or(-1),
-        };
-        ret
+    /// Arguments the application is run with on `files`.
+    fn app_args(&self, files: &WorkingFiles, chunk: Option<&Chunk>) -> Result<Vec<String>> {
+        let mut ret = vec![
+            "-i".to_string(),
+            files.name.clone(),
+            "-p".to_string(),
+            self.pegsfile.clone(),
+        ];
+        ret.extend(self.expand_extra_args(files, chunk)?);
+        Ok(ret)
     }
 
-    pub fn run_and_cleanup(&self) -> SingSimFinished {
-        let ret = self.run();
-        self.cleanup();
-        ret
+    fn write_input(&self, files: &WorkingFiles) -> Result<()> {
+        let path = files.path("egsinp");
+        fs::File::create(&path)
+            .chain_err(|| cannot_create(&path))?
+            .write_all(self.content.as_bytes())
+            .chain_err(|| cannot_write(&path))
     }
 
-    pub fn cleanup(&self) -> () {
-        for ext in ["egsinp", "egsdat", "ptracks"].iter() {
-            let path = self.path_exec_with_ext(&ext);
-            if path.exists() {
-                let _ = fs::remove_file(path);
+    fn run_cmd<F>(
+        &self,
+        args: &[String],
+        limits: &Limits,
+        mut on_progress: F,
+    ) -> Result<ProcessOutput>
+    where
+        F: FnMut(Progress) + Send + 'static,
+    {
+        let mut cmd = self.env.command(&self.application);
+        cmd.args(args);
+        let mut parser = OutputParser::new();
+        runner::run_streaming(&mut cmd, limits, move |line| {
+            parser.feed_line(line);
+            on_progress(parser.progress());
+        }).chain_err(|| format!("Cannot run {:?}", self.application))
+    }
+
+    #[allow(dead_code)]
+    pub fn run(&self, limits: &Limits) -> SingSimFinished {
+        self.run_monitored(None, limits, |_| {})
+    }
+
+    /// Run and report the progress of the application as its output arrives.
+    pub fn run_monitored<F>(
+        &self,
+        chunk: Option<&Chunk>,
+        limits: &Limits,
+        on_progress: F,
+    ) -> SingSimFinished
+    where
+        F: FnMut(Progress) + Send + 'static,
+    {
+        let files = match self.app_dir()
+            .and_then(|dir| WorkingFiles::claim(dir, &self.checksum))
+        {
+            Ok(files) => files,
+            Err(e) => return SingSimFinished::failed(self.clone(), &e),
+        };
+        let out = self.write_input(&files)
+            .and_then(|()| self.app_args(&files, chunk))
+            .and_then(|args| self.run_cmd(&args, limits, on_progress));
+        files.release();
+        self.finished(&files, out)
+    }
+
+    fn finished(&self, files: &WorkingFiles, out: Result<ProcessOutput>) -> SingSimFinished {
+        let out = match out {
+            Ok(out) => out,
+            Err(e) => {
+                let mut ret = SingSimFinished::failed(self.clone(), &e);
+                ret.working_name = Some(files.name.clone());
+                return ret;
             }
+        };
+        SingSimFinished {
+            input: self.clone(),
+            stdout: String::from_utf8_lossy(&out.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&out.stderr).to_string(),
+            exit_status: out.status.code().unwrap_or(-1),
+            dose3d: files.read_dose3d(),
+            phsp_files: files.phsp_paths(),
+            killed: out.killed,
+            error: None,
+            working_name: Some(files.name.clone()),
+            accounting: Some(out.accounting),
         }
     }
 
-    fn app_dir(&self) -> PathBuf {
-        let mut path = egs_home_path();
+    fn app_dir(&self) -> Result<PathBuf> {
+        let mut path = self.env
+            .egs_home()
+            .ok_or("EGS_HOME is not set. Is the EGSnrc environment loaded?")?;
         path.push(self.application.clone());
-        path
-    }
-
-    fn path_exec_with_ext(&self, ext: &str) -> PathBuf {
-        let mut path = self.app_dir();
-        path.push(&self.checksum);
-        assert!(path.set_extension(ext));
-        path
+        Ok(path)
     }
 
     pub fn split(self, ncases: Vec<u64>, seeds: Vec<Seed>) -> ParSimInput {
@@ -324,6 +1088,35 @@ impl SingSimInput {
             seeds,
             prototype,
             ncases,
+            particle_ranges: Vec::new(),
+            max_failed_chunks: None,
+        }
+    }
+
+    /// Number of particles in the phase space file of the source, if any.
+    /// Relative paths are tried from the working directory and from the
+    /// application directory. A file that cannot be read here, e.g. one only
+    /// the compute nodes see, is not partitioned.
+    fn count_phsp_particles(&self, stream: &TokenStream) -> Result<Option<u64>> {
+        let file = match stream.get_phsp_file()? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        let mut path = PathBuf::from(&file);
+        if !path.exists() && path.is_relative() {
+            if let Ok(app_dir) = self.app_dir() {
+                path = app_dir.join(&file);
+            }
+        }
+        match phsp::read_header(&path) {
+            Ok(header) => Ok(Some(header.nparticles as u64)),
+            Err(e) => {
+                eprintln!(
+                    "Warning: Cannot read phase space file {:?}, every chunk reads all of its particles: {}",
+                    path, e
+                );
+                Ok(None)
+            }
         }
     }
 
@@ -343,12 +1136,85 @@ impl SingSimInput {
         let n = seed_count.or(case_count).unwrap_or(nthreads);
         let seeds = mseeds.unwrap_or(stream.generate_seeds(n)?);
         let ncases = mncases.unwrap_or(stream.generate_ncases(n)?);
+        let nparticles = self.count_phsp_particles(&stream)?;
 
-        Ok(self.split(ncases, seeds))
+        let mut ret = self.split(ncases, seeds);
+        if let Some(nparticles) = nparticles {
+            ret.particle_ranges = partition_particles(nparticles, &ret.ncases);
+        }
+        Ok(ret)
+    }
+}
+
+/// Split `0..nparticles` into consecutive ranges proportional to `ncases`.
+pub fn partition_particles(nparticles: u64, ncases: &[u64]) -> Vec<ParticleRange> {
+    let total: u64 = ncases.iter().sum();
+    let mut ret = Vec::new();
+    let mut cumsum = 0;
+    let mut first = 0;
+    for ncase in ncases {
+        cumsum += ncase;
+        let last = if total == 0 {
+            nparticles
+        } else {
+            ((nparticles as u128) * (cumsum as u128) / (total as u128)) as u64
+        };
+        ret.push((first, last));
+        first = last;
     }
+    ret
 }
 
 impl SingSimFinished {
+    fn not_started(input: SingSimInput, reason: String) -> Self {
+        SingSimFinished {
+            input,
+            stderr: String::new(),
+            stdout: String::new(),
+            exit_status: -1,
+            dose3d: None,
+            phsp_files: Vec::new(),
+            killed: Some(reason),
+            error: None,
+            working_name: None,
+            accounting: None,
+        }
+    }
+
+    fn failed(input: SingSimInput, err: &Error) -> Self {
+        let msg: Vec<String> = err.iter().map(|e| e.to_string()).collect();
+        SingSimFinished {
+            input,
+            stderr: String::new(),
+            stdout: String::new(),
+            exit_status: -1,
+            dose3d: None,
+            phsp_files: Vec::new(),
+            killed: None,
+            error: Some(msg.join(": ")),
+            working_name: None,
+            accounting: None,
+        }
+    }
+
+    /// Remove the files the application left in its directory.
+    pub fn cleanup(&self) {
+        if let Some(ref name) = self.working_name {
+            if let Ok(dir) = self.input.app_dir() {
+                let files = WorkingFiles {
+                    dir,
+                    name: name.clone(),
+                };
+                files.cleanup();
+            }
+        }
+    }
+
+    /// Whether the application ran to the end without problems.
+    pub fn succeeded(&self) -> bool {
+        self.report().succeeded()
+    }
+
     fn parse_output(&self) -> SingSimParsedOutput {
         let mut reader = BufReader::new(self.stdout.as_bytes());
         let rout = output_parser::parse_simulation_output(&mut reader).into_stub();
@@ -358,10 +1224,29 @@ impl SingSimFinished {
                 dose: Err(err.clone()),
                 total_cpu_time: Err(err.clone()),
                 simulation_finished: Err(err.clone()),
+                histories: None,
+                dose_table: None,
             },
         }
     }
 
+    fn parse_dose3d(&self) -> Omittable<Dose3d> {
+        match self.dose3d {
+            None => Omittable::Omitted,
+            Some(ref s) => Omittable::from(Dose3d::parse_string(s)),
+        }
+    }
+
+    fn result_digest(&self, dose_table: &Option<String>) -> Option<String> {
+        let mut hasher = sha3::Sha3_256::new();
+        hasher.input(dose_table.as_ref()?.as_bytes());
+        if let Some(ref dose3d) = self.dose3d {
+            hasher.input(b"\n3ddose\n");
+            hasher.input(dose3d.as_bytes());
+        }
+        Some(format!("{:x}", hasher.result()))
+    }
+
     pub fn report(&self) -> SingSimReport {
         let out = self.parse_output();
         let exit_status = Omittable::Available(self.exit_status);
@@ -388,6 +1273,13 @@ impl SingSimFinished {
             dose,
             total_cpu_time,
             simulation_finished,
+            dose3d: self.parse_dose3d(),
+            killed: self.killed.clone(),
+            error: self.error.clone(),
+            working_name: self.working_name.clone(),
+            accounting: self.accounting.clone(),
+            histories: out.histories,
+            result_digest: self.result_digest(&out.dose_table),
         }
     }
 
@@ -409,6 +1301,13 @@ impl SingSimFinished {
             dose,
             total_cpu_time,
             simulation_finished,
+            dose3d: self.parse_dose3d(),
+            killed: self.killed.clone(),
+            error: self.error.clone(),
+            working_name: self.working_name.clone(),
+            accounting: self.accounting.clone(),
+            histories: out.histories,
+            result_digest: self.result_digest(&out.dose_table),
         }
     }
 }
@@ -428,23 +1327,104 @@ impl ParSimFinished {
         let mut single_runs: Vec<SingSimReport> =
             self.outputs.iter().map(SingSimFinished::report).collect();
         single_runs[0] = self.outputs[0].report_full();
+        let failed_attempts = self.failed_attempts
+            .iter()
+            .map(|(i, o)| (*i, o.report_full()))
+            .collect();
 
         let input = self.input.clone();
         let total_cpu_time = Omittable::Omitted;
         let simulation_finished = Omittable::Omitted;
         let dose = Omittable::Omitted;
+        let dose3d = Omittable::Omitted;
+        let killed = Vec::new();
         let ret = ParSimReport {
             input,
             single_runs,
             total_cpu_time,
             simulation_finished,
             dose,
+            dose3d,
+            killed,
+            failed_attempts,
+            excluded: Vec::new(),
+            lost_histories: 0,
+            accounting: Omittable::Omitted,
+            native_combine: self.native_combine.as_ref().map(SingSimFinished::report),
+            provenance: None,
+            histories: Vec::new(),
+            weights: Vec::new(),
+            consistency: Vec::new(),
+            rejected: Vec::new(),
         };
         let ret = ret.recalculate();
         ret
     }
 }
 
+impl ParSimFinished {
+    /// Move the .egsdat files of a native run next to `output_path`,
+    /// replacing the working name by the stem of `output_path`.
+    pub fn keep_egsdat(&self, output_path: &Path) -> Result<Vec<PathBuf>> {
+        let name = match self.native_combine.as_ref().and_then(|c| c.working_name.as_ref()) {
+            Some(name) => name,
+            None => return Ok(Vec::new()),
+        };
+        let stem = output_path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| format!("Bad output path {:?}", output_path))?;
+        let mut ret = Vec::new();
+        for path in &self.egsdat_files {
+            let filename = path.file_name().and_then(|s| s.to_str()).unwrap_or_default();
+            let target = output_path.with_file_name(filename.replacen(name.as_str(), stem, 1));
+            if fs::rename(path, &target).is_err() {
+                // e.g. on another file system
+                fs::copy(path, &target).chain_err(|| cannot_write(&target))?;
+                fs::remove_file(path).chain_err(|| cannot_remove(&path))?;
+            }
+            ret.push(target);
+        }
+        Ok(ret)
+    }
+
+    /// Concatenate the phase space files of all chunks into files next to
+    /// `output_path`, one for each scoring plane extension.
+    pub fn combine_phsp(&self, output_path: &Path, cleanup: bool) -> Result<Vec<PathBuf>> {
+        combine_phsp(&self.outputs, output_path, cleanup)
+    }
+}
+
+/// Concatenate the phase space files of `outputs`, see `ParSimFinished::combine_phsp`.
+pub fn combine_phsp<'a, I>(outputs: I, output_path: &Path, cleanup: bool) -> Result<Vec<PathBuf>>
+where
+    I: IntoIterator<Item = &'a SingSimFinished>,
+{
+    let mut by_ext: Vec<(String, Vec<PathBuf>)> = Vec::new();
+    for path in outputs.into_iter().flat_map(|o| o.phsp_files.iter()) {
+        let ext = path.extension()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| format!("Bad phase space file name {:?}", path))?
+            .to_string();
+        match by_ext.iter().position(|(e, _)| *e == ext) {
+            Some(i) => by_ext[i].1.push(path.clone()),
+            None => by_ext.push((ext, vec![path.clone()])),
+        }
+    }
+    let mut ret = Vec::new();
+    for (ext, inputs) in by_ext {
+        let out = output_path.with_extension(&ext);
+        phsp::combine(&inputs, &out)?;
+        if cleanup {
+            for p in &inputs {
+                fs::remove_file(p).chain_err(|| cannot_remove(&p))?;
+            }
+        }
+        ret.push(out);
+    }
+    Ok(ret)
+}
+
 fn compute_total_cpu_time(single_runs: &[SingSimReport]) -> Omittable<f64> {
     single_runs
         .iter()
@@ -463,11 +1443,120 @@ fn compute_simulation_finished(single_runs: &[SingSimReport]) -> Omittable<bool>
         })
 }
 
-fn compute_dose(single_runs: &[SingSimReport]) -> Omittable<Vec<(String, Uf64)>> {
-    Omittable::from(compute_dose_result(&single_runs))
+fn compute_dose(single_runs: &[SingSimReport], weights: &[f64]) -> Omittable<Vec<(String, Uf64)>> {
+    Omittable::from(compute_dose_result(single_runs, weights))
+}
+
+/// Weights proportional to the number of histories, equal if none are known.
+pub fn history_weights(histories: &[u64]) -> Vec<f64> {
+    let total: u64 = histories.iter().sum();
+    if total == 0 {
+        let n = histories.len() as f64;
+        return histories.iter().map(|_| 1. / n).collect();
+    }
+    histories
+        .iter()
+        .map(|&h| h as f64 / total as f64)
+        .collect()
+}
+
+fn compute_killed(single_runs: &[SingSimReport]) -> Vec<(usize, String)> {
+    single_runs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, o)| o.killed.clone().map(|reason| (i, reason)))
+        .collect()
 }
 
-fn compute_dose_result(reports: &[SingSimReport]) -> Result<Vec<(String, Uf64)>> {
+/// Chunks that were not killed, but did not finish properly either.
+fn compute_failed(single_runs: &[SingSimReport]) -> Vec<usize> {
+    single_runs
+        .iter()
+        .enumerate()
+        .filter(|(_, o)| o.killed.is_none() && !o.succeeded())
+        .map(|(i, _)| i)
+        .collect()
+}
+
+fn compute_dose3d(single_runs: &[SingSimReport], weights: &[f64]) -> Omittable<Dose3d> {
+    if single_runs.iter().all(|o| o.dose3d == Omittable::Omitted) {
+        return Omittable::Omitted;
+    }
+    let doses: Vec<StubResult<Dose3d>> = single_runs
+        .iter()
+        .map(|o| o.dose3d.clone().into_stub_result())
+        .collect();
+    match traverse_result(doses) {
+        Ok(doses) => Omittable::from(Dose3d::combine(&doses, weights)),
+        Err(msg) => Omittable::Fail(msg),
+    }
+}
+
+/// Summary of the chunks that were measured, all of them, not only the
+/// completed ones, since the others used resources as well.
+fn compute_accounting(single_runs: &[SingSimReport]) -> Omittable<AccountingSummary> {
+    let measured: Vec<&Accounting> = single_runs
+        .iter()
+        .filter_map(|o| o.accounting.as_ref())
+        .collect();
+    if measured.is_empty() {
+        return Omittable::Omitted;
+    }
+    let walls: Vec<f64> = measured.iter().map(|a| a.wall_time()).collect();
+    let mut hosts: Vec<String> = measured.iter().map(|a| a.hostname.clone()).collect();
+    hosts.sort();
+    hosts.dedup();
+    Omittable::Available(AccountingSummary {
+        nchunks: measured.len(),
+        start: measured.iter().map(|a| a.start).fold(f64::INFINITY, f64::min),
+        end: measured.iter().map(|a| a.end).fold(f64::NEG_INFINITY, f64::max),
+        user_time: measured.iter().map(|a| a.user_time).sum(),
+        system_time: measured.iter().map(|a| a.system_time).sum(),
+        max_rss: measured.iter().map(|a| a.max_rss).max().unwrap_or(0),
+        mean_wall_time: walls.iter().sum::<f64>() / walls.len() as f64,
+        max_wall_time: walls.iter().cloned().fold(0., f64::max),
+        hosts,
+    })
+}
+
+
+/// Compare the doses of the chunks that were not excluded with the combined `dose`.
+fn compute_consistency(
+    single_runs: &[SingSimReport],
+    excluded: &[usize],
+    dose: &Omittable<Vec<(String, Uf64)>>,
+) -> Vec<Consistency> {
+    let dose = match *dose {
+        Omittable::Available(ref dose) => dose,
+        _ => return Vec::new(),
+    };
+    let chunks: Vec<(usize, Vec<(String, Uf64)>)> = single_runs
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !excluded.contains(i))
+        .filter_map(|(i, o)| match o.dose {
+            Omittable::Available(ref d) => Some((i, d.clone())),
+            _ => None,
+        })
+        .collect();
+    if chunks.len() < 2 {
+        return Vec::new();
+    }
+    dose.iter()
+        .enumerate()
+        .map(|(k, (name, mean))| {
+            let results: Vec<(usize, Uf64)> = chunks
+                .iter()
+                .filter_map(|(i, d)| d.get(k).map(|&(_, r)| (*i, r)))
+                .collect();
+            Consistency::test(name, &results, mean.value())
+        })
+        .collect()
+}
+
+/// The weighted mean of the doses of `reports`. The chunks are independent,
+/// so the variance is the sum of the variances times the squared weights.
+fn compute_dose_result(reports: &[SingSimReport], weights: &[f64]) -> Result<Vec<(String, Uf64)>> {
     let doses1: Vec<StubResult<Vec<(String, Uf64)>>> = reports
         .iter()
         .map(|o| o.dose.clone().into_stub_result())
@@ -476,7 +1565,13 @@ fn compute_dose_result(reports: &[SingSimReport]) -> Result<Vec<(String, Uf64)>>
     if doses2.is_empty() {
         return Ok(Vec::new());
     };
-    let mut ret = doses2[0].clone();
+    if doses2.len() != weights.len() {
+        bail!("Got {} doses, but {} weights", doses2.len(), weights.len());
+    }
+    let mut ret: Vec<(String, Uf64)> = doses2[0]
+        .iter()
+        .map(|(label, dose)| (label.clone(), *dose * weights[0]))
+        .collect();
     let nruns = doses2.len();
     for i_run in 1..nruns {
         if doses2[i_run].len() != ret.len() {
@@ -487,7 +1582,7 @@ fn compute_dose_result(reports: &[SingSimReport]) -> Result<Vec<(String, Uf64)>>
                 let (ref s_inc, ref d_inc) = doses2[i_run][i_reg];
                 let (ref s_ret, ref d_ret) = ret[i_reg];
                 if *s_inc == *s_ret {
-                    *d_ret + *d_inc
+                    *d_ret + *d_inc * weights[i_run]
                 } else {
                     bail!("Simulation have inconsistent scoring regions");
                 }
@@ -495,18 +1590,6 @@ fn compute_dose_result(reports: &[SingSimReport]) -> Result<Vec<(String, Uf64)>>
             ret[i_reg].1 = d_new;
         }
     }
-    let wt = Uf64::from_value_var(1. / (nruns as f64), 0.);
-    // normalize
-    let mask_nan = |dose: Uf64| {
-        if dose.rstd().is_finite() {
-            dose
-        } else {
-            Uf64::from_value_rstd(dose.value(), 1.0)
-        }
-    };
-    ret = ret.iter()
-        .map(|&(ref label, ref dose)| (label.to_string(), mask_nan(*dose * wt)))
-        .collect();
     Ok(ret)
 }
 
@@ -518,27 +1601,241 @@ impl ParSimReport {
             dose,
             total_cpu_time,
             simulation_finished,
+            dose3d,
+            killed,
+            failed_attempts,
+            excluded,
+            lost_histories,
+            accounting,
+            native_combine,
+            provenance,
+            histories,
+            weights,
+            consistency,
+            rejected,
         } = self;
         let _ = dose;
         let _ = total_cpu_time;
         let _ = simulation_finished;
-        let dose = compute_dose(&single_runs);
-        let total_cpu_time = compute_total_cpu_time(&single_runs);
-        let simulation_finished = compute_simulation_finished(&single_runs);
+        let _ = dose3d;
+        let _ = killed;
+        let _ = excluded;
+        let _ = lost_histories;
+        let _ = accounting;
+        let _ = histories;
+        let _ = weights;
+        let _ = consistency;
+        let killed = compute_killed(&single_runs);
+        // killed chunks would spoil the statistics of the others
+        let mut excluded: Vec<usize> = killed.iter().map(|&(i, _)| i).collect();
+        let failed = compute_failed(&single_runs);
+        let nlost = failed.len() + killed.len();
+        let max_lost = input.max_failed_chunks.unwrap_or(0);
+        let too_many_failed = if nlost > max_lost {
+            Some(max_lost)
+        } else {
+            excluded.extend(&failed);
+            None
+        };
+        excluded.extend(&rejected);
+        excluded.sort();
+        excluded.dedup();
+        let lost_histories = excluded
+            .iter()
+            .filter_map(|&i| input.ncases.get(i))
+            .sum();
+        let completed: Vec<SingSimReport> = single_runs
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !excluded.contains(i))
+            .map(|(_, o)| o.clone())
+            .collect();
+        let histories: Vec<u64> = single_runs
+            .iter()
+            .enumerate()
+            .map(|(i, o)| o.histories.or_else(|| input.ncases.get(i).cloned()).unwrap_or(0))
+            .collect();
+        let completed_histories: Vec<u64> = histories
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !excluded.contains(i))
+            .map(|(_, &h)| h)
+            .collect();
+        let completed_weights = history_weights(&completed_histories);
+        let nsamples: u64 = completed_histories.iter().sum();
+        let mut weights = vec![0.; single_runs.len()];
+        let included = (0..single_runs.len()).filter(|i| !excluded.contains(i));
+        for (i, &w) in included.zip(&completed_weights) {
+            weights[i] = w;
+        }
+        let dose = match too_many_failed {
+            Some(max) => Omittable::Fail(format!(
+                "{} chunks failed or were killed, but at most {} may be excluded",
+                nlost,
+                max
+            )),
+            None if completed.is_empty() => Omittable::Fail("No chunk finished".to_string()),
+            None => compute_dose(&completed, &completed_weights).map(|doses| {
+                doses
+                    .into_iter()
+                    .map(|(name, d)| (name, d.with_samples(nsamples)))
+                    .collect()
+            }),
+        };
+        let total_cpu_time = compute_total_cpu_time(&completed);
+        let simulation_finished = if excluded.is_empty() {
+            compute_simulation_finished(&completed)
+        } else {
+            Omittable::Available(false)
+        };
+        let dose3d = compute_dose3d(&completed, &completed_weights);
+        let consistency = compute_consistency(&single_runs, &excluded, &dose);
+        let accounting = compute_accounting(&single_runs);
         ParSimReport {
             input,
             single_runs,
             dose,
             total_cpu_time,
             simulation_finished,
+            dose3d,
+            killed,
+            failed_attempts,
+            excluded,
+            lost_histories,
+            accounting,
+            native_combine,
+            provenance,
+            histories,
+            weights,
+            consistency,
+            rejected,
+        }
+    }
+
+    /// Chunks that are outliers in any geometry.
+    pub fn flagged_chunks(&self) -> Vec<usize> {
+        let mut ret: Vec<usize> = self.consistency
+            .iter()
+            .flat_map(|c| c.outliers.iter().map(|&(i, _)| i))
+            .collect();
+        ret.sort();
+        ret.dedup();
+        ret
+    }
+
+    /// Leave the flagged chunks out of the statistics. Fails if that would
+    /// leave fewer than two chunks, or if too many are flagged to blame the
+    /// chunks rather than their uncertainties.
+    pub fn reject_flagged(mut self) -> Result<Self> {
+        let flagged = self.flagged_chunks();
+        let ntested = self.single_runs
+            .iter()
+            .enumerate()
+            .filter(|&(i, r)| !self.excluded.contains(&i) && r.dose.is_available())
+            .count();
+        if ntested < flagged.len() + 2 {
+            bail!(
+                "Only {} of {} chunks would remain, at least 2 are needed",
+                ntested - flagged.len(),
+                ntested
+            );
+        }
+        if flagged.len() as f64 > MAX_REJECTED_FRACTION * ntested as f64 {
+            bail!(
+                "{} of {} chunks are outliers, more likely their uncertainties are underestimated",
+                flagged.len(),
+                ntested
+            );
+        }
+        self.rejected.extend(flagged);
+        Ok(self.recalculate())
+    }
+
+    /// The combined dose in `geometry`.
+    pub fn dose_of(&self, geometry: &str) -> Result<Uf64> {
+        let dose = self.dose.clone().into_stub_result()?;
+        dose.iter()
+            .find(|(name, _)| name == geometry)
+            .map(|&(_, d)| d)
+            .ok_or_else(|| format!("There is no geometry {:?}", geometry).into())
+    }
+
+    /// The dose in `geometry` with its uncertainty estimated from the spread
+    /// of the chunks, so that it keeps its correlation with other geometries.
+    pub fn correlated_dose(&self, geometry: &str) -> Result<Correlated> {
+        let n = self.single_runs.len();
+        let included: Vec<usize> = (0..n).filter(|i| !self.excluded.contains(i)).collect();
+        let mut results = Vec::new();
+        let mut weights = Vec::new();
+        for &i in &included {
+            let dose = self.single_runs[i].dose.clone().into_stub_result()?;
+            let d = dose.iter()
+                .find(|(name, _)| name == geometry)
+                .map(|&(_, d)| d)
+                .ok_or_else(|| format!("Chunk {} has no geometry {:?}", i, geometry))?;
+            results.push(d);
+            // reports written before the weights were recorded used equal ones
+            weights.push(if self.weights.len() == n {
+                self.weights[i]
+            } else {
+                1. / included.len() as f64
+            });
         }
+        if results.is_empty() {
+            bail!("No chunk finished");
+        }
+        Correlated::from_chunks(&results, &weights)
+    }
+
+    /// The ratio of the doses in two geometries, taking into account that
+    /// they were scored with the same random numbers.
+    pub fn to_string_ratio(&self, numerator: &str, denominator: &str) -> Result<String> {
+        let num = self.correlated_dose(numerator)?;
+        let den = self.correlated_dose(denominator)?;
+        let correlation = num.correlation(&den)?;
+        let ratio = (num / den)?;
+        let independent = self.dose_of(numerator)? / self.dose_of(denominator)?;
+        Ok(format!(
+            "{} / {}: {}\nIgnoring the correlation of {:.3}: {}",
+            numerator, denominator, ratio, correlation, independent
+        ))
+    }
+
+    /// The value of an expression of the doses in geometries, like
+    /// `sqrt(a / b)`, once with and once without their correlations.
+    pub fn to_string_expression(&self, expression: &str) -> Result<String> {
+        let expr = Expr::parse(expression)?;
+        let correlated = expr.eval(&|name: &str| self.correlated_dose(name))?;
+        let independent = expr.eval(&|name: &str| self.dose_of(name))?;
+        Ok(format!(
+            "{}: {}\nIgnoring the correlations: {}",
+            expression, correlated, independent
+        ))
+    }
+
+    /// Record what this report was produced with on this machine.
+    pub fn record_provenance(&mut self) {
+        let log = match self.single_runs.first().map(|r| &r.stdout) {
+            Some(Omittable::Available(ref stdout)) => stdout.as_str(),
+            _ => "",
+        };
+        self.provenance = Some(Provenance::collect(&self.input.prototype, log));
     }
 
     pub fn combine(sims: &[ParSimReport]) -> Result<ParSimReport> {
         let mut inputs = Vec::new();
         let mut single_runs = Vec::new();
+        let mut failed_attempts = Vec::new();
+        let mut rejected = Vec::new();
         for sim in sims {
             inputs.push(sim.input.clone());
+            let offset = single_runs.len();
+            failed_attempts.extend(
+                sim.failed_attempts
+                    .iter()
+                    .map(|(i, o)| (i + offset, o.clone())),
+            );
+            rejected.extend(sim.rejected.iter().map(|i| i + offset));
             single_runs.extend(sim.single_runs.clone());
         }
         let input = ParSimInput::combine(&inputs)?;
@@ -546,12 +1843,28 @@ impl ParSimReport {
         let dose = Omittable::Omitted;
         let total_cpu_time = Omittable::Omitted;
         let simulation_finished = Omittable::Omitted;
+        let dose3d = Omittable::Omitted;
+        let killed = Vec::new();
         let ret = ParSimReport {
             input,
             single_runs,
             dose,
             total_cpu_time,
             simulation_finished,
+            dose3d,
+            killed,
+            failed_attempts,
+            excluded: Vec::new(),
+            lost_histories: 0,
+            accounting: Omittable::Omitted,
+            // the application can only combine the jobs of one run
+            native_combine: None,
+            // the reports may come from different machines
+            provenance: None,
+            histories: Vec::new(),
+            weights: Vec::new(),
+            consistency: Vec::new(),
+            rejected,
         };
         let ret = ret.recalculate();
         Ok(ret)
@@ -574,6 +1887,12 @@ impl ParSimReport {
         ret.push_str(&Self::string_section("Output"));
         ret.push_str("\n");
         ret.push_str(&self.to_string_output());
+        if let Some(ref provenance) = self.provenance {
+            ret.push('\n');
+            ret.push_str(&Self::string_section("Provenance"));
+            ret.push('\n');
+            ret.push_str(&provenance.to_string());
+        }
         ret
     }
 
@@ -616,11 +1935,212 @@ impl ParSimReport {
         ret.push_str(&"\n");
         ret.push_str(&self.string_dose());
         ret.push_str(&"\n");
+        ret.push_str(&self.string_native());
         ret.push_str(&self.string_efficienty());
         ret.push_str(&"\n");
+        ret.push_str(&self.string_accounting());
+        ret.push_str(&self.string_dose3d());
+        ret.push('\n');
+        ret.push_str(&self.string_killed());
+        ret.push_str(&self.string_excluded());
+        ret.push_str(&self.string_consistency());
+        ret
+    }
+
+    fn string_excluded(&self) -> String {
+        let mut ret = String::new();
+        if !self.failed_attempts.is_empty() {
+            ret.push_str(&format!(
+                "Failed attempts retried: {}\n",
+                self.failed_attempts.len()
+            ));
+        }
+        if !self.excluded.is_empty() {
+            let total: u64 = self.input.ncases.iter().sum();
+            ret.push_str(&format!(
+                "Excluded chunks: {:?}, lost histories: {} of {}\n",
+                self.excluded, self.lost_histories, total
+            ));
+        }
+        if !self.rejected.is_empty() {
+            ret.push_str(&format!("Rejected as outliers: {:?}\n", self.rejected));
+        }
+        ret
+    }
+
+    /// Warnings for the geometries in which the chunks disagree.
+    fn string_consistency(&self) -> String {
+        let mut ret = String::new();
+        for c in self.consistency.iter().filter(|c| !c.is_consistent()) {
+            ret.push_str(&format!(
+                "Warning: The chunks disagree in {}: chi-square {:.1} for {} degrees of freedom, p = {:.1e}\n",
+                c.geometry, c.chi2, c.dof, c.p_value
+            ));
+            for &(i, z) in &c.outliers {
+                ret.push_str(&format!("    chunk {} deviates by {:.1} standard deviations\n", i, z));
+            }
+        }
+        ret
+    }
+
+    /// Fail if more chunks failed or were killed than the input allows to
+    /// exclude.
+    pub fn check_failures(&self) -> Result<()> {
+        let nlost = compute_failed(&self.single_runs).len() + compute_killed(&self.single_runs).len();
+        let max = self.input.max_failed_chunks.unwrap_or(0);
+        if nlost > max {
+            bail!(
+                "{} chunks failed or were killed, but at most {} may be excluded",
+                nlost,
+                max
+            );
+        }
+        Ok(())
+    }
+
+    /// The dose of the application's combine step next to hen's.
+    fn string_native(&self) -> String {
+        let native = match self.native_combine {
+            Some(ref native) => native,
+            None => return String::new(),
+        };
+        let mut ret = "Combined by the application:\n".to_string();
+        match native.dose {
+            Omittable::Available(ref v) => for &(ref name, score) in v {
+                let hen = match self.dose {
+                    Omittable::Available(ref doses) => doses.iter().find(|(n, _)| n == name),
+                    _ => None,
+                };
+                let diff = match hen {
+                    Some(&(_, h)) => format!(
+                        ", hen differs by {:.3}%",
+                        (h.value() / score.value() - 1.) * 100.
+                    ),
+                    None => String::new(),
+                };
+                ret.push_str(&format!(
+                    "{}: {} +- {}%{}\n",
+                    name,
+                    score.value(),
+                    score.rstd() * 100.,
+                    diff
+                ));
+            },
+            Omittable::Omitted => {}
+            Omittable::Fail(ref s) => ret.push_str(&format!("{}\n", s)),
+        }
+        if let Some(ref error) = native.error {
+            ret.push_str(&format!("Combine step failed: {}\n", error));
+        }
+        ret
+    }
+
+    fn string_accounting(&self) -> String {
+        match self.accounting {
+            Omittable::Available(ref a) => format!(
+                "Wall-clock time: {:.1} s on {} host(s), cpu time measured by hen: {:.1} s, \
+                 peak memory: {:.1} MB, slowest chunk: {:.2} x mean\n",
+                a.wall_time(),
+                a.hosts.len(),
+                a.user_time + a.system_time,
+                a.max_rss as f64 / 1e6,
+                a.imbalance()
+            ),
+            _ => String::new(),
+        }
+    }
+
+    /// When each chunk ran, to spot stragglers and load imbalance.
+    pub fn to_string_timeline(&self) -> String {
+        const WIDTH: usize = 40;
+        let summary = match self.accounting {
+            Omittable::Available(ref a) => a,
+            _ => return "The report contains no timing of the chunks.\n".to_string(),
+        };
+        let span = summary.wall_time().max(1e-9);
+        let mut ret = format!(
+            "{:>5} {:<12} {:>9} {:>9} {:>9} {:>9} {:>8}  {}\n",
+            "Chunk", "Host", "Start/s", "Wall/s", "User/s", "Sys/s", "RSS/MB", "Timeline"
+        );
+        for (i, run) in self.single_runs.iter().enumerate() {
+            let a = match run.accounting {
+                Some(ref a) => a,
+                None => {
+                    ret.push_str(&format!("{:>5} not measured\n", i));
+                    continue;
+                }
+            };
+            let col = |t: f64| ((t - summary.start) / span * WIDTH as f64).round() as usize;
+            let from = col(a.start).min(WIDTH - 1);
+            let to = col(a.end).min(WIDTH).max(from + 1);
+            let bar = format!(
+                "{}{}{}",
+                " ".repeat(from),
+                "#".repeat(to - from),
+                " ".repeat(WIDTH - to)
+            );
+            // chunks that took much longer than the others hold up the run
+            let straggler = if a.wall_time() > 1.5 * summary.mean_wall_time {
+                " straggler"
+            } else {
+                ""
+            };
+            let signal = match a.signal {
+                Some(sig) => format!(" {}", runner::signal_name(sig)),
+                None => String::new(),
+            };
+            ret.push_str(&format!(
+                "{:>5} {:<12} {:>9.1} {:>9.1} {:>9.1} {:>9.1} {:>8.1}  |{}|{}{}\n",
+                i,
+                a.hostname,
+                a.start - summary.start,
+                a.wall_time(),
+                a.user_time,
+                a.system_time,
+                a.max_rss as f64 / 1e6,
+                bar,
+                straggler,
+                signal
+            ));
+        }
+        ret.push_str(&format!(
+            "Wall-clock time: {:.1} s, chunks: mean {:.1} s, max {:.1} s, imbalance {:.2}\n",
+            summary.wall_time(),
+            summary.mean_wall_time,
+            summary.max_wall_time,
+            summary.imbalance()
+        ));
         ret
     }
 
+    fn string_killed(&self) -> String {
+        let mut ret = String::new();
+        for &(i, ref reason) in &self.killed {
+            ret.push_str(&format!("Chunk {} killed: {}\n", i, reason));
+        }
+        for (i, run) in self.single_runs.iter().enumerate() {
+            if let Some(ref error) = run.error {
+                ret.push_str(&format!("Chunk {} failed: {}\n", i, error));
+            }
+        }
+        ret
+    }
+
+    fn string_dose3d(&self) -> String {
+        let shape = self.dose3d
+            .clone()
+            .map(|d| format!("{} x {} x {} voxels", d.nx(), d.ny(), d.nz()));
+        Self::string_key_omittable("3ddose", &shape)
+    }
+
+    /// Write the combined .3ddose next to the .henout at `path`, if there is one.
+    pub fn save_dose3d(&self, path: &Path) -> Result<()> {
+        match self.dose3d {
+            Omittable::Available(ref d) => d.save(&path.with_extension("3ddose")),
+            _ => Ok(()),
+        }
+    }
+
     fn string_dose(&self) -> String {
         let mut ret = String::new();
         match self.dose {
@@ -666,9 +2186,23 @@ impl fmt::Display for ParSimReport {
     }
 }
 
+impl SingSimReport {
+    pub fn succeeded(&self) -> bool {
+        self.killed.is_none()
+            && (self.exit_status == Omittable::Available(0))
+            && (self.simulation_finished == Omittable::Available(true))
+    }
+}
+
 impl fmt::Display for SingSimReport {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "{}", self.input)?;
+        if let Some(ref error) = self.error {
+            writeln!(f, "Error: {}", error)?;
+        }
+        if let Some(ref name) = self.working_name {
+            writeln!(f, "Working name: {}", name)?;
+        }
         writeln!(f, "{}", self.stdout)?;
         writeln!(f, "{}", self.stderr)
     }
@@ -680,6 +2214,9 @@ impl fmt::Display for SingSimInput {
         writeln!(f, "Filename: {}", self.filename)?;
         writeln!(f, "Application: {}", self.application)?;
         writeln!(f, "Pegsfile: {}", self.pegsfile)?;
+        if !self.extra_args.is_empty() {
+            writeln!(f, "Extra arguments: {}", self.extra_args.join(" "))?;
+        }
         write!(f, "Checksum: {}", self.checksum)
     }
 }
@@ -689,6 +2226,194 @@ mod tests {
     use super::*;
     use util::{asset_path, load};
     use uncertain::Uf64;
+    use expression::Value;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_partition_particles() {
+        assert_eq!(
+            partition_particles(1000, &[100, 100, 200]),
+            vec![(0, 250), (250, 500), (500, 1000)]
+        );
+        assert_eq!(partition_particles(10, &[1, 1, 1]), vec![(0, 3), (3, 6), (6, 10)]);
+        let ranges = partition_particles(12345, &[173, 200, 1]);
+        assert_eq!(ranges.first().unwrap().0, 0);
+        assert_eq!(ranges.last().unwrap().1, 12345);
+        assert!(ranges.windows(2).all(|w| w[0].1 == w[1].0));
+    }
+
+    #[test]
+    fn test_split_unreadable_phsp() {
+        let input = SingSimInput::from_egsinp_path(
+            "egs_chamber",
+            &asset_path().join("three_calc_geos.egsinp"),
+            "521icru",
+        ).unwrap();
+        let content = format!(
+            "{}
+:start source definition:
+    :start source:
+        library = egs_phsp_source
+        name = the_source
+        phase space file = /only/on/the/nodes.egsphsp1
+    :stop source:
+    simulation source = the_source
+:stop source definition:
+",
+            input.content
+        );
+        let par = input.with_content(&content).unwrap().splitn(3).unwrap();
+        assert_eq!(par.ncases.len(), 3);
+        assert!(par.particle_ranges.is_empty());
+    }
+
+    #[test]
+    fn test_report_killed_chunk() {
+        let path = asset_path().join("fin_par_sim.json");
+        let mut raw: ParSimFinished = load(&path).unwrap();
+        raw.outputs[1].killed = Some("Wall-clock timeout of 1 s exceeded".to_string());
+        raw.outputs[1].stdout = "killed before the dose table".to_string();
+        let report = raw.report();
+        assert_eq!(
+            report.killed,
+            vec![(1, "Wall-clock timeout of 1 s exceeded".to_string())]
+        );
+        assert_eq!(report.simulation_finished, Omittable::Available(false));
+        assert!(report.to_string_output().contains("Chunk 1 killed"));
+        // killed chunks count against the same allowance as failed ones
+        assert!(!report.dose.is_available());
+        assert!(report.check_failures().is_err());
+
+        raw.input.max_failed_chunks = Some(1);
+        let report = raw.report();
+        assert_eq!(report.excluded, vec![1]);
+        assert!(report.dose.is_available());
+        assert!(report.check_failures().is_ok());
+    }
+
+    #[test]
+    fn test_run_missing_application() {
+        let sim = SingSimInputBuilder::new()
+            .application("hen_no_such_application")
+            .content("")
+            .pegsfile("521icru")
+            .filename("empty.egsinp")
+            .build()
+            .unwrap();
+        assert!(sim.check_environment().is_err());
+        let out = sim.run(&Limits::default());
+        assert!(out.error.is_some());
+        assert_eq!(out.exit_status, -1);
+        let report = out.report();
+        assert!(!report.succeeded());
+        assert!(report.to_string().contains("Error: "));
+    }
+
+    #[test]
+    fn test_working_files() {
+        let dir = tempdir().unwrap();
+        let a = WorkingFiles::claim(dir.path().to_path_buf(), "0123456789abcdef0123").unwrap();
+        let b = WorkingFiles::claim(dir.path().to_path_buf(), "0123456789abcdef0123").unwrap();
+        assert_ne!(a.name, b.name);
+        assert!(a.name.starts_with("0123456789abcdef_"));
+        assert!(!a.name.contains('.'));
+        assert!(a.path("lock").exists());
+
+        fs::write(a.path("3ddose"), "1 1 1").unwrap();
+        fs::write(a.path("egsphsp1"), "").unwrap();
+        assert_eq!(a.read_dose3d(), Some("1 1 1".to_string()));
+        assert_eq!(a.phsp_paths(), vec![a.path("egsphsp1")]);
+        assert!(b.phsp_paths().is_empty());
+        a.cleanup();
+        assert!(!a.path("3ddose").exists());
+        a.release();
+        assert!(!a.path("lock").exists());
+        assert!(b.path("lock").exists());
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let dir = tempdir().unwrap();
+        let mut input = SingSimInput::from_egsinp_path(
+            "egs_chamber",
+            &asset_path().join("three_calc_geos.egsinp"),
+            "521icru",
+        ).unwrap();
+        input.env.egs_home = Some(PathBuf::from("/egs_home"));
+        input.extra_args = vec!["--seed={seed2}".to_string()];
+        let sim = input.clone().split(vec![10, 20], vec![(7, 1), (7, 2)]);
+        let chunks = sim.dry_run(dir.path()).unwrap();
+        assert_eq!(chunks.len(), 2);
+        let c = &chunks[1];
+        assert_eq!(c.chunk.seed, (7, 2));
+        assert_eq!(c.ncase, 20);
+        assert_eq!(c.particle_range, None);
+        assert_eq!(c.egsinp, dir.path().join("three_calc_geos_1.egsinp"));
+        assert_eq!(c.working_dir, Some(PathBuf::from("/egs_home/egs_chamber")));
+        assert_eq!(
+            c.command,
+            "EGS_HOME='/egs_home' 'egs_chamber' '-i' 'three_calc_geos_1' '-p' '521icru' '--seed=2'"
+        );
+        let content = fs::read_to_string(&c.egsinp).unwrap();
+        assert!(content.contains("ncase = 20"));
+        assert!(content.contains("initial seeds = 7 2"));
+
+        // EGSnrc would silently run these with its default seeds
+        for &seed in &[(0, 1), (MAX_IXX + 1, 1), (7, MAX_JXX + 1)] {
+            let sim = input.clone().split(vec![10], vec![seed]);
+            let err = sim.dry_run(dir.path()).unwrap_err();
+            assert!(err.to_string().starts_with("Invalid seed"));
+        }
+    }
+
+    #[test]
+    fn test_fresh_seed() {
+        let used = vec![(42, 1), (42, 2), (43, 2)];
+        assert_eq!(fresh_seed((42, 1), &used).unwrap(), (43, 1));
+        assert_eq!(fresh_seed((42, 2), &used).unwrap(), (44, 2));
+        assert_eq!(fresh_seed((MAX_IXX - 1, 1), &used).unwrap(), (MAX_IXX, 1));
+        assert!(fresh_seed((MAX_IXX, 1), &used).is_err());
+        assert!(fresh_seed((MAX_IXX - 1, 1), &[(MAX_IXX, 1)]).is_err());
+    }
+
+    #[test]
+    fn test_report_failed_chunks() {
+        let path = asset_path().join("fin_par_sim.json");
+        let mut raw: ParSimFinished = load(&path).unwrap();
+        let attempt = raw.outputs[1].clone();
+        raw.outputs[1].exit_status = 1;
+        raw.failed_attempts.push((1, attempt));
+        assert!(!raw.outputs[1].succeeded());
+
+        raw.input.max_failed_chunks = Some(1);
+        let report = raw.report();
+        assert_eq!(report.excluded, vec![1]);
+        assert_eq!(report.lost_histories, raw.input.ncases[1]);
+        assert_eq!(report.failed_attempts.len(), 1);
+        assert_eq!(report.simulation_finished, Omittable::Available(false));
+        assert!(report.dose.is_available());
+        assert!(report.check_failures().is_ok());
+        assert!(report.to_string_output().contains("Excluded chunks: [1]"));
+
+        let n = raw.outputs.len();
+        let mut other = report.clone();
+        for seed in &mut other.input.seeds {
+            seed.1 += n;
+        }
+        let combined = ParSimReport::combine(&[report, other]).unwrap();
+        assert_eq!(combined.excluded, vec![1, n + 1]);
+        let indices: Vec<usize> = combined.failed_attempts.iter().map(|&(i, _)| i).collect();
+        assert_eq!(indices, vec![1, n + 1]);
+
+        raw.input.max_failed_chunks = Some(0);
+        let report = raw.report();
+        assert!(report.excluded.is_empty());
+        assert!(!report.dose.is_available());
+        assert!(report.check_failures().is_err());
+
+        raw.input.max_failed_chunks = None;
+        assert!(raw.report().check_failures().is_err());
+    }
 
     #[test]
     fn test_report_par_sim() {
@@ -712,4 +2437,208 @@ mod tests {
         assert_relative_eq!(dose_reported.value(), dose_combined.value());
         assert_relative_eq!(dose_reported.rstd(), dose_combined.rstd());
     }
+
+    #[test]
+    fn test_history_weights() {
+        assert_eq!(history_weights(&[1, 3]), vec![0.25, 0.75]);
+        assert_eq!(history_weights(&[0, 0]), vec![0.5, 0.5]);
+        assert!(history_weights(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_report_weighted_by_histories() {
+        let path = asset_path().join("fin_par_sim.json");
+        let mut raw: ParSimFinished = load(&path).unwrap();
+        let n = raw.outputs.len();
+        raw.outputs[0].stdout = raw.outputs[0]
+            .stdout
+            .replace("last case = 1250000", "last case = 3750000");
+        // an old report without the line falls back to the ncase of the chunk
+        raw.outputs[1].stdout = raw.outputs[1].stdout.replace("last case", "final case");
+        raw.input.ncases[1] = 2500000;
+        let report = raw.report();
+        let mut histories = vec![1250000; n];
+        histories[0] = 3750000;
+        histories[1] = 2500000;
+        assert_eq!(report.histories, histories);
+        let total: u64 = histories.iter().sum();
+        let doses: Vec<Uf64> = raw.outputs
+            .iter()
+            .map(|o| o.report().dose.unwrap()[0].1)
+            .collect();
+        let value: f64 = doses
+            .iter()
+            .zip(&histories)
+            .map(|(d, &h)| d.value() * h as f64 / total as f64)
+            .sum();
+        let var: f64 = doses
+            .iter()
+            .zip(&histories)
+            .map(|(d, &h)| d.var() * (h as f64 / total as f64).powi(2))
+            .sum();
+        assert_relative_eq!(report.weights[0], 3. / 11.);
+        assert_relative_eq!(report.weights.iter().sum::<f64>(), 1.);
+        let dose = report.dose.unwrap()[0].1;
+        assert_relative_eq!(dose.value(), value);
+        assert_relative_eq!(dose.var(), var);
+
+        raw.outputs[2].exit_status = 1;
+        raw.input.max_failed_chunks = Some(1);
+        let report = raw.report();
+        assert_eq!(report.weights[2], 0.);
+        assert_relative_eq!(report.weights[0], 3750000. / (total - 1250000) as f64);
+    }
+
+    #[test]
+    fn test_combine_zero_dose() {
+        let log = fs::read_to_string(asset_path().join("Wasservoxel.log")).unwrap();
+        let path = asset_path().join("fin_par_sim.json");
+        let raw: ParSimFinished = load(&path).unwrap();
+        let mut report = raw.outputs[0].report();
+        report.dose = Omittable::from(output_parser::parse_simulation_output(
+            &mut BufReader::new(log.as_bytes()),
+        ).unwrap()
+            .dose);
+        let dose = compute_dose_result(&[report.clone(), report.clone()], &[0.5, 0.5]).unwrap();
+        assert_eq!(dose[0], ("PSS_Box".to_string(), Uf64::from_value(0.)));
+        assert_eq!(dose[0].1.rstd(), 1.);
+        assert_relative_eq!(dose[1].1.value(), 5.6425e-13);
+        assert_relative_eq!(dose[1].1.rstd(), 0.955e-2 / 2_f64.sqrt());
+
+        let mut scored = report.clone();
+        if let Omittable::Available(ref mut d) = scored.dose {
+            d[0].1 = Uf64::from_value_rstd(2e-14, 0.5);
+        }
+        let dose = compute_dose_result(&[report, scored], &[0.5, 0.5]).unwrap();
+        assert_eq!(dose[0].1.value(), 1e-14);
+        assert_relative_eq!(dose[0].1.rstd(), 0.5);
+    }
+
+    #[test]
+    fn test_report_ratio() {
+        let path = asset_path().join("fin_par_sim.json");
+        let raw: ParSimFinished = load(&path).unwrap();
+        let report = raw.report();
+        let dose = report.dose_of("Block_").unwrap();
+        assert!(report.dose_of("nothing").is_err());
+        let correlated = report.correlated_dose("Block_").unwrap();
+        assert_relative_eq!(correlated.to_uf64().value(), dose.value());
+        // the spread of 8 chunks agrees roughly with their reported uncertainty
+        assert!((correlated.std() / dose.std() - 1.).abs() < 0.5);
+        let s = report.to_string_ratio("Block_", "Block_").unwrap();
+        assert!(s.starts_with("Block_ / Block_: 1 +- 0%\nIgnoring the correlation of 1.000: 1 +- "));
+        let s = report.to_string_expression("sqrt(Block_ / Block_) ^ 2").unwrap();
+        assert!(s.starts_with("sqrt(Block_ / Block_) ^ 2: 1 +- 0%\nIgnoring the correlations: 1 +- "));
+        let expr = Expr::parse("sqrt(Block_ * Block_) / Block_").unwrap();
+        match expr.eval(&|name: &str| report.correlated_dose(name)).unwrap() {
+            Value::Quantity(q) => assert!(q.std() < 1e-12),
+            Value::Number(_) => panic!("Lost the geometry"),
+        }
+        let s = report.to_string_expression("ln(exp(Block_ / Block_)) - 1").unwrap();
+        assert!(s.starts_with("ln(exp(Block_ / Block_)) - 1: 0 +- "));
+        assert!(report.to_string_expression("Block_ / nothing").is_err());
+        assert!(report.to_string_expression("Block_ /").is_err());
+    }
+
+    #[test]
+    fn test_report_consistency() {
+        let path = asset_path().join("fin_par_sim.json");
+        let mut raw: ParSimFinished = load(&path).unwrap();
+        let report = raw.report();
+        assert_eq!(report.consistency.len(), 1);
+        assert_eq!(report.consistency[0].dof, raw.outputs.len() - 1);
+        assert!(report.consistency[0].is_consistent());
+        assert!(!report.to_string_output().contains("Warning"));
+
+        raw.outputs[3].stdout = raw.outputs[3]
+            .stdout
+            .replace("1.3646e-14 +/- 6.552", "3.0e-14 +/- 6.552");
+        let report = raw.report();
+        assert_eq!(report.flagged_chunks(), vec![3]);
+        let output = report.to_string_output();
+        assert!(output.contains("Warning: The chunks disagree in Block_"));
+        assert!(output.contains("chunk 3 deviates by"));
+
+        let cleaned = report.clone().reject_flagged().unwrap();
+        assert_eq!(cleaned.rejected, vec![3]);
+        assert_eq!(cleaned.excluded, vec![3]);
+        assert_eq!(cleaned.weights[3], 0.);
+        assert!(cleaned.consistency[0].is_consistent());
+        assert!(cleaned.dose_of("Block_").unwrap().value() < report.dose_of("Block_").unwrap().value());
+        assert!(cleaned.to_string_output().contains("Rejected as outliers: [3]"));
+
+        let n = raw.outputs.len();
+        let mut cleaned = cleaned;
+        for seed in &mut cleaned.input.seeds {
+            seed.1 += n;
+        }
+        let combined = ParSimReport::combine(&[report, cleaned]).unwrap();
+        assert_eq!(combined.rejected, vec![n + 3]);
+        assert_eq!(combined.flagged_chunks(), vec![3]);
+
+        // two chunks that disagree cannot tell which one is off
+        let mut two = raw.clone();
+        two.outputs.swap(1, 3);
+        two.outputs.truncate(2);
+        two.input.seeds.truncate(2);
+        two.input.ncases.truncate(2);
+        let report = two.report();
+        assert_eq!(report.flagged_chunks(), vec![0, 1]);
+        let err = report.reject_flagged().unwrap_err();
+        assert!(err.to_string().starts_with("Only 0 of 2 chunks would remain"));
+
+        // too many outliers to blame the chunks
+        for &(i, dose) in &[(0, "1.2027e-14 +/- 6.940"), (5, "1.2592e-14 +/- 7.217")] {
+            raw.outputs[i].stdout = raw.outputs[i].stdout.replace(dose, "3.0e-14 +/- 6.552");
+        }
+        let report = raw.report();
+        assert!(report.flagged_chunks().len() >= 3);
+        let err = report.reject_flagged().unwrap_err();
+        assert!(err.to_string().contains(" of 8 chunks are outliers"));
+    }
+
+    #[test]
+    fn test_report_accounting() {
+        let path = asset_path().join("fin_par_sim.json");
+        let mut raw: ParSimFinished = load(&path).unwrap();
+        assert_eq!(raw.report().accounting, Omittable::Omitted);
+        assert!(raw.report().to_string_timeline().contains("no timing"));
+        for (i, out) in raw.outputs.iter_mut().enumerate() {
+            out.accounting = Some(Accounting {
+                start: 100. + i as f64,
+                end: 110. + i as f64 * 2.,
+                user_time: 9.,
+                system_time: 1.,
+                max_rss: 1000 * (i as u64 + 1),
+                hostname: if i < 4 { "a" } else { "b" }.to_string(),
+                signal: None,
+            });
+        }
+        raw.outputs[1].accounting = None;
+        let n = raw.outputs.len();
+        let report = raw.report();
+        let a = report.accounting.clone().unwrap();
+        assert_eq!(a.nchunks, n - 1);
+        assert_eq!(a.start, 100.);
+        assert_eq!(a.end, 110. + (n - 1) as f64 * 2.);
+        assert_eq!(a.user_time, 9. * (n - 1) as f64);
+        assert_eq!(a.max_rss, 1000 * n as u64);
+        assert_eq!(a.hosts, vec!["a", "b"]);
+        assert_eq!(a.max_wall_time, 10. + (n - 1) as f64);
+        let timeline = report.to_string_timeline();
+        assert_eq!(timeline.lines().count(), n + 2);
+        assert!(timeline.contains("    1 not measured"));
+        assert!(report.to_string_output().contains("on 2 host(s)"));
+
+        // chunks restored from the cache take no time
+        for out in raw.outputs.iter_mut() {
+            if let Some(ref mut a) = out.accounting {
+                a.end = a.start;
+            }
+        }
+        let report = raw.report();
+        assert_eq!(report.accounting.clone().unwrap().imbalance(), 1.);
+        let output = report.to_string_output() + &report.to_string_timeline();
+        assert!(!output.contains("NaN") && !output.contains("inf"));
+    }
 }
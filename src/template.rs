@@ -0,0 +1,100 @@
+use errors::*;
+
+/// Placeholders that may appear in extra application arguments.
+pub const PLACEHOLDERS: [&str; 7] = [
+    "chunk",
+    "seed1",
+    "seed2",
+    "name",
+    "input",
+    "pegsfile",
+    "application",
+];
+
+/// Split `template` into literal text and `{key}` placeholders.
+/// `{{` and `}}` stand for literal braces.
+fn parse(template: &str) -> Result<Vec<(bool, String)>> {
+    let mut ret = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut key = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => key.push(c),
+                        None => bail!("Unterminated placeholder {{{} in {:?}", key, template),
+                    }
+                }
+                ret.push((false, text.clone()));
+                text.clear();
+                ret.push((true, key));
+            }
+            '}' => bail!("Unmatched '}}' in {:?}", template),
+            _ => text.push(c),
+        }
+    }
+    ret.push((false, text));
+    Ok(ret)
+}
+
+/// Check that `template` only uses known placeholders.
+pub fn validate(template: &str) -> Result<()> {
+    for (is_key, s) in parse(template)? {
+        if is_key && !PLACEHOLDERS.contains(&s.as_str()) {
+            bail!(
+                "Unknown placeholder {{{}}} in {:?}, expected one of {:?}",
+                s,
+                template,
+                PLACEHOLDERS
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Replace the placeholders in `template` by their `values`.
+pub fn expand(template: &str, values: &[(&str, String)]) -> Result<String> {
+    let mut ret = String::new();
+    for (is_key, s) in parse(template)? {
+        if !is_key {
+            ret.push_str(&s);
+            continue;
+        }
+        match values.iter().find(|(k, _)| *k == s) {
+            Some((_, v)) => ret.push_str(v),
+            None => bail!("No value for placeholder {{{}}} in {:?}", s, template),
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let values = [("chunk", "3".to_string()), ("name", "abc".to_string())];
+        assert_eq!(expand("-o", &values).unwrap(), "-o");
+        assert_eq!(expand("{name}_{chunk}.out", &values).unwrap(), "abc_3.out");
+        assert_eq!(expand("{{chunk}}", &values).unwrap(), "{chunk}");
+        assert!(expand("{seed1}", &values).is_err());
+        assert!(expand("a}b", &values).is_err());
+        assert!(expand("{chunk", &values).is_err());
+        assert!(validate("--out={name").is_err());
+
+        assert!(validate("--batch={seed1},{seed2}").is_ok());
+        assert!(validate("{nme}").is_err());
+    }
+}
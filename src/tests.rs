@@ -1,11 +1,11 @@
 use util::{asset_path, has_unique_elements, load};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use simulation::{ParSimInput, ParSimReport, Seed};
 use assert_cli;
 use rand;
 use rand::Rng;
 use std::fs;
-use tempfile::tempdir;
+use tempfile::{tempdir, TempDir};
 use uncertain::Uf64;
 
 fn randstring() -> String {
@@ -26,6 +26,56 @@ fn run_and_load(input_path: &Path, extra_args: &[&str]) -> ParSimReport {
     r
 }
 
+/// Output of an egs_chamber run with a dose of 1 +/- 1%.
+const FAKE_OUTPUT: &str = "====\n====\n====\nFinished simulation\n\
+                           Total cpu time for this run: 1.0 (sec.) 0.0003(hours)\n\n\
+                           Geometry     Cavity dose\n---------\n\
+                           geo     1.0 +/- 1.0  %\n\nfinishSimulation(egs_chamber) 0";
+
+/// An EGS_HOME with an egs_chamber directory and an executable shell script
+/// standing in for the application.
+struct FakeEgs {
+    egs_home: TempDir,
+    app: PathBuf,
+}
+
+impl FakeEgs {
+    fn new(script: &str) -> Self {
+        use std::os::unix::fs::PermissionsExt;
+        let egs_home = tempdir().unwrap();
+        fs::create_dir(egs_home.path().join("egs_chamber")).unwrap();
+        let app = egs_home.path().join("fake_app");
+        fs::write(&app, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(&app, fs::Permissions::from_mode(0o755)).unwrap();
+        FakeEgs { egs_home, app }
+    }
+
+    /// A script that appends its arguments to `log` and prints `FAKE_OUTPUT`.
+    fn logging(log: &Path) -> Self {
+        FakeEgs::new(&format!("echo \"$@\" >> '{}'\necho '{}'", log.display(), FAKE_OUTPUT))
+    }
+
+    fn dir(&self) -> PathBuf {
+        self.egs_home.path().join("egs_chamber")
+    }
+
+    /// Options that run the script as the application.
+    fn args(&self) -> Vec<&str> {
+        vec![
+            "--egs-home",
+            self.egs_home.path().to_str().unwrap(),
+            "--executable",
+            self.app.to_str().unwrap(),
+        ]
+    }
+
+    fn run(&self, input_path: &Path, extra_args: &[&str]) -> ParSimReport {
+        let mut args = extra_args.to_vec();
+        args.extend(self.args());
+        run_and_load(input_path, &args)
+    }
+}
+
 fn assert_close_doses(dose1: Vec<(String, Uf64)>, dose2: Vec<(String, Uf64)>) {
     if dose1.len() != dose2.len() {
         panic!("Vectors of different length");
@@ -292,3 +342,571 @@ fn test_error_message() {
         .contains("Caused by: No such file or directory")
         .unwrap();
 }
+
+#[test]
+fn test_invalid_limits() {
+    let input_path = asset_path().join("three_calc_geos.egsinp");
+    let sinput_path = input_path.to_str().unwrap();
+    for &(arg, message) in &[
+        ("--timeout=-1", "must be a positive number"),
+        ("--timeout=nan", "must be a positive number"),
+        ("--timeout=inf", "must be a positive number"),
+        ("--max-memory=18446744073709551615", "is too large"),
+    ] {
+        assert_cli::Assert::main_binary()
+            .with_args(&["run", sinput_path, "-o", "myout", arg])
+            .fails()
+            .and()
+            .stderr()
+            .contains(message)
+            .unwrap();
+    }
+}
+
+#[test]
+fn test_missing_application() {
+    let egs_home = tempdir().unwrap();
+    let app = "hen_no_such_application";
+    fs::create_dir(egs_home.path().join(app)).unwrap();
+    let input_path = asset_path().join("three_calc_geos.egsinp");
+    let output_path = tempdir().unwrap().path().join(randstring());
+    let env = assert_cli::Environment::inherit().insert("EGS_HOME", egs_home.path());
+    assert_cli::Assert::main_binary()
+        .with_env(env.clone())
+        .with_args(&[
+            "run",
+            input_path.to_str().unwrap(),
+            "-o",
+            output_path.to_str().unwrap(),
+            "-a",
+            app,
+        ])
+        .fails()
+        .and()
+        .stderr()
+        .contains("Cannot find executable")
+        .unwrap();
+
+    let env = env.insert("EGS_HOME", "");
+    assert_cli::Assert::main_binary()
+        .with_env(env)
+        .with_args(&["run", input_path.to_str().unwrap(), "-o", "myout"])
+        .fails()
+        .and()
+        .stderr()
+        .contains("EGS_HOME is not set")
+        .unwrap();
+}
+
+#[test]
+fn test_egs_env_options() {
+    let egs_home = tempdir().unwrap();
+    fs::create_dir(egs_home.path().join("egs_chamber")).unwrap();
+    let input_path = asset_path().join("three_calc_geos.egsinp");
+    let output_path = tempdir().unwrap().path().join(randstring());
+    let args = [
+        "run",
+        input_path.to_str().unwrap(),
+        "-o",
+        output_path.to_str().unwrap(),
+        "--egs-home",
+        egs_home.path().to_str().unwrap(),
+    ];
+    let mut bad_executable = args.to_vec();
+    bad_executable.extend(&["--executable", "/hen/no/such/egs_chamber"]);
+    assert_cli::Assert::main_binary()
+        .with_args(&bad_executable)
+        .fails()
+        .and()
+        .stderr()
+        .contains("\"/hen/no/such/egs_chamber\" is not an executable file")
+        .unwrap();
+
+    let mut bad_env = args.to_vec();
+    bad_env.extend(&["--env", "OMP_NUM_THREADS"]);
+    assert_cli::Assert::main_binary()
+        .with_args(&bad_env)
+        .fails()
+        .and()
+        .stderr()
+        .contains("Expected KEY=VALUE")
+        .unwrap();
+
+    // the executable comes from the file, relative to its directory
+    let env_file = egs_home.path().join("egs.json");
+    fs::write(&env_file, r#"{"executable": "no_such_egs_chamber"}"#).unwrap();
+    let env = assert_cli::Environment::inherit().insert("HEN_EGS_ENV", &env_file);
+    assert_cli::Assert::main_binary()
+        .with_env(env)
+        .with_args(&args)
+        .fails()
+        .and()
+        .stderr()
+        .contains("no_such_egs_chamber\" is not an executable file")
+        .unwrap();
+}
+
+
+/// A script that logs to `dir/overlap` if it starts while another instance
+/// is still running.
+fn exclusive_fake_egs(dir: &Path) -> FakeEgs {
+    FakeEgs::new(&format!(
+        "mkdir '{lock}' || echo overlap >> '{log}'\nsleep 0.2\nrmdir '{lock}'\necho '{out}'",
+        lock = dir.join("lock").display(),
+        log = dir.join("overlap").display(),
+        out = FAKE_OUTPUT
+    ))
+}
+
+#[test]
+fn test_extra_args() {
+    let tmp = tempdir().unwrap();
+    let log = tmp.path().join("log");
+    let fake = FakeEgs::logging(&log);
+    let input_path = asset_path().join("three_calc_geos.egsinp");
+    let r = fake.run(
+        &input_path,
+        &["-t2", "--arg", "--chunk={chunk}", "--arg", "{seed1},{seed2}"],
+    );
+    assert_eq!(r.input.prototype.extra_args, vec!["--chunk={chunk}", "{seed1},{seed2}"]);
+    let started = fs::read_to_string(&log).unwrap();
+    for i in 0..r.single_runs.len() {
+        let (ixx, jxx) = r.input.seeds[i];
+        let line = format!("-p 521icru --chunk={} {},{}\n", i, ixx, jxx);
+        assert!(started.contains(&line));
+    }
+}
+
+#[test]
+fn test_scheduler_job() {
+    use std::process::Command;
+    let tmp = tempdir().unwrap();
+    let fake = FakeEgs::logging(&tmp.path().join("log"));
+    let input_path = asset_path().join("three_calc_geos.egsinp");
+    let split_dir = tmp.path().join("split");
+    fs::create_dir(&split_dir).unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "split",
+            input_path.to_str().unwrap(),
+            "-o",
+            split_dir.to_str().unwrap(),
+            "--nthreads",
+            "1",
+            "--nfiles",
+            "2",
+            "--scheduler",
+            "slurm",
+            "--submit-cmd",
+            "echo '4711;cluster'",
+            "--poll-cmd",
+            "echo {job} RUNNING",
+        ])
+        .with_args(&fake.args())
+        .unwrap();
+    let script = split_dir.join("three_calc_geos.slurm.sh");
+    let job = split_dir.join("three_calc_geos.henjob");
+    let sjob = job.to_str().unwrap();
+    assert!(fs::read_to_string(&script).unwrap().contains("#SBATCH --array=0-1\n"));
+
+    assert_cli::Assert::main_binary()
+        .with_args(&["submit", sjob])
+        .stdout()
+        .contains("Submitted three_calc_geos with job id 4711")
+        .unwrap();
+
+    // what the scheduler would do for the second task
+    let out = Command::new("sh")
+        .arg(&script)
+        .env("SLURM_ARRAY_TASK_ID", "1")
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_cli::Assert::main_binary()
+        .with_args(&["poll", sjob])
+        .stdout()
+        .contains("4711 RUNNING\nFinished chunks: 1/2")
+        .unwrap();
+}
+
+#[test]
+fn test_target_rstd() {
+    // every chunk reports a dose of 1 +/- 1%
+    let tmp = tempdir().unwrap();
+    let fake = FakeEgs::logging(&tmp.path().join("log"));
+    let input_path = asset_path().join("three_calc_geos.egsinp");
+    let r = fake.run(&input_path, &["-t2", "--target-rstd", "0.0051"]);
+    // 1000 histories give 0.71%, so about 1000 * (0.71 / 0.51)^2 are needed
+    assert_eq!(r.input.ncases[..2], [500, 500]);
+    assert!(r.input.ncases[2] > 460);
+    assert!(r.dose.unwrap()[0].1.rstd() <= 0.0051);
+
+    // the files of a split simulation draw disjoint seeds for their rounds
+    let split_dir = tmp.path().join("split");
+    let run_dir = tmp.path().join("run");
+    let combined_dir = tmp.path().join("combined");
+    for dir in &[&split_dir, &run_dir, &combined_dir] {
+        fs::create_dir(dir).unwrap();
+    }
+    assert_cli::Assert::main_binary()
+        .with_args(&["split", input_path.to_str().unwrap(), "-o", split_dir.to_str().unwrap()])
+        .with_args(&["--nthreads", "1", "--nfiles", "2"])
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&["run", split_dir.to_str().unwrap(), "-o", run_dir.to_str().unwrap()])
+        .with_args(&["--target-rstd", "0.0051"])
+        .with_args(&fake.args())
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&["combine", run_dir.to_str().unwrap(), "-o", combined_dir.to_str().unwrap()])
+        .unwrap();
+    let combined: ParSimReport = load(&combined_dir.join("three_calc_geos.henout")).unwrap();
+    assert!(combined.single_runs.len() > 2);
+    assert!(has_unique_elements(combined.input.seeds.clone()));
+}
+
+#[test]
+fn test_resume() {
+    let tmp = tempdir().unwrap();
+    let fail = tmp.path().join("fail");
+    let retried = tmp.path().join("retried");
+    let log = tmp.path().join("log");
+    // chunk 1 fails while the marker exists, chunk 2 only at its first start,
+    // every start is logged
+    let script = format!(
+        "echo \"$5\" >> '{log}'\n\
+         if [ \"$5\" = 1 ] && [ -e '{fail}' ]; then exit 1; fi\n\
+         if [ \"$5\" = 2 ] && [ ! -e '{retried}' ]; then touch '{retried}'; exit 1; fi\n\
+         echo '{out}'",
+        log = log.display(),
+        fail = fail.display(),
+        retried = retried.display(),
+        out = FAKE_OUTPUT
+    );
+    let fake = FakeEgs::new(&script);
+    fs::write(&fail, "").unwrap();
+
+    let input_path = asset_path().join("three_calc_geos.egsinp");
+    let output_path = tmp.path().join("out.henout");
+    let checkpoints = tmp.path().join("out.henout.chunks");
+    let args = [
+        "run",
+        input_path.to_str().unwrap(),
+        "-o",
+        output_path.to_str().unwrap(),
+        "-t3",
+        "--retries",
+        "1",
+        "--arg",
+        "{chunk}",
+    ];
+    assert_cli::Assert::main_binary()
+        .with_args(&args)
+        .with_args(&fake.args())
+        .fails()
+        .and()
+        .stdout()
+        .contains("Kept finished chunks")
+        .unwrap();
+    let first: ParSimReport = load(&output_path).unwrap();
+    assert_eq!(fs::read_dir(&checkpoints).unwrap().count(), 2);
+
+    fs::remove_file(&fail).unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&args)
+        .with_args(&fake.args())
+        .stdout()
+        .contains("Resuming from 2 finished chunks")
+        .unwrap();
+    let second: ParSimReport = load(&output_path).unwrap();
+    let mut started: Vec<String> = fs::read_to_string(&log)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect();
+    started.sort();
+    // the retried chunk 2 is restored with its fresh seed
+    assert_eq!(started, vec!["0", "1", "1", "1", "2", "2"]);
+    assert_eq!(first.single_runs[0], second.single_runs[0]);
+    assert_eq!(first.input.seeds[2], second.input.seeds[2]);
+    assert!(second.dose.is_available());
+    assert!(!checkpoints.exists());
+}
+
+#[test]
+fn test_cache() {
+    let tmp = tempdir().unwrap();
+    let log = tmp.path().join("log");
+    let fake = FakeEgs::logging(&log);
+    let cache_dir = tmp.path().join("cache");
+    let scache_dir = cache_dir.to_str().unwrap();
+    let copy = tmp.path().join("copy.egsinp");
+    fs::copy(asset_path().join("three_calc_geos.egsinp"), &copy).unwrap();
+    let starts = || fs::read_to_string(&log).unwrap().lines().count();
+
+    let mut args = vec!["-t2", "--cache-dir", scache_dir];
+    let first = fake.run(&asset_path().join("three_calc_geos.egsinp"), &args);
+    assert_eq!(starts(), 2);
+    let second = fake.run(&copy, &args);
+    assert_eq!(starts(), 2);
+    assert_eq!(second.dose, first.dose);
+    args.push("--no-cache");
+    fake.run(&copy, &args);
+    assert_eq!(starts(), 4);
+
+    assert_cli::Assert::main_binary()
+        .with_args(&["cache", "info", "--cache-dir", scache_dir])
+        .stdout()
+        .contains("Chunks: 2")
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&["cache", "prune", "--cache-dir", scache_dir, "--max-age", "inf"])
+        .fails()
+        .and()
+        .stderr()
+        .contains("MAX_AGE and MAX_SIZE must be finite and not negative.")
+        .unwrap();
+}
+
+#[test]
+fn test_jobs() {
+    let tmp = tempdir().unwrap();
+    let fake = exclusive_fake_egs(tmp.path());
+    let input_path = asset_path().join("three_calc_geos.egsinp");
+    let r = fake.run(&input_path, &["-t4", "--jobs", "1", "--nice", "1"]);
+    assert_eq!(r.single_runs.len(), 4);
+    assert!(!tmp.path().join("overlap").exists());
+}
+
+#[test]
+fn test_run_tree() {
+    let tmp = tempdir().unwrap();
+    let fake = exclusive_fake_egs(tmp.path());
+    let inputs = tmp.path().join("inputs");
+    fs::create_dir_all(inputs.join("sub")).unwrap();
+    for name in &["a.egsinp", "sub/b.egsinp"] {
+        fs::copy(asset_path().join("three_calc_geos.egsinp"), inputs.join(name)).unwrap();
+    }
+    fs::write(inputs.join("sub/notes.txt"), "not an input").unwrap();
+    let run = |input: &Path, output: &Path, extra: &[&str]| {
+        assert_cli::Assert::main_binary()
+            .with_args(&["run", input.to_str().unwrap(), "-o", output.to_str().unwrap()])
+            .with_args(&["-t2", "--jobs", "1"])
+            .with_args(extra)
+            .with_args(&fake.args())
+            .stdout()
+            .contains("(2/2)")
+            .unwrap();
+    };
+
+    let out = tmp.path().join("out");
+    run(&inputs, &out, &["-r"]);
+    // one slot for the chunks of both inputs
+    assert!(!tmp.path().join("overlap").exists());
+    for name in &["a.henout", "sub/b.henout"] {
+        let r: ParSimReport = load(&out.join(name)).unwrap();
+        assert_eq!(r.single_runs.len(), 2);
+    }
+
+    let out = tmp.path().join("glob");
+    run(&inputs.join("*/*.egsinp"), &out, &[inputs.join("a.egsinp").to_str().unwrap()]);
+    assert!(out.join("sub/b.henout").exists());
+    assert!(out.join("a.henout").exists());
+}
+
+#[test]
+fn test_show_timeline() {
+    let tmp = tempdir().unwrap();
+    let fake = FakeEgs::logging(&tmp.path().join("log"));
+    let output_path = tmp.path().join("out.henout");
+    let soutput_path = output_path.to_str().unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&["run", asset_path().join("three_calc_geos.egsinp").to_str().unwrap()])
+        .with_args(&["-o", soutput_path, "-t3"])
+        .with_args(&fake.args())
+        .stdout()
+        .contains("Wall-clock time:")
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&["show", soutput_path, "--timeline"])
+        .stdout()
+        .contains("Timeline")
+        .stdout()
+        .contains(::util::hostname().as_str())
+        .unwrap();
+}
+
+#[test]
+fn test_dry_run() {
+    let tmp = tempdir().unwrap();
+    let log = tmp.path().join("log");
+    let fake = FakeEgs::logging(&log);
+    let input_path = asset_path().join("three_calc_geos.egsinp");
+    let output_path = tmp.path().join("out.henout");
+    assert_cli::Assert::main_binary()
+        .with_args(&["run", input_path.to_str().unwrap(), "-o", output_path.to_str().unwrap()])
+        .with_args(&["-t2", "--dry-run"])
+        .with_args(&fake.args())
+        .stdout()
+        .contains("'-i' 'three_calc_geos_1' '-p' '521icru'")
+        .unwrap();
+    assert!(!log.exists());
+    assert!(!output_path.exists());
+    let dir = tmp.path().join("out.henout.dryrun");
+    let content = fs::read_to_string(dir.join("three_calc_geos_1.egsinp")).unwrap();
+    assert!(content.contains("ncase = 500"));
+}
+
+#[test]
+fn test_native_backend() {
+    // jobs write <name>_w<job>.egsdat, the combine step <name>.egsdat
+    let combined = FAKE_OUTPUT.replace("1.0 +/- 1.0", "1.01 +/- 0.5");
+    let script = format!(
+        "name=$2\ndir=\"$EGS_HOME/egs_chamber\"\n\
+         while [ $# -gt 0 ]; do\n\
+           case $1 in\n\
+             -j) touch \"$dir/${{name}}_w$2.egsdat\" \"$dir/${{name}}_w$2.egslog\"; echo '{job}'; exit 0;;\n\
+             -c) touch \"$dir/$name.egsdat\"; echo '{combined}'; exit 0;;\n\
+           esac\n\
+           shift\n\
+         done\n\
+         exit 1",
+        job = FAKE_OUTPUT,
+        combined = combined
+    );
+    let fake = FakeEgs::new(&script);
+    let tmp = tempdir().unwrap();
+    let output_path = tmp.path().join("out.henout");
+    assert_cli::Assert::main_binary()
+        .with_args(&["run", asset_path().join("three_calc_geos.egsinp").to_str().unwrap()])
+        .with_args(&["-o", output_path.to_str().unwrap(), "-t3", "--backend", "native"])
+        .with_args(&fake.args())
+        .stdout()
+        .contains("Combined by the application:\ngeo: 1.01 +- 0.5%, hen differs by -0.990%")
+        .unwrap();
+    let r: ParSimReport = load(&output_path).unwrap();
+    assert_eq!(r.single_runs.len(), 3);
+    assert!(r.native_combine.unwrap().dose.is_available());
+    for name in &["out_w1.egsdat", "out_w3.egsdat", "out.egsdat"] {
+        assert!(tmp.path().join(name).exists(), "{} is missing", name);
+    }
+    assert_eq!(fs::read_dir(fake.dir()).unwrap().count(), 0);
+
+    let rerun_path = tmp.path().join("rerun.henout");
+    assert_cli::Assert::main_binary()
+        .with_args(&["rerun", output_path.to_str().unwrap(), "-o", rerun_path.to_str().unwrap()])
+        .with_args(&fake.args())
+        .unwrap();
+    let r: ParSimReport = load(&rerun_path).unwrap();
+    assert!(r.native_combine.is_some());
+    assert!(tmp.path().join("rerun.egsdat").exists());
+    assert_eq!(fs::read_dir(fake.dir()).unwrap().count(), 0);
+}
+
+#[test]
+fn test_verify() {
+    let fake = FakeEgs::new("cat \"$EGS_HOME/output.log\"");
+    let log = fake.egs_home.path().join("output.log");
+    fs::write(&log, FAKE_OUTPUT).unwrap();
+    let tmp = tempdir().unwrap();
+    let output_path = tmp.path().join("out.henout");
+    let input_path = asset_path().join("three_calc_geos.egsinp");
+    assert_cli::Assert::main_binary()
+        .with_args(&["run", input_path.to_str().unwrap(), "-o", output_path.to_str().unwrap()])
+        .with_args(&["-t3"])
+        .with_args(&fake.args())
+        .unwrap();
+
+    let args = ["verify", output_path.to_str().unwrap(), "--chunks", "0,2"];
+    assert_cli::Assert::main_binary()
+        .with_args(&args)
+        .with_args(&fake.args())
+        .stdout()
+        .contains("Chunk 0: identical\nChunk 2: identical")
+        .unwrap();
+
+    fs::write(&log, FAKE_OUTPUT.replace("1.0 +/- 1.0", "1.5 +/- 1.0")).unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&args)
+        .with_args(&fake.args())
+        .fails()
+        .and()
+        .stdout()
+        .contains("Chunk 0: differs")
+        .stderr()
+        .contains("2 of 2 chunks do not match")
+        .unwrap();
+
+    // the same values, printed differently
+    fs::write(&log, FAKE_OUTPUT.replace("1.0 +/- 1.0", "1.00 +/- 1.0")).unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&args)
+        .with_args(&fake.args())
+        .fails()
+        .and()
+        .stdout()
+        .contains("Chunk 0: differs\n    Printed results differ, though they parse to the same values")
+        .unwrap();
+    assert_eq!(fs::read_dir(fake.dir()).unwrap().count(), 0);
+}
+
+#[test]
+fn test_show_ratio() {
+    use simulation::ParSimFinished;
+    use util::save;
+    let raw: ParSimFinished = load(&asset_path().join("fin_par_sim.json")).unwrap();
+    let tmp = tempdir().unwrap();
+    let path = tmp.path().join("out.henout");
+    save(&path, &raw.report()).unwrap();
+    let spath = path.to_str().unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&["show", spath, "--ratio", "Block_", "Block_"])
+        .stdout()
+        .contains("Block_ / Block_: 1 +- 0%")
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&["show", spath, "--ratio", "Block_", "cavity"])
+        .fails()
+        .and()
+        .stderr()
+        .contains("Chunk 0 has no geometry \"cavity\"")
+        .unwrap();    assert_cli::Assert::main_binary()
+        .with_args(&["show", spath, "--expr", "sqrt(Block_ / Block_) ^ 2"])
+        .stdout()
+        .contains("sqrt(Block_ / Block_) ^ 2: 1 +- 0%")
+        .unwrap();
+}
+
+#[test]
+fn test_combine_exclude_outliers() {
+    use simulation::ParSimFinished;
+    use util::save;
+    let mut raw: ParSimFinished = load(&asset_path().join("fin_par_sim.json")).unwrap();
+    let good = raw.report();
+    raw.outputs[3].stdout = raw.outputs[3]
+        .stdout
+        .replace("1.3646e-14 +/- 6.552", "3.0e-14 +/- 6.552");
+    for seed in &mut raw.input.seeds {
+        seed.1 += 100;
+    }
+    let bad = raw.report();
+    let tmp = tempdir().unwrap();
+    let run_dir = tmp.path().join("runs");
+    fs::create_dir(&run_dir).unwrap();
+    save(&run_dir.join("good.henout"), &good).unwrap();
+    save(&run_dir.join("bad.henout"), &bad).unwrap();
+    let output_dir = tmp.path().join("combined");
+    fs::create_dir(&output_dir).unwrap();
+    let output_path = output_dir
+        .join(&good.input.prototype.filename)
+        .with_extension("henout");
+    assert_cli::Assert::main_binary()
+        .with_args(&["combine", run_dir.to_str().unwrap(), "-o", output_dir.to_str().unwrap()])
+        .with_args(&["--exclude-outliers"])
+        .stdout()
+        .contains("Excluding outlier chunks")
+        .unwrap();
+    let r: ParSimReport = load(&output_path).unwrap();
+    assert_eq!(r.rejected.len(), 1);
+    assert_eq!(r.excluded, r.rejected);
+}
@@ -1,7 +1,7 @@
 use std::io::{BufRead, BufReader};
 use std::option::Option;
 use std::iter::Iterator;
-use simulation::Seed;
+use simulation::{ParticleRange, Seed};
 use errors::*;
 
 use regex::Regex;
@@ -195,6 +195,14 @@ impl TokenStream {
         Ok(ncase)
     }
 
+    /// Number of batches the application reports, 10 unless set in the input.
+    pub fn get_nbatch(&self) -> usize {
+        self.find_index_single("nbatch")
+            .and_then(|i| self.get_index(i).value())
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(10)
+    }
+
     pub fn split(&self, seeds: &Vec<Seed>, ncases: &Vec<u64>) -> Result<Vec<TokenStream>> {
         let ret = ncases
             .iter()
@@ -204,6 +212,97 @@ impl TokenStream {
         Ok(ret)
     }
 
+    /// Index of the matching stop token of the block started at `istart`.
+    fn find_block_stop(&self, istart: usize) -> Option<usize> {
+        let mut depth = 0;
+        for (i, token) in self.tokens.iter().enumerate().skip(istart) {
+            match *token {
+                Token::Start(_) => depth += 1,
+                Token::Stop(_) => depth -= 1,
+                Token::KeyValue(_, _) => {}
+            }
+            if depth == 0 {
+                return Some(i);
+            }
+        }
+        None
+    }
+
+    /// Values of the keys directly inside the block `[istart, istop]`,
+    /// ignoring nested blocks.
+    fn block_values(&self, istart: usize, istop: usize, key: &str) -> Vec<(usize, &String)> {
+        let mut ret = Vec::new();
+        let mut depth = 0;
+        for i in (istart + 1)..istop {
+            match self.tokens[i] {
+                Token::Start(_) => depth += 1,
+                Token::Stop(_) => depth -= 1,
+                Token::KeyValue(ref k, ref v) => {
+                    if (depth == 0) & (k == key) {
+                        ret.push((i, v));
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    /// Token index ranges of `:start source:` blocks that read particles
+    /// from a phase space file.
+    pub fn find_phsp_sources(&self) -> Vec<(usize, usize)> {
+        let mut ret = Vec::new();
+        for (istart, token) in self.tokens.iter().enumerate() {
+            if *token != Token::Start("source".to_string()) {
+                continue;
+            }
+            if let Some(istop) = self.find_block_stop(istart) {
+                let libs = self.block_values(istart, istop, "library");
+                if libs.iter().any(|&(_, lib)| lib == "egs_phsp_source") {
+                    ret.push((istart, istop));
+                }
+            }
+        }
+        ret
+    }
+
+    /// Path of the phase space file, if the input uses a phase space source.
+    pub fn get_phsp_file(&self) -> Result<Option<String>> {
+        let sources = self.find_phsp_sources();
+        let (istart, istop) = match sources.len() {
+            0 => return Ok(None),
+            1 => sources[0],
+            n => bail!("Found {} phase space sources, can only partition one.", n),
+        };
+        let files = self.block_values(istart, istop, "phase space file");
+        let &(_, file) = single(&files).ok_or("Cannot find phase space file")?;
+        Ok(Some(file.clone()))
+    }
+
+    /// Restrict the phase space source to the zero based, half open particle
+    /// range. It is written as the one based, inclusive `first particle` and
+    /// `last particle` keys of the source block.
+    pub fn with_particle_range(&self, range: &ParticleRange) -> Result<TokenStream> {
+        let sources = self.find_phsp_sources();
+        let &(istart, istop) = single(&sources).ok_or("Cannot find single phase space source")?;
+        let mut ret = self.clone();
+        let mut obsolete: Vec<usize> = Vec::new();
+        for key in ["first particle", "last particle"].iter() {
+            obsolete.extend(self.block_values(istart, istop, key).iter().map(|&(i, _)| i));
+        }
+        obsolete.sort();
+        for i in obsolete.iter().rev() {
+            ret.tokens.remove(*i);
+        }
+        let &(first, last) = range;
+        let new = vec![
+            Token::KeyValue("first particle".to_string(), format!("{}", first + 1)),
+            Token::KeyValue("last particle".to_string(), format!("{}", last)),
+        ];
+        let pos = istart + 1;
+        ret.tokens.splice(pos..pos, new);
+        Ok(ret)
+    }
+
     fn with_seed_and_ncase(&self, seed: &Seed, ncase_new: u64) -> Result<TokenStream> {
         let mut ret = self.clone();
         let index_ncase = self.find_index_single("ncase").ok_or("Cannot find ncase")?;
@@ -281,6 +380,48 @@ fn test_parse_single_token() {
     assert_eq!(Token::parse(s_garbage).into_stub(), t_garbage);
 }
 
+#[test]
+fn test_particle_range() {
+    let s = "
+:start source definition:
+    :start source:
+        library = egs_phsp_source
+        name = the_source
+        phase space file = ../linac.egsphsp1
+        first particle = 7
+        :start cutout:
+            last particle = 3
+        :stop cutout:
+    :stop source:
+    simulation source = the_source
+:stop source definition:
+";
+    let stream = TokenStream::parse_string(s).unwrap();
+    assert_eq!(stream.find_phsp_sources(), vec![(1, 9)]);
+    assert_eq!(
+        stream.get_phsp_file().unwrap(),
+        Some("../linac.egsphsp1".to_string())
+    );
+    let out = stream.with_particle_range(&(100, 200)).unwrap();
+    assert_eq!(out.tokens.len(), stream.tokens.len() + 1);
+    assert_eq!(
+        out.tokens[2],
+        Token::KeyValue("first particle".to_string(), "101".to_string())
+    );
+    assert_eq!(
+        out.tokens[3],
+        Token::KeyValue("last particle".to_string(), "200".to_string())
+    );
+    assert_eq!(out.find_index("first particle").len(), 1);
+    assert_eq!(out.find_index("last particle").len(), 2);
+
+    let path = ::util::asset_path().join("three_calc_geos.egsinp");
+    let mut reader = BufReader::new(::std::fs::File::open(path).unwrap());
+    let stream = TokenStream::parse_reader(&mut reader).unwrap();
+    assert_eq!(stream.get_phsp_file().unwrap(), None);
+    assert!(stream.with_particle_range(&(0, 1)).is_err());
+}
+
 #[test]
 fn test_parse_tokenstream() {
     let s = "
@@ -1,43 +1,92 @@
-use std::ops::{Add, Mul};
+use std::ops::{Add, Div, Mul, Neg, Sub};
 use std::fmt;
+use errors::*;
 
+/// A value with its variance, and the number of samples it was estimated
+/// from if known. Only a value estimated directly from samples knows their
+/// number, the results of arithmetic do not.
 #[derive(Copy, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "Uf64Repr")]
 pub struct Uf64 {
     value: f64,
-    rstd: f64,
+    var: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    samples: Option<u64>,
+}
+
+/// How a `Uf64` is read. Reports written before the variance was stored
+/// have the relative standard deviation instead.
+#[derive(Deserialize)]
+struct Uf64Repr {
+    value: f64,
+    #[serde(default)]
+    var: Option<f64>,
+    #[serde(default)]
+    rstd: Option<f64>,
+    #[serde(default)]
+    samples: Option<u64>,
+}
+
+impl From<Uf64Repr> for Uf64 {
+    fn from(repr: Uf64Repr) -> Uf64 {
+        let var = match (repr.var, repr.rstd) {
+            (Some(var), _) => var,
+            (None, Some(rstd)) if rstd.is_finite() => (repr.value * rstd).powi(2),
+            // old reports store an rstd they could not compute as null: a
+            // zero dose where nothing was scored, else an unknown uncertainty
+            (None, _) if repr.value == 0. => 0.,
+            (None, _) => f64::INFINITY,
+        };
+        Uf64 {
+            value: repr.value,
+            var,
+            samples: repr.samples,
+        }
+    }
 }
 
 impl Uf64 {
-    #[allow(dead_code)]
     pub fn std(&self) -> f64 {
         self.var().sqrt()
     }
 
+    /// Relative standard deviation. A zero value has none, so like EGSnrc we
+    /// report 100% if nothing was scored and infinity otherwise.
     pub fn rstd(&self) -> f64 {
-        // self.std() / self.value
-        self.rstd
+        if self.value != 0. {
+            self.std() / self.value.abs()
+        } else if self.var == 0. {
+            1.
+        } else {
+            f64::INFINITY
+        }
     }
 
     pub fn rvar(&self) -> f64 {
-        // self.std() / self.value
         self.rstd().powi(2)
     }
 
     pub fn var(&self) -> f64 {
-        (self.value() * self.rstd()).powi(2)
+        self.var
     }
 
     pub fn value(&self) -> f64 {
         self.value
     }
 
-    #[allow(dead_code)]
+    pub fn with_samples(self, samples: u64) -> Self {
+        Uf64 {
+            samples: Some(samples),
+            ..self
+        }
+    }
+
     pub fn from_value(value: f64) -> Self {
         Self::from_value_var(value, 0.)
     }
 
     pub fn from_value_rstd(value: f64, rstd: f64) -> Self {
-        Self { value, rstd }
+        Self::from_value_var(value, (value * rstd).powi(2))
     }
 
     #[allow(dead_code)]
@@ -46,10 +95,37 @@ impl Uf64 {
         Self::from_value_var(value, var)
     }
 
-    #[allow(dead_code)]
     pub fn from_value_var(value: f64, var: f64) -> Self {
-        let rstd = var.sqrt() / value;
-        Self { value, rstd }
+        Uf64 {
+            value,
+            var,
+            samples: None,
+        }
+    }
+
+    /// `f(self)` to first order, given `f(value)` and `f'(value)`.
+    fn apply(self, value: f64, derivative: f64) -> Self {
+        Uf64::from_value_var(value, derivative.powi(2) * self.var)
+    }
+
+    pub fn powf(self, p: f64) -> Self {
+        let x = self.value;
+        self.apply(x.powf(p), p * x.powf(p - 1.))
+    }
+
+    pub fn sqrt(self) -> Self {
+        let root = self.value.sqrt();
+        self.apply(root, 0.5 / root)
+    }
+
+    pub fn ln(self) -> Self {
+        let x = self.value;
+        self.apply(x.ln(), 1. / x)
+    }
+
+    pub fn exp(self) -> Self {
+        let e = self.value.exp();
+        self.apply(e, e)
     }
 }
 
@@ -57,21 +133,77 @@ impl Add for Uf64 {
     type Output = Uf64;
 
     fn add(self: Uf64, other: Uf64) -> Uf64 {
-        let val1 = self.value();
-        let val2 = other.value();
-        let var1 = self.var();
-        let var2 = other.var();
-        Uf64::from_value_var(val1 + val2, var1 + var2)
+        Uf64::from_value_var(self.value() + other.value(), self.var() + other.var())
     }
 }
 
 impl Mul for Uf64 {
     type Output = Uf64;
 
+    /// Product of independent values, to first order.
     fn mul(self: Uf64, other: Uf64) -> Uf64 {
         let value = self.value() * other.value();
-        let rstd = (self.rvar() + other.rvar()).sqrt();
-        Uf64::from_value_rstd(value, rstd)
+        let var = other.value().powi(2) * self.var() + self.value().powi(2) * other.var();
+        Uf64::from_value_var(value, var)
+    }
+}
+
+impl Neg for Uf64 {
+    type Output = Uf64;
+
+    fn neg(self) -> Uf64 {
+        self * -1.
+    }
+}
+
+impl Sub for Uf64 {
+    type Output = Uf64;
+
+    fn sub(self, other: Uf64) -> Uf64 {
+        self + -other
+    }
+}
+
+impl Div for Uf64 {
+    type Output = Uf64;
+
+    /// Quotient of independent values, to first order.
+    fn div(self, other: Uf64) -> Uf64 {
+        let value = self.value() / other.value();
+        let var = (self.var() + value.powi(2) * other.var()) / other.value().powi(2);
+        Uf64::from_value_var(value, var)
+    }
+}
+
+impl Add<f64> for Uf64 {
+    type Output = Uf64;
+
+    fn add(self, x: f64) -> Uf64 {
+        self.apply(self.value + x, 1.)
+    }
+}
+
+impl Sub<f64> for Uf64 {
+    type Output = Uf64;
+
+    fn sub(self, x: f64) -> Uf64 {
+        self.apply(self.value - x, 1.)
+    }
+}
+
+impl Mul<f64> for Uf64 {
+    type Output = Uf64;
+
+    fn mul(self, x: f64) -> Uf64 {
+        self.apply(self.value * x, x)
+    }
+}
+
+impl Div<f64> for Uf64 {
+    type Output = Uf64;
+
+    fn div(self, x: f64) -> Uf64 {
+        self.apply(self.value / x, 1. / x)
     }
 }
 
@@ -83,9 +215,221 @@ impl fmt::Display for Uf64 {
     }
 }
 
+/// A quantity that may be correlated with others computed from the same
+/// chunks. Its fluctuation is a sum of independent contributions, one per
+/// chunk, so the covariance of two quantities is the sum of the products of
+/// their contributions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Correlated {
+    value: f64,
+    contributions: Vec<f64>,
+}
+
+impl Correlated {
+    /// The weighted mean of the results of independent chunks. The spread of
+    /// the chunks estimates the uncertainty, so that quantities scored in the
+    /// same chunks pick up their correlation. A single chunk has no spread, so
+    /// its own uncertainty is used instead.
+    pub fn from_chunks(results: &[Uf64], weights: &[f64]) -> Result<Self> {
+        if results.len() != weights.len() {
+            bail!("Got {} results, but {} weights", results.len(), weights.len());
+        }
+        let value: f64 = results
+            .iter()
+            .zip(weights)
+            .map(|(r, w)| r.value() * w)
+            .sum();
+        let n = results.len();
+        let contributions = if n < 2 {
+            results.iter().zip(weights).map(|(r, w)| r.std() * w).collect()
+        } else {
+            let bessel = (n as f64 / (n - 1) as f64).sqrt();
+            results
+                .iter()
+                .zip(weights)
+                .map(|(r, w)| w * (r.value() - value) * bessel)
+                .collect()
+        };
+        Ok(Correlated {
+            value,
+            contributions,
+        })
+    }
+
+    pub fn var(&self) -> f64 {
+        self.contributions.iter().map(|c| c * c).sum()
+    }
+
+    pub fn std(&self) -> f64 {
+        self.var().sqrt()
+    }
+
+    /// Covariance with a quantity from the same chunks.
+    pub fn cov(&self, other: &Correlated) -> Result<f64> {
+        self.check_same_chunks(other)?;
+        Ok(self.contributions
+            .iter()
+            .zip(&other.contributions)
+            .map(|(a, b)| a * b)
+            .sum())
+    }
+
+    /// Correlation coefficient with a quantity from the same chunks.
+    pub fn correlation(&self, other: &Correlated) -> Result<f64> {
+        Ok(self.cov(other)? / (self.std() * other.std()))
+    }
+
+    /// Forget the correlations.
+    pub fn to_uf64(&self) -> Uf64 {
+        Uf64::from_value_var(self.value, self.var())
+    }
+
+    fn check_same_chunks(&self, other: &Correlated) -> Result<()> {
+        if self.contributions.len() != other.contributions.len() {
+            bail!(
+                "Correlated quantities must come from the same chunks, got {} and {}",
+                self.contributions.len(),
+                other.contributions.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// `f(self)` to first order, given `f(value)` and `f'(value)`.
+    fn apply(self, value: f64, derivative: f64) -> Self {
+        Correlated {
+            value,
+            contributions: self.contributions.iter().map(|c| c * derivative).collect(),
+        }
+    }
+
+    /// `f(self, other)` to first order, given `f` and its partial derivatives.
+    fn apply2(self, other: Correlated, value: f64, d_self: f64, d_other: f64) -> Result<Self> {
+        self.check_same_chunks(&other)?;
+        let contributions = self.contributions
+            .iter()
+            .zip(&other.contributions)
+            .map(|(a, b)| d_self * a + d_other * b)
+            .collect();
+        Ok(Correlated {
+            value,
+            contributions,
+        })
+    }
+
+    pub fn powf(self, p: f64) -> Self {
+        let x = self.value;
+        self.apply(x.powf(p), p * x.powf(p - 1.))
+    }
+
+    pub fn sqrt(self) -> Self {
+        let root = self.value.sqrt();
+        self.apply(root, 0.5 / root)
+    }
+
+    pub fn ln(self) -> Self {
+        let x = self.value;
+        self.apply(x.ln(), 1. / x)
+    }
+
+    pub fn exp(self) -> Self {
+        let e = self.value.exp();
+        self.apply(e, e)
+    }
+}
+
+impl Neg for Correlated {
+    type Output = Correlated;
+
+    fn neg(self) -> Correlated {
+        self * -1.
+    }
+}
+
+/// Quantities from different chunks cannot be combined, so the arithmetic
+/// of two correlated quantities can fail.
+impl Add for Correlated {
+    type Output = Result<Correlated>;
+
+    fn add(self, other: Correlated) -> Result<Correlated> {
+        let value = self.value + other.value;
+        self.apply2(other, value, 1., 1.)
+    }
+}
+
+impl Sub for Correlated {
+    type Output = Result<Correlated>;
+
+    fn sub(self, other: Correlated) -> Result<Correlated> {
+        let value = self.value - other.value;
+        self.apply2(other, value, 1., -1.)
+    }
+}
+
+impl Mul for Correlated {
+    type Output = Result<Correlated>;
+
+    fn mul(self, other: Correlated) -> Result<Correlated> {
+        let (a, b) = (self.value, other.value);
+        self.apply2(other, a * b, b, a)
+    }
+}
+
+impl Div for Correlated {
+    type Output = Result<Correlated>;
+
+    fn div(self, other: Correlated) -> Result<Correlated> {
+        let (a, b) = (self.value, other.value);
+        self.apply2(other, a / b, 1. / b, -a / (b * b))
+    }
+}
+
+impl Add<f64> for Correlated {
+    type Output = Correlated;
+
+    fn add(self, x: f64) -> Correlated {
+        let value = self.value + x;
+        self.apply(value, 1.)
+    }
+}
+
+impl Sub<f64> for Correlated {
+    type Output = Correlated;
+
+    fn sub(self, x: f64) -> Correlated {
+        let value = self.value - x;
+        self.apply(value, 1.)
+    }
+}
+
+impl Mul<f64> for Correlated {
+    type Output = Correlated;
+
+    fn mul(self, x: f64) -> Correlated {
+        let value = self.value * x;
+        self.apply(value, x)
+    }
+}
+
+impl Div<f64> for Correlated {
+    type Output = Correlated;
+
+    fn div(self, x: f64) -> Correlated {
+        let value = self.value / x;
+        self.apply(value, 1. / x)
+    }
+}
+
+impl fmt::Display for Correlated {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.to_uf64().fmt(f)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json;
 
     #[test]
     #[allow(non_snake_case)]
@@ -106,7 +450,174 @@ mod tests {
         assert_relative_eq!(u1.std() * c1.value(), (u1 * c1).std());
     }
 
+    #[test]
+    fn test_zero_value() {
+        let nothing = Uf64::from_value_rstd(0., 1.);
+        assert_eq!(nothing.var(), 0.);
+        assert_eq!(nothing.rstd(), 1.);
+        let noise = Uf64::from_value_var(0., 4.);
+        assert_eq!(noise.std(), 2.);
+        assert_eq!(noise.rstd(), f64::INFINITY);
+        assert_eq!((nothing + nothing).rstd(), 1.);
+        let sum = noise + Uf64::from_value_var(1., 5.);
+        assert_eq!(sum.value(), 1.);
+        assert_eq!(sum.rstd(), 3.);
+        assert_eq!((Uf64::from_value_var(-2., 4.)).rstd(), 1.);
+    }
+
+    #[test]
+    fn test_samples() {
+        // results of arithmetic are not estimated from samples
+        let u = Uf64::from_value_var(1., 1.);
+        let s = u.with_samples(3);
+        assert_ne!(s, u);
+        assert_eq!(s + s, u + u);
+        assert_eq!(s - s, u - u);
+        assert_eq!(s * 2., u * 2.);
+        assert_eq!(s * s, u * u);
+        assert_eq!(s / s, u / u);
+    }
+
+    #[test]
+    fn test_serde() {
+        let old: Uf64 = serde_json::from_str(r#"{"value": 2.0, "rstd": 0.5}"#).unwrap();
+        assert_eq!(old, Uf64::from_value_var(2., 1.));
+        let zero: Uf64 = serde_json::from_str(r#"{"value": 0.0, "rstd": null}"#).unwrap();
+        assert_eq!(zero, Uf64::from_value(0.));
+        assert_eq!(zero.rstd(), 1.);
+        let unknown: Uf64 = serde_json::from_str(r#"{"value": 2.0, "rstd": null}"#).unwrap();
+        assert_eq!(unknown.rstd(), f64::INFINITY);
+        let u = Uf64::from_value_var(0., 4.).with_samples(10);
+        let s = serde_json::to_string(&u).unwrap();
+        assert_eq!(s, r#"{"value":0.0,"var":4.0,"samples":10}"#);
+        assert_eq!(serde_json::from_str::<Uf64>(&s).unwrap(), u);
+        let s = serde_json::to_string(&Uf64::from_value(1.)).unwrap();
+        assert_eq!(s, r#"{"value":1.0,"var":0.0}"#);
+    }
+
+    #[test]
+    fn test_functions() {
+        let u = Uf64::from_value_var(4., 1.);
+        assert_eq!(u.sqrt(), Uf64::from_value_var(2., 1. / 16.));
+        assert_eq!(u.powf(2.), Uf64::from_value_var(16., 64.));
+        assert_eq!(u.ln().std(), 0.25);
+        assert_relative_eq!(Uf64::from_value_var(0., 1.).exp().std(), 1.);
+        assert_eq!(u - u, Uf64::from_value_var(0., 2.));
+        assert_eq!(-u, Uf64::from_value_var(-4., 1.));
+        assert_relative_eq!((u / u).var(), 2. / 16.);
+        assert_eq!(u * 2. + 1., Uf64::from_value_var(9., 4.));
+        assert_eq!((u - 2.) / 2., Uf64::from_value_var(1., 0.25));
+    }
+
+    #[test]
+    fn test_correlated() {
+        // two geometries that fluctuate together from chunk to chunk
+        let a: Vec<Uf64> = [1., 2., 3.].iter().map(|&x| Uf64::from_value_var(x, 1.)).collect();
+        let b: Vec<Uf64> = [2., 4., 6.].iter().map(|&x| Uf64::from_value_var(x, 1.)).collect();
+        let w = [1. / 3.; 3];
+        let ca = Correlated::from_chunks(&a, &w).unwrap();
+        let cb = Correlated::from_chunks(&b, &w).unwrap();
+        assert_relative_eq!(ca.to_uf64().value(), 2.);
+        // the variance of the mean is the sample variance over n
+        assert_relative_eq!(ca.var(), 1. / 3.);
+        assert_relative_eq!(ca.correlation(&cb).unwrap(), 1.);
+        let ratio = (cb.clone() / ca.clone()).unwrap();
+        assert_relative_eq!(ratio.to_uf64().value(), 2.);
+        assert_relative_eq!(ratio.var(), 0.);
+        assert!((cb.to_uf64() / ca.to_uf64()).var() > 0.);
+        assert_relative_eq!((cb - ca.clone() * 2.).unwrap().std(), 0.);
+        // functions scale the contributions, so they keep the correlation
+        let root = ca.clone().sqrt();
+        assert_relative_eq!(root.std(), ca.std() / (2. * 2_f64.sqrt()));
+        assert_relative_eq!(root.correlation(&ca).unwrap(), 1.);
+        assert_relative_eq!(ca.clone().ln().std(), ca.std() / 2.);
+        assert_relative_eq!(ca.clone().exp().std(), ca.std() * 2_f64.exp());
+        assert_relative_eq!(ca.clone().powf(3.).std(), ca.std() * 12.);
+
+        let single = Correlated::from_chunks(&a[..1], &[1.]).unwrap();
+        assert_eq!(single.to_uf64(), a[0]);
+        assert!(Correlated::from_chunks(&a, &w[..2]).is_err());
+    }
+
+    #[test]
+    fn test_correlated_different_chunks() {
+        let a = Correlated::from_chunks(&[Uf64::from_value(1.)], &[1.]).unwrap();
+        let b = Correlated::from_chunks(&[Uf64::from_value(1.), Uf64::from_value(2.)], &[0.5, 0.5])
+            .unwrap();
+        assert!(a.cov(&b).is_err());
+        assert!((a + b).is_err());
+    }
+
+    fn correlated(values: &[f64]) -> Correlated {
+        let results: Vec<Uf64> = values.iter().map(|&x| Uf64::from_value(x)).collect();
+        let weights = vec![1. / values.len() as f64; values.len()];
+        Correlated::from_chunks(&results, &weights).unwrap()
+    }
+
     quickcheck! {
+        fn prop_inclusion_subtractive(x:f64, y:f64) -> bool {
+            Uf64::from_value(x) - Uf64::from_value(y)
+                == Uf64::from_value(x-y)
+        }
+
+        fn prop_inclusion_divisive(x:f64, y:f64) -> bool {
+            y == 0. || Uf64::from_value(x) / Uf64::from_value(y)
+                == Uf64::from_value(x/y)
+        }
+
+        fn prop_independent_difference(x:f64, var:f64) -> bool {
+            let u = Uf64::from_value_var(x, var.abs());
+            (u - u).var() == 2. * var.abs()
+        }
+
+        fn prop_correlated_difference(values:Vec<f64>) -> bool {
+            let c = correlated(&values);
+            values.is_empty() || (c.clone() - c).unwrap().var() == 0.
+        }
+
+        fn prop_correlated_sum(a:Vec<(f64, f64)>) -> bool {
+            if a.is_empty() {
+                return true;
+            }
+            let x = correlated(&a.iter().map(|p| p.0).collect::<Vec<f64>>());
+            let y = correlated(&a.iter().map(|p| p.1).collect::<Vec<f64>>());
+            let expected = x.var() + y.var() + 2. * x.cov(&y).unwrap();
+            relative_eq!((x + y).unwrap().var(), expected, epsilon = 1e-9, max_relative = 1e-9)
+        }
+
+        fn prop_correlated_mean(values:Vec<f64>) -> bool {
+            values.is_empty() || relative_eq!(
+                correlated(&values).to_uf64().value(),
+                values.iter().sum::<f64>() / values.len() as f64,
+                epsilon = 1e-9,
+                max_relative = 1e-9
+            )
+        }
+
+        fn prop_sqrt_square(x:f64, var:f64) -> bool {
+            let u = Uf64::from_value_var(x.abs() + 1e-3, var.abs());
+            let v = u.sqrt().powf(2.);
+            relative_eq!(v.value(), u.value(), max_relative = 1e-9)
+                && relative_eq!(v.var(), u.var(), epsilon = 1e-12, max_relative = 1e-9)
+        }
+
+        fn prop_ln_exp(x:f64, var:f64) -> bool {
+            let u = Uf64::from_value_var(x % 100., var.abs());
+            let v = u.exp().ln();
+            relative_eq!(v.value(), u.value(), epsilon = 1e-9, max_relative = 1e-9)
+                && relative_eq!(v.var(), u.var(), epsilon = 1e-12, max_relative = 1e-9)
+        }
+
+        fn prop_correlated_sqrt_square(values:Vec<f64>) -> bool {
+            if values.is_empty() {
+                return true;
+            }
+            let c = correlated(&values.iter().map(|x| x.abs() + 1e-3).collect::<Vec<f64>>());
+            let d = c.clone().sqrt().powf(2.);
+            relative_eq!(d.to_uf64().value(), c.to_uf64().value(), max_relative = 1e-9)
+                && relative_eq!(d.var(), c.var(), epsilon = 1e-12, max_relative = 1e-9)
+        }
+
         fn prop_inclusion_multipicative(x:f64, y:f64) -> bool {
             Uf64::from_value(x) * Uf64::from_value(y)
                 == Uf64::from_value(x*y)
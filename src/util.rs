@@ -8,6 +8,12 @@ use std::path::{Path, PathBuf};
 use std::fs;
 use std::fmt;
 use errors::*;
+use std::env;
+use libc;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct HenInfo {
@@ -87,13 +93,172 @@ pub fn asset_path() -> PathBuf {
 }
 
 pub fn read_paths_in_dir(dir: &Path) -> Result<Vec<PathBuf>> {
-    let ret = fs::read_dir(dir)
-        .chain_err(|| cannot_read(&dir))?
-        .map(|entry| entry.unwrap().path())
-        .collect();
+    let mut ret = Vec::new();
+    for entry in fs::read_dir(dir).chain_err(|| cannot_read(&dir))? {
+        ret.push(entry.chain_err(|| cannot_read(&dir))?.path());
+    }
+    Ok(ret)
+}
+
+/// All files below `dir`, descending into subdirectories if `recursive`, sorted.
+pub fn read_files_in_dir(dir: &Path, recursive: bool) -> Result<Vec<PathBuf>> {
+    let mut ret = Vec::new();
+    for path in read_paths_in_dir(dir)? {
+        if path.is_dir() {
+            if recursive {
+                ret.extend(read_files_in_dir(&path, true)?);
+            }
+        } else {
+            ret.push(path);
+        }
+    }
+    ret.sort();
     Ok(ret)
 }
 
+/// Quote `s` for a POSIX shell.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Name of this host, reduced to characters that are safe in file names.
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return "localhost".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len])
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+        .collect()
+}
+
+fn is_executable(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(m) => m.is_file() && (m.permissions().mode() & 0o111 != 0),
+        Err(_) => false,
+    }
+}
+
+/// Locate an executable like the shell would, searching PATH for bare names.
+pub fn find_executable(name: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        let path = PathBuf::from(name);
+        return if is_executable(&path) { Some(path) } else { None };
+    }
+    let paths = env::var_os("PATH")?;
+    env::split_paths(&paths)
+        .map(|dir| dir.join(name))
+        .find(|p| is_executable(p))
+}
+
+/// Apply `f` to all `items` on `jobs` threads, starting them in order.
+/// `f` also gets the number of the thread it runs on.
+pub fn run_queue<T, R, F>(items: &[T], jobs: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(usize, &T) -> R + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+    thread::scope(|s| {
+        for worker in 0..jobs.max(1).min(items.len()) {
+            let (next, results, f) = (&next, &results, &f);
+            s.spawn(move || loop {
+                let k = next.fetch_add(1, Ordering::SeqCst);
+                if k >= items.len() {
+                    break;
+                }
+                let r = f(worker, &items[k]);
+                results.lock().unwrap()[k] = Some(r);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.expect("every item was processed"))
+        .collect()
+}
+
+/// A fixed number of numbered slots, shared by everything that wants to
+/// run at the same time, e.g. the chunks of several simulations.
+#[derive(Debug)]
+pub struct Slots {
+    free: Mutex<Vec<usize>>,
+    released: Condvar,
+}
+
+/// A slot that is taken until this is dropped.
+#[derive(Debug)]
+pub struct Slot<'a> {
+    slots: &'a Slots,
+    pub id: usize,
+}
+
+impl Slots {
+    pub fn new(len: usize) -> Self {
+        let len = len.max(1);
+        Slots {
+            free: Mutex::new((0..len).rev().collect()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Block until a slot is free and take it.
+    pub fn acquire(&self) -> Slot<'_> {
+        let mut free = self.free.lock().unwrap();
+        loop {
+            if let Some(id) = free.pop() {
+                return Slot { slots: self, id };
+            }
+            free = self.released.wait(free).unwrap();
+        }
+    }
+
+    /// Apply `f` to all `items`, starting them in order, each on its own
+    /// thread once it got a slot. `f` also gets the id of that slot.
+    ///
+    /// Unlike a pool of workers waiting for slots, this keeps the number of
+    /// threads at the number of slots, however many callers share them.
+    pub fn run<T, R, F>(&self, items: &[T], f: F) -> Vec<R>
+    where
+        T: Sync,
+        R: Send,
+        F: Fn(usize, &T) -> R + Sync,
+    {
+        let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+        thread::scope(|s| {
+            for (k, item) in items.iter().enumerate() {
+                let slot = self.acquire();
+                let (results, f) = (&results, &f);
+                s.spawn(move || {
+                    let r = f(slot.id, item);
+                    drop(slot);
+                    results.lock().unwrap()[k] = Some(r);
+                });
+            }
+        });
+        results
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|r| r.expect("every item was processed"))
+            .collect()
+    }
+}
+
+impl<'a> Drop for Slot<'a> {
+    fn drop(&mut self) {
+        self.slots.free.lock().unwrap().push(self.id);
+        self.slots.released.notify_one();
+    }
+}
+
 pub fn has_unique_elements<T>(iter: T) -> bool
 where
     T: IntoIterator,
@@ -112,3 +277,48 @@ fn test_has_unique_elements() {
     assert!(!has_unique_elements(vec![(1, 2), (1, 2)]));
     assert!(!has_unique_elements(vec!["a".to_string(), "a".to_string()]));
 }
+
+#[test]
+fn test_find_executable() {
+    assert!(find_executable("sh").is_some());
+    assert!(find_executable("/bin/sh").is_some());
+    assert!(find_executable("hen-no-such-application").is_none());
+    assert!(find_executable("/etc/hostname-no-such-file").is_none());
+}
+
+#[test]
+fn test_run_queue() {
+    use std::time::Duration;
+    let running = AtomicUsize::new(0);
+    let max_running = AtomicUsize::new(0);
+    let items: Vec<usize> = (0..12).collect();
+    let ret = run_queue(&items, 3, |worker, &i| {
+        let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+        max_running.fetch_max(n, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(20));
+        running.fetch_sub(1, Ordering::SeqCst);
+        assert!(worker < 3);
+        i * 2
+    });
+    assert_eq!(ret, (0..12).map(|i| i * 2).collect::<Vec<_>>());
+    assert_eq!(max_running.load(Ordering::SeqCst), 3);
+    assert!(run_queue(&Vec::<usize>::new(), 4, |_, &i| i).is_empty());
+}
+
+#[test]
+fn test_slots() {
+    let slots = Slots::new(2);
+    let running = AtomicUsize::new(0);
+    let items: Vec<usize> = (0..8).collect();
+    // two simulations share the same two slots
+    let ret = run_queue(&[0, 1], 2, |_, _| {
+        slots.run(&items, |id, &i| {
+            assert!(running.fetch_add(1, Ordering::SeqCst) < 2);
+            assert!(id < 2);
+            thread::sleep(::std::time::Duration::from_millis(5));
+            running.fetch_sub(1, Ordering::SeqCst);
+            i
+        })
+    });
+    assert_eq!(ret, vec![items.clone(), items]);
+}